@@ -0,0 +1,859 @@
+use anyhow::{anyhow, Context, Result};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::lockfile::LockFile;
+use crate::refs::{Ref, Refs};
+use crate::regex::Regex;
+use crate::wildmatch::{wildmatch, CASEFOLD, PATHNAME};
+
+// Like git, to avoid include loops
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+// The failures that `git config` reports with their own exit code, which scripts rely on
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("key does not contain a section: {0}")]
+    NoSection(String),
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+    #[error("{0} has multiple values")]
+    MultipleValues(String),
+    #[error("{0} is not set")]
+    NotSet(String),
+}
+
+impl ConfigError {
+    // Like git: 1 for an invalid key, 2 without a section, and 5 when the key to change isn't
+    // there or has several values
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ConfigError::InvalidKey(_) => 1,
+            ConfigError::NoSection(_) => 2,
+            ConfigError::MultipleValues(_) | ConfigError::NotSet(_) => 5,
+        }
+    }
+}
+
+// The settings from git's config files, in the order they were read: later values override
+// earlier ones
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    entries: Vec<ConfigEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigEntry {
+    // `section.name` or `section.subsection.name`, with the section and name lowercased
+    pub key: String,
+    // `None` for a bare `name` line, which means true for booleans
+    pub value: Option<String>,
+}
+
+impl Config {
+    // Reads the system, global and repository config files, in this order, like git. `env` gives
+    // the value of environment variables (`HOME`, `XDG_CONFIG_HOME`, `GIT_CONFIG_GLOBAL`...).
+    pub fn load(git_dir: Option<&Path>, env: impl Fn(&str) -> Option<OsString>) -> Result<Self> {
+        let home = env("HOME").map(PathBuf::from);
+        let mut files = Vec::new();
+
+        let no_system = env("GIT_CONFIG_NOSYSTEM")
+            .is_some_and(|value| parse_bool(&value.to_string_lossy()).unwrap_or(false));
+        if !no_system {
+            files.push(
+                env("GIT_CONFIG_SYSTEM")
+                    .map(PathBuf::from)
+                    .unwrap_or("/etc/gitconfig".into()),
+            );
+        }
+
+        files.extend(Self::global_files(&env));
+        files.extend(git_dir.map(|git_dir| git_dir.join("config")));
+
+        let loader = Loader {
+            git_dir: git_dir.map(|git_dir| git_dir.canonicalize().unwrap_or(git_dir.into())),
+            home,
+        };
+        let mut config = Self::default();
+        for file in files {
+            loader.read_file(&file, 0, &mut config.entries)?;
+        }
+        Ok(config)
+    }
+
+    // `$GIT_CONFIG_GLOBAL`, or the XDG config file and `~/.gitconfig`
+    pub(crate) fn global_files(env: &impl Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+        if let Some(global) = env("GIT_CONFIG_GLOBAL") {
+            return vec![global.into()];
+        }
+        let home = env("HOME").map(PathBuf::from);
+        let xdg = env("XDG_CONFIG_HOME")
+            .filter(|xdg| !xdg.is_empty())
+            .map(PathBuf::from)
+            .or(home.as_ref().map(|home| home.join(".config")));
+        let mut files = Vec::new();
+        files.extend(xdg.map(|xdg| xdg.join("git").join("config")));
+        files.extend(home.map(|home| home.join(".gitconfig")));
+        files
+    }
+
+    // The settings of these files only, without following includes
+    pub(crate) fn from_files(paths: &[PathBuf]) -> Result<Self> {
+        let mut entries = Vec::new();
+        for path in paths {
+            entries.extend(ConfigFile::open(path)?.config()?.entries);
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn entries(&self) -> &[ConfigEntry] {
+        &self.entries
+    }
+
+    // The last value of `key`, an empty string for a bare `name` line
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.get_entry(key)
+            .map(|entry| entry.value.as_deref().unwrap_or_default())
+    }
+
+    // Every value of a multi-valued key like `remote.origin.fetch`
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .filter(|entry| entry.key == key)
+            .map(|entry| entry.value.as_deref().unwrap_or_default())
+            .collect()
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>> {
+        self.get_entry(key)
+            .map(|entry| match &entry.value {
+                None => Ok(true),
+                Some(value) => parse_bool(value).ok_or(anyhow!(
+                    "bad boolean config value '{}' for '{}'",
+                    value,
+                    key
+                )),
+            })
+            .transpose()
+    }
+
+    fn get_entry(&self, key: &str) -> Option<&ConfigEntry> {
+        let key = normalize_key(key);
+        self.entries.iter().rev().find(|entry| entry.key == key)
+    }
+}
+
+// A single config file being edited. Changes leave the rest of the file as is, comments and
+// formatting included.
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    path: PathBuf,
+    contents: Vec<char>,
+}
+
+impl ConfigFile {
+    // A missing file is empty until saved
+    pub fn open(path: &Path) -> Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("read config file {}", path.display()))
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            contents: contents
+                .strip_prefix('\u{feff}')
+                .unwrap_or(&contents)
+                .chars()
+                .collect(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The settings of this file only, without following includes
+    pub fn config(&self) -> Result<Config> {
+        let mut entries = Vec::new();
+        self.parse(&mut |event| {
+            if let Event::Entry { key, value, .. } = event {
+                entries.push(ConfigEntry { key, value });
+            }
+            Ok(())
+        })?;
+        Ok(Config { entries })
+    }
+
+    // Replaces the value of `key`, or the one matching `value_pattern`, or adds the key if it
+    // doesn't exist. Fails if there are several values to choose from.
+    pub fn set(&mut self, key: &str, value: &str, value_pattern: Option<&str>) -> Result<()> {
+        let (_, _, name) = split_key(key)?;
+        let line = format!("\t{} = {}\n", name, quote_value(value));
+        match self.find(key, value_pattern)?[..] {
+            [] => self.insert(key, &line),
+            [ref span] => {
+                let span = span.clone();
+                self.contents.splice(span, line.chars());
+                Ok(())
+            }
+            _ => Err(ConfigError::MultipleValues(key.to_string()).into()),
+        }
+    }
+
+    // Adds a value to `key` even if it already has some, for multi-valued keys
+    pub fn add(&mut self, key: &str, value: &str) -> Result<()> {
+        let (_, _, name) = split_key(key)?;
+        self.insert(key, &format!("\t{} = {}\n", name, quote_value(value)))
+    }
+
+    // Removes the value of `key`, or the one matching `value_pattern`. Fails if there are several
+    // values to choose from.
+    pub fn unset(&mut self, key: &str, value_pattern: Option<&str>) -> Result<()> {
+        split_key(key)?;
+        match self.find(key, value_pattern)?[..] {
+            [] => Err(ConfigError::NotSet(key.to_string()).into()),
+            [ref span] => {
+                let mut span = span.clone();
+                // An entry on the same line as its section header keeps the line break
+                if span.start > 0 && self.contents[span.start - 1] != '\n' {
+                    span.end -= (self.contents[span.end - 1] == '\n') as usize;
+                }
+                self.contents.drain(span);
+                Ok(())
+            }
+            _ => Err(ConfigError::MultipleValues(key.to_string()).into()),
+        }
+    }
+
+    // Like git, the file is locked before it is read, so that a concurrent edit fails instead
+    // of silently overwriting this one
+    pub fn edit(path: &Path, edit: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        let mut lock = LockFile::acquire(path)?;
+        let mut file = Self::open(path)?;
+        edit(&mut file)?;
+        lock.write_all(file.contents.iter().collect::<String>().as_bytes())
+            .with_context(|| format!("write config file {}", path.display()))?;
+        lock.commit()
+    }
+
+    fn parse(&self, on_event: &mut dyn FnMut(Event) -> Result<()>) -> Result<()> {
+        parse(&self.contents, on_event)
+            .with_context(|| format!("bad config file {}", self.path.display()))
+    }
+
+    fn find(&self, key: &str, value_pattern: Option<&str>) -> Result<Vec<Range<usize>>> {
+        let key = normalize_key(key);
+        let value_matches = value_matcher(value_pattern)?;
+        let mut spans = Vec::new();
+        self.parse(&mut |event| {
+            match event {
+                Event::Entry {
+                    key: entry_key,
+                    value,
+                    span,
+                } if entry_key == key && value_matches(value.as_deref().unwrap_or_default()) => {
+                    spans.push(span)
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+        Ok(spans)
+    }
+
+    // Right after the last value of `key` if there is one, otherwise at the end of its section,
+    // which is created if needed
+    fn insert(&mut self, key: &str, line: &str) -> Result<()> {
+        let (section, subsection, _) = split_key(key)?;
+        let normalized = normalize_key(key);
+        let section_name = &normalized[..normalized.rfind('.').expect("key has a section")];
+
+        let (mut after_key, mut after_section) = (None, None);
+        let mut in_section = false;
+        self.parse(&mut |event| {
+            match event {
+                Event::Section { name, end } => {
+                    in_section = name == section_name;
+                    if in_section {
+                        after_section = Some(end);
+                    }
+                }
+                Event::Entry { key, span, .. } => {
+                    if key == normalized {
+                        after_key = Some(span.end);
+                    }
+                    if in_section {
+                        after_section = Some(span.end);
+                    }
+                }
+            }
+            Ok(())
+        })?;
+
+        let (position, text) = match after_key.or(after_section) {
+            Some(position) => (position, line.to_string()),
+            None => {
+                let header = match subsection {
+                    Some(subsection) => format!(
+                        "[{} \"{}\"]\n",
+                        section,
+                        subsection.replace('\\', "\\\\").replace('"', "\\\"")
+                    ),
+                    None => format!("[{}]\n", section),
+                };
+                (self.contents.len(), header + line)
+            }
+        };
+        let text = match position {
+            0 => text,
+            _ if self.contents[position - 1] == '\n' => text,
+            _ => format!("\n{}", text),
+        };
+        self.contents.splice(position..position, text.chars());
+        Ok(())
+    }
+}
+
+// `section.name` or `section.subsection.name` as typed
+pub(crate) fn split_key(key: &str) -> Result<(&str, Option<&str>, &str)> {
+    let (Some(first), Some(last)) = (key.find('.'), key.rfind('.')) else {
+        return Err(ConfigError::NoSection(key.to_string()).into());
+    };
+    let (section, name) = (&key[..first], &key[last + 1..]);
+    let subsection = (first != last).then(|| &key[first + 1..last]);
+
+    let valid_section = !section.is_empty()
+        && section
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-');
+    let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid_section || !valid_name || subsection.is_some_and(|sub| sub.contains('\n')) {
+        return Err(ConfigError::InvalidKey(key.to_string()).into());
+    }
+    Ok((section, subsection, name))
+}
+
+// Quotes values that would otherwise lose whitespace or be cut by a comment
+fn quote_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    let quoted = value.starts_with(char::is_whitespace)
+        || value.ends_with(char::is_whitespace)
+        || value.contains(['#', ';']);
+    match quoted {
+        true => format!("\"{}\"", escaped),
+        false => escaped,
+    }
+}
+
+// Like git, `!<regex>` selects the values that don't match
+pub(crate) fn value_matcher(pattern: Option<&str>) -> Result<impl Fn(&str) -> bool> {
+    let (pattern, negated) = match pattern.map(|pattern| pattern.strip_prefix('!')) {
+        Some(Some(pattern)) => (Some(pattern), true),
+        _ => (pattern, false),
+    };
+    let regex = pattern.map(Regex::new).transpose()?;
+    Ok(move |value: &str| {
+        regex
+            .as_ref()
+            .is_none_or(|regex| regex.is_match(value) != negated)
+    })
+}
+
+// What git accepts as booleans, case insensitively
+pub(crate) fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" | "" => Some(false),
+        _ => None,
+    }
+}
+
+// `Section.Sub.Name` -> `section.Sub.name`: only subsections are case sensitive
+pub(crate) fn normalize_key(key: &str) -> String {
+    match (key.find('.'), key.rfind('.')) {
+        (Some(first), Some(last)) if first != last => format!(
+            "{}{}{}",
+            key[..first].to_ascii_lowercase(),
+            &key[first..last],
+            key[last..].to_ascii_lowercase()
+        ),
+        _ => key.to_ascii_lowercase(),
+    }
+}
+
+struct Loader {
+    git_dir: Option<PathBuf>,
+    home: Option<PathBuf>,
+}
+
+impl Loader {
+    // Missing files are fine, there is usually no system config for instance
+    fn read_file(&self, path: &Path, depth: usize, entries: &mut Vec<ConfigEntry>) -> Result<()> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(anyhow!(
+                "exceeded maximum include depth ({}) while including {}",
+                MAX_INCLUDE_DEPTH,
+                path.display()
+            ));
+        }
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("read config file {}", path.display()))
+            }
+        };
+
+        parse_entries(&contents, &mut |key, value| {
+            let include = match key.strip_prefix("includeif.") {
+                Some(condition) => condition
+                    .strip_suffix(".path")
+                    .map(|condition| self.condition_holds(condition, path))
+                    .transpose()?
+                    .unwrap_or(false),
+                None => key == "include.path",
+            };
+            // The included settings come right after the include itself
+            let included = value.clone().filter(|_| include);
+            entries.push(ConfigEntry { key, value });
+            if let Some(included) = included {
+                let included = self.expand_path(&included, path)?;
+                self.read_file(&included, depth + 1, entries)?;
+            }
+            Ok(())
+        })
+        .with_context(|| format!("bad config file {}", path.display()))
+    }
+
+    // `~/` is the home directory and relative paths are relative to the including file
+    fn expand_path(&self, path: &str, config_file: &Path) -> Result<PathBuf> {
+        let path = match path.strip_prefix("~/") {
+            Some(path) => self
+                .home
+                .as_ref()
+                .ok_or(anyhow!("cannot expand ~/ without $HOME"))?
+                .join(path),
+            None => PathBuf::from(path),
+        };
+        Ok(config_file.parent().unwrap_or(Path::new("")).join(path))
+    }
+
+    // `gitdir:<pattern>`, `gitdir/i:<pattern>` and `onbranch:<pattern>`
+    fn condition_holds(&self, condition: &str, config_file: &Path) -> Result<bool> {
+        let (kind, pattern) = condition.split_once(':').unwrap_or((condition, ""));
+        match kind {
+            "gitdir" | "gitdir/i" => {
+                let Some(git_dir) = &self.git_dir else {
+                    return Ok(false);
+                };
+                let pattern = match pattern.strip_prefix("./") {
+                    Some(relative) => {
+                        let dir = config_file.parent().unwrap_or(Path::new(""));
+                        format!("{}/{}", dir.display(), relative)
+                    }
+                    None if pattern.starts_with("~/") => self
+                        .expand_path(pattern, config_file)?
+                        .display()
+                        .to_string(),
+                    None if pattern.starts_with('/') => pattern.to_string(),
+                    None => format!("**/{}", pattern),
+                };
+                let flags = match kind {
+                    "gitdir/i" => PATHNAME | CASEFOLD,
+                    _ => PATHNAME,
+                };
+                Ok(wildmatch(
+                    &directory_pattern(&pattern),
+                    &git_dir.display().to_string(),
+                    flags,
+                ))
+            }
+            "onbranch" => {
+                let Some(git_dir) = &self.git_dir else {
+                    return Ok(false);
+                };
+                let branch = match Refs::new(git_dir).read("HEAD")? {
+                    Some(Ref::Symbolic(target)) => target,
+                    _ => return Ok(false),
+                };
+                Ok(branch
+                    .strip_prefix("refs/heads/")
+                    .is_some_and(|branch| wildmatch(&directory_pattern(pattern), branch, PATHNAME)))
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+// A trailing slash matches everything below, as in `gitdir:~/work/`
+fn directory_pattern(pattern: &str) -> String {
+    match pattern.ends_with('/') {
+        true => format!("{}**", pattern),
+        false => pattern.to_string(),
+    }
+}
+
+// What a config file is made of, with positions (in chars) to edit it
+enum Event {
+    // `name` is `section` or `section.subsection`, and `end` is where entries can be inserted
+    // right after the header
+    Section {
+        name: String,
+        end: usize,
+    },
+    // `span` covers the whole line, or lines for continued values
+    Entry {
+        key: String,
+        value: Option<String>,
+        span: Range<usize>,
+    },
+}
+
+// Calls `on_event` with each section header and `key = value` of a config file, see
+// `git help config` for the syntax
+fn parse(contents: &[char], on_event: &mut dyn FnMut(Event) -> Result<()>) -> Result<()> {
+    let mut parser = Parser {
+        chars: contents,
+        pos: 0,
+        line: 1,
+    };
+    parser
+        .parse(on_event)
+        .with_context(|| format!("bad config line {}", parser.line))
+}
+
+// Only the entries
+fn parse_entries(
+    contents: &str,
+    on_entry: &mut dyn FnMut(String, Option<String>) -> Result<()>,
+) -> Result<()> {
+    let contents: Vec<char> = contents
+        .strip_prefix('\u{feff}')
+        .unwrap_or(contents)
+        .chars()
+        .collect();
+    parse(&contents, &mut |event| match event {
+        Event::Entry { key, value, .. } => on_entry(key, value),
+        Event::Section { .. } => Ok(()),
+    })
+}
+
+struct Parser<'a> {
+    chars: &'a [char],
+    pos: usize,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn skip_comment(&mut self) {
+        while !matches!(self.next(), Some('\n') | None) {}
+    }
+
+    fn parse(&mut self, on_event: &mut dyn FnMut(Event) -> Result<()>) -> Result<()> {
+        let mut section: Option<String> = None;
+        while let Some(c) = self.peek() {
+            match c {
+                c if c.is_whitespace() => {
+                    self.next();
+                }
+                '#' | ';' => self.skip_comment(),
+                '[' => {
+                    self.next();
+                    let name = self.section_header()?;
+                    section = Some(name.clone());
+                    on_event(Event::Section {
+                        name,
+                        end: self.header_end(),
+                    })?;
+                }
+                c if c.is_ascii_alphabetic() => {
+                    let section = section
+                        .as_ref()
+                        .ok_or(anyhow!("variable outside of any section"))?;
+                    let start = self.line_start();
+                    let (name, value) = self.variable()?;
+                    on_event(Event::Entry {
+                        key: format!("{}.{}", section, name),
+                        value,
+                        span: start..self.pos,
+                    })?;
+                }
+                c => return Err(anyhow!("unexpected character {:?}", c)),
+            }
+        }
+        Ok(())
+    }
+
+    // Where the current entry starts, whitespace before it included
+    fn line_start(&self) -> usize {
+        let indent = self.chars[..self.pos]
+            .iter()
+            .rev()
+            .take_while(|&&c| c == ' ' || c == '\t')
+            .count();
+        self.pos - indent
+    }
+
+    // After the end of the line of a section header, unless an entry follows on the same line
+    fn header_end(&self) -> usize {
+        let rest = &self.chars[self.pos..];
+        let blank = rest.iter().take_while(|&&c| c == ' ' || c == '\t').count();
+        match rest.get(blank) {
+            None => self.chars.len(),
+            Some('\n') => self.pos + blank + 1,
+            Some('#' | ';') => rest
+                .iter()
+                .position(|&c| c == '\n')
+                .map_or(self.chars.len(), |end| self.pos + end + 1),
+            Some(_) => self.pos,
+        }
+    }
+
+    // `[section]`, `[section "subsection"]` or the deprecated `[section.subsection]`, the opening
+    // bracket being already consumed
+    fn section_header(&mut self) -> Result<String> {
+        let mut name = String::new();
+        loop {
+            match self.next() {
+                Some(']') => return Ok(name.to_ascii_lowercase()),
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '.' => name.push(c),
+                Some(' ' | '\t') => break,
+                _ => return Err(anyhow!("invalid section header")),
+            }
+        }
+        if name.is_empty() {
+            return Err(anyhow!("invalid section header"));
+        }
+
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.next();
+        }
+        if self.next() != Some('"') {
+            return Err(anyhow!("invalid section header"));
+        }
+        let mut subsection = String::new();
+        loop {
+            match self.next() {
+                Some('"') => break,
+                Some('\\') => match self.next() {
+                    Some('\n') | None => return Err(anyhow!("invalid section header")),
+                    Some(c) => subsection.push(c),
+                },
+                Some('\n') | None => return Err(anyhow!("invalid section header")),
+                Some(c) => subsection.push(c),
+            }
+        }
+        if self.next() != Some(']') {
+            return Err(anyhow!("invalid section header"));
+        }
+        Ok(format!("{}.{}", name.to_ascii_lowercase(), subsection))
+    }
+
+    // `name`, `name = value`, with the value possibly quoted, escaped or continued on the next
+    // line
+    fn variable(&mut self) -> Result<(String, Option<String>)> {
+        let mut name = String::new();
+        while let Some(c) = self.peek() {
+            if !c.is_ascii_alphanumeric() && c != '-' {
+                break;
+            }
+            name.push(c.to_ascii_lowercase());
+            self.next();
+        }
+
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.next();
+        }
+        match self.peek() {
+            None => return Ok((name, None)),
+            Some('\n') => {
+                self.next();
+                return Ok((name, None));
+            }
+            Some('#' | ';') => {
+                self.skip_comment();
+                return Ok((name, None));
+            }
+            Some('=') => {
+                self.next();
+            }
+            Some(_) => return Err(anyhow!("invalid variable name")),
+        }
+
+        self.value().map(|value| (name, Some(value)))
+    }
+
+    // Leading and trailing whitespace is dropped, and unquoted whitespace within the value is
+    // kept as spaces
+    fn value(&mut self) -> Result<String> {
+        let mut value = String::new();
+        let mut quoted = false;
+        let mut spaces = 0;
+        loop {
+            let c = match self.next() {
+                None | Some('\n') if quoted => return Err(anyhow!("unterminated quote")),
+                None | Some('\n') => return Ok(value),
+                Some(c) => c,
+            };
+            if !quoted {
+                if c == ' ' || c == '\t' {
+                    if !value.is_empty() {
+                        spaces += 1;
+                    }
+                    continue;
+                }
+                if c == '#' || c == ';' {
+                    self.skip_comment();
+                    return Ok(value);
+                }
+            }
+            value.extend(std::iter::repeat_n(' ', spaces));
+            spaces = 0;
+
+            match c {
+                '"' => quoted = !quoted,
+                '\\' => match self.next() {
+                    // Line continuation
+                    Some('\n') => {}
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('b') => value.push('\u{8}'),
+                    Some(c @ ('"' | '\\')) => value.push(c),
+                    _ => return Err(anyhow!("invalid escape sequence")),
+                },
+                c => value.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn parse_like_git() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config");
+        fs::write(
+            &path,
+            concat!(
+                "# Comment\n",
+                "[core]\n",
+                "\tbare = false ; trailing comment\n",
+                "\tFileMode\n",
+                "[User]\n",
+                "  name = \"  Jane   Doe \"  \n",
+                "  email = jane@example.com # comment\n",
+                "[remote \"Ori\\\"gin\"]\n",
+                "\tfetch = +refs/heads/*:refs/remotes/origin/*\n",
+                "\tfetch = +refs/tags/*:refs/tags/*\n",
+                "[alias] lg = log \\\n",
+                "  --oneline\\t\"#not a comment\"\n",
+                "[Section.Sub]\n",
+                "\tkey = a\\\\b\\n  c   d\n",
+                "[empty]\n",
+                "\tkey =\n",
+            ),
+        )?;
+
+        let config = ConfigFile::open(&path)?.config()?;
+        let ours: Vec<String> = config
+            .entries()
+            .iter()
+            .map(|entry| match &entry.value {
+                Some(value) => format!("{}={}", entry.key, value),
+                None => entry.key.clone(),
+            })
+            .collect();
+        let output = Command::new("git")
+            .args(["config", "--list", "--file"])
+            .arg(&path)
+            .output()?;
+        assert!(output.status.success());
+        let theirs = String::from_utf8(output.stdout)?;
+        assert_eq!(format!("{}\n", ours.join("\n")), theirs);
+
+        assert_eq!(config.get("USER.Name"), Some("  Jane   Doe "));
+        assert_eq!(config.get("remote.ori\"gin.fetch"), None);
+        assert_eq!(config.get_all("remote.Ori\"gin.fetch").len(), 2);
+        assert_eq!(config.get_bool("core.filemode")?, Some(true));
+        assert_eq!(config.get_bool("core.bare")?, Some(false));
+        assert!(config.get_bool("user.name").is_err());
+        assert_eq!(config.get("core.missing"), None);
+
+        for invalid in [
+            "key = value\n",
+            "[core\n",
+            "[core]\nkey = \"open\n",
+            "[a]\nb=\\x\n",
+        ] {
+            fs::write(&path, invalid)?;
+            assert!(ConfigFile::open(&path)?.config().is_err(), "{}", invalid);
+        }
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn edit_under_lock() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config");
+        fs::write(&path, "[a]\n\tb = 1\n")?;
+
+        // Another edit can't start between reading the file and writing it back
+        ConfigFile::edit(&path, |file| {
+            assert!(ConfigFile::edit(&path, |file| file.add("a.b", "lost")).is_err());
+            file.add("a.b", "2")
+        })?;
+        assert_eq!(fs::read_to_string(&path)?, "[a]\n\tb = 1\n\tb = 2\n");
+
+        // A failed edit leaves the file and no lock behind
+        assert!(ConfigFile::edit(&path, |file| file.set("nosection", "x", None)).is_err());
+        assert_eq!(fs::read_to_string(&path)?, "[a]\n\tb = 1\n\tb = 2\n");
+        assert!(!dir.path().join("config.lock").exists());
+
+        dir.close()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, Offset, TimeZone};
+
+use crate::git_object::Time;
+
+// Parses the dates git accepts in options like `--expire` or `--since`, relative to `now` (in
+// seconds since the Unix epoch): `now`, `yesterday`, `<n>.<unit>.ago` (or with spaces), `@<seconds>`
+// and `YYYY-MM-DD` with an optional `HH:MM:SS`, in local time
+pub(crate) fn parse_approxidate(date: &str, now: i64) -> Result<i64> {
+    let date = date.trim();
+    match date {
+        "now" => return Ok(now),
+        "yesterday" => return Ok(now - 24 * 60 * 60),
+        _ => {}
+    }
+    if let Some(seconds) = date.strip_prefix('@') {
+        return seconds.parse().context("parse timestamp");
+    }
+
+    let words: Vec<&str> = date.split(['.', ' ']).filter(|w| !w.is_empty()).collect();
+    if let [count, unit, "ago"] = words[..] {
+        let count: i64 = count
+            .parse()
+            .with_context(|| format!("Invalid count in date {}", date))?;
+        let seconds = match unit.strip_suffix('s').unwrap_or(unit) {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => 24 * 60 * 60,
+            "week" => 7 * 24 * 60 * 60,
+            "month" => 30 * 24 * 60 * 60,
+            "year" => 365 * 24 * 60 * 60,
+            _ => return Err(anyhow!("Unknown unit in date {}", date)),
+        };
+        return Ok(now - count * seconds);
+    }
+
+    let local = if let Ok(date_time) = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S") {
+        date_time
+    } else if let Ok(day) = NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        day.and_hms_opt(0, 0, 0).expect("midnight is a valid time")
+    } else {
+        return Err(anyhow!("Invalid date {}", date));
+    };
+    Local
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|date_time| date_time.timestamp())
+        .ok_or(anyhow!("Invalid local date {}", date))
+}
+
+// Parses the date of a commit, as given to `--date` or in `GIT_AUTHOR_DATE`: git's internal
+// `<seconds> <+hhmm>`, `@<seconds>` with an optional timezone (UTC otherwise), RFC 2822, the
+// default format of `git log` and ISO 8601 with an optional timezone. Dates without one, and the
+// approximate dates above, are in local time.
+pub(crate) fn parse_date(date: &str, now: i64) -> Result<Time> {
+    let date = date.trim();
+    if let Ok(time) = date.parse::<Time>() {
+        return Ok(time);
+    }
+    if let Some(seconds) = date.strip_prefix('@') {
+        if let Ok(time) = seconds.parse::<Time>() {
+            return Ok(time);
+        }
+        if let Ok(seconds) = seconds.parse() {
+            return Ok(Time {
+                seconds,
+                offset: 0,
+                negative_zero: false,
+            });
+        }
+    }
+
+    let zoned = DateTime::parse_from_rfc2822(date).or_else(|_| {
+        [
+            "%Y-%m-%dT%H:%M:%S%#z",
+            "%Y-%m-%d %H:%M:%S%#z",
+            "%Y-%m-%d %H:%M:%S %#z",
+            "%a %b %e %H:%M:%S %Y %z",
+        ]
+        .iter()
+        .find_map(|format| DateTime::parse_from_str(date, format).ok())
+        .ok_or(())
+    });
+    if let Ok(date_time) = zoned {
+        return Ok(Time {
+            seconds: date_time.timestamp(),
+            offset: date_time.offset().local_minus_utc() / 60,
+            negative_zero: false,
+        });
+    }
+
+    let seconds = match NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S") {
+        Ok(local) => Local
+            .from_local_datetime(&local)
+            .earliest()
+            .map(|date_time| date_time.timestamp())
+            .ok_or(anyhow!("Invalid local date {}", date))?,
+        Err(_) => {
+            parse_approxidate(date, now).map_err(|_| anyhow!("invalid date format: {}", date))?
+        }
+    };
+    let offset = Local
+        .timestamp_opt(seconds, 0)
+        .earliest()
+        .map_or(0, |date_time| {
+            date_time.offset().fix().local_minus_utc() / 60
+        });
+    Ok(Time {
+        seconds,
+        offset,
+        negative_zero: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        let cases = [
+            ("1112911993 +0200", 1112911993, 120),
+            ("@1112911993", 1112911993, 0),
+            ("Thu, 07 Apr 2005 22:13:13 +0200", 1112904793, 120),
+            ("2005-04-07T22:13:13Z", 1112911993, 0),
+            ("2005-04-07T22:13:13-0130", 1112917393, -90),
+            ("2005-04-07 22:13:13 +02:00", 1112904793, 120),
+            ("@1112911993 +0000", 1112911993, 0),
+            ("@1112904793 +0200", 1112904793, 120),
+            ("Thu Apr 7 22:13:13 2005 +0200", 1112904793, 120),
+            ("Sun Apr 17 22:13:13 2005 -0100", 1113779593, -60),
+        ];
+        for (date, seconds, offset) in cases {
+            assert_eq!(
+                parse_date(date, 0).unwrap(),
+                Time {
+                    seconds,
+                    offset,
+                    negative_zero: false
+                },
+                "{}",
+                date
+            );
+        }
+        assert!(parse_date("not a date", 0).is_err());
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::Path;
+
+use crate::git_object::{FileMode, Object, ObjectKind, TreeEntry};
+use crate::object_id::ObjectId;
+use crate::odb::{LooseObjectDatabase, ObjectDatabase};
+use crate::pack::PackedObjectDatabase;
+use crate::refs::Refs;
+
+// What fsck found out about the objects of a repository
+#[derive(Default)]
+struct Report {
+    // Objects that could be read, with the objects they point to
+    objects: BTreeMap<ObjectId, (ObjectKind, Vec<(ObjectKind, ObjectId)>)>,
+    // Problems that git tolerates, unless checks are strict
+    warnings: Vec<String>,
+    errors: Vec<String>,
+    strict: bool,
+}
+
+// Checks the integrity of every object (loose and packed), then walks the objects reachable
+// from the refs to report missing objects, and the ones that are not reachable
+pub(crate) fn fsck<W: Write>(
+    git_dir: &Path,
+    show_unreachable: bool,
+    strict: bool,
+    writer: &mut W,
+) -> Result<()> {
+    let objects_dir = git_dir.join("objects");
+    let loose = LooseObjectDatabase::new(&objects_dir);
+    let packs = PackedObjectDatabase::open(objects_dir.join("pack")).context("open packs")?;
+    let mut report = Report {
+        strict,
+        ..Default::default()
+    };
+
+    for id in loose.iter()? {
+        let object = loose
+            .read_loose(&id)
+            .and_then(|bytes| {
+                let (kind, content) = Object::split_header(&bytes)?;
+                check_hash(&id, kind, content)?;
+                Object::parse(kind, content)
+            })
+            .with_context(|| format!("loose object {} is corrupt", id));
+        report.add(id, object);
+    }
+
+    for pack in packs.packs() {
+        if let Err(e) = pack.verify_checksum() {
+            report.errors.push(format!("error: {:#}", e));
+        }
+        for id in pack.index().ids() {
+            let object = pack
+                .read(id, Some(&packs))
+                .and_then(|object| {
+                    let (kind, content) = object.ok_or(anyhow!("not found in its index"))?;
+                    check_hash(id, kind, &content)?;
+                    Object::parse(kind, &content)
+                })
+                .with_context(|| {
+                    format!(
+                        "packed object {} in {} is corrupt",
+                        id,
+                        pack.path().display()
+                    )
+                });
+            report.add(*id, object);
+        }
+    }
+
+    // Walk the objects reachable from the refs
+    let mut reachable = BTreeSet::new();
+    let mut to_visit = Vec::new();
+    for (name, id) in ref_roots(git_dir)? {
+        if report.objects.contains_key(&id) {
+            to_visit.push(id);
+        } else {
+            report
+                .errors
+                .push(format!("error: {}: invalid sha1 pointer {}", name, id));
+        }
+    }
+    let mut missing = BTreeMap::new();
+    while let Some(id) = to_visit.pop() {
+        if !reachable.insert(id) {
+            continue;
+        }
+        let Some((_, targets)) = report.objects.get(&id) else {
+            continue;
+        };
+        for (kind, target) in targets {
+            if report.objects.contains_key(target) {
+                to_visit.push(*target);
+            } else {
+                missing.insert(*target, *kind);
+            }
+        }
+    }
+    for (id, kind) in missing {
+        report.errors.push(format!("missing {} {}", kind, id));
+    }
+
+    for warning in &report.warnings {
+        writeln!(writer, "{}", warning)?;
+    }
+
+    // Unreachable objects that no other unreachable object points to are dangling
+    let unreachable: BTreeSet<_> = report
+        .objects
+        .keys()
+        .filter(|id| !reachable.contains(id))
+        .copied()
+        .collect();
+    let referenced: BTreeSet<_> = unreachable
+        .iter()
+        .flat_map(|id| &report.objects[id].1)
+        .map(|(_, target)| *target)
+        .collect();
+    for id in &unreachable {
+        let kind = report.objects[id].0;
+        if show_unreachable {
+            writeln!(writer, "unreachable {} {}", kind, id)?;
+        } else if !referenced.contains(id) {
+            writeln!(writer, "dangling {} {}", kind, id)?;
+        }
+    }
+
+    for error in &report.errors {
+        writeln!(writer, "{}", error)?;
+    }
+    if !report.errors.is_empty() {
+        return Err(anyhow!("fsck found {} problem(s)", report.errors.len()));
+    }
+
+    Ok(())
+}
+
+impl Report {
+    fn add(&mut self, id: ObjectId, object: Result<Object>) {
+        let object = match object {
+            Ok(object) => object,
+            Err(e) => {
+                self.errors.push(format!("error: {:#}", e));
+                return;
+            }
+        };
+
+        let targets = match &object {
+            Object::Blob(_) => Vec::new(),
+            Object::Commit(commit) => std::iter::once((ObjectKind::Tree, commit.tree))
+                .chain(
+                    commit
+                        .parents
+                        .iter()
+                        .map(|parent| (ObjectKind::Commit, *parent)),
+                )
+                .collect(),
+            Object::Tag(tag) => vec![(tag.kind, tag.object)],
+            Object::Tree(entries) => {
+                if let Err(e) = check_tree(entries) {
+                    self.errors.push(format!("error in tree {}: {}", id, e));
+                }
+                self.check_modes(id, entries);
+                // Submodule commits live in another repository
+                entries
+                    .iter()
+                    .filter(|entry| entry.mode != FileMode::Gitlink)
+                    .map(|entry| (entry.mode.object_kind(), entry.id))
+                    .collect()
+            }
+        };
+        self.objects.insert(id, (object.kind(), targets));
+    }
+
+    // Modes that aren't canonical are read as the closest canonical one, but like git, zero-padded
+    // modes are worth a warning, and the modes old versions of git wrote are only reported when
+    // checks are strict
+    fn check_modes(&mut self, id: ObjectId, entries: &[TreeEntry]) {
+        let mut raw_modes = entries.iter().filter_map(|entry| entry.raw_mode.as_deref());
+        if raw_modes.clone().any(|mode| mode.starts_with('0')) {
+            let problem = format!(
+                "in tree {}: zeroPaddedFilemode: contains zero-padded file modes",
+                id
+            );
+            match self.strict {
+                true => self.errors.push(format!("error {}", problem)),
+                false => self.warnings.push(format!("warning {}", problem)),
+            }
+        }
+        if self.strict && raw_modes.any(|mode| !mode.starts_with('0')) {
+            self.warnings.push(format!(
+                "warning in tree {}: badFilemode: contains bad file modes",
+                id
+            ));
+        }
+    }
+}
+
+fn check_hash(id: &ObjectId, kind: ObjectKind, content: &[u8]) -> Result<()> {
+    let computed = ObjectId::hash(&Object::with_header(kind, content));
+    if computed != *id {
+        return Err(anyhow!("hash mismatch, contents hash to {}", computed));
+    }
+    Ok(())
+}
+
+fn check_tree(entries: &[TreeEntry]) -> Result<()> {
+    for entry in entries {
+        let name = String::from_utf8_lossy(&entry.name);
+        if entry.name.is_empty() {
+            return Err(anyhow!("contains empty pathname"));
+        }
+        if entry.name.contains(&b'/') {
+            return Err(anyhow!("contains full pathnames: {}", name));
+        }
+        if [&b"."[..], b"..", b".git"].contains(&entry.name.as_slice()) {
+            return Err(anyhow!("contains '{}'", name));
+        }
+    }
+    for pair in entries.windows(2) {
+        match pair[0].sort_key().cmp(&pair[1].sort_key()) {
+            std::cmp::Ordering::Less => {}
+            std::cmp::Ordering::Equal => {
+                return Err(anyhow!(
+                    "contains duplicate file entries: {}",
+                    String::from_utf8_lossy(&pair[0].name)
+                ))
+            }
+            std::cmp::Ordering::Greater => return Err(anyhow!("not properly sorted")),
+        }
+    }
+    Ok(())
+}
+
+// HEAD and every ref, loose or packed
+fn ref_roots(git_dir: &Path) -> Result<Vec<(String, ObjectId)>> {
+    let refs = Refs::new(git_dir);
+    let mut roots = refs.list("refs/")?;
+    // HEAD may point to a branch that doesn't exist yet
+    if let Some(head) = refs.resolve("HEAD").context("resolve HEAD")? {
+        roots.push(("HEAD".to_string(), head));
+    }
+    roots.sort();
+    Ok(roots)
+}
@@ -1,48 +1,110 @@
 use anyhow::{anyhow, Context, Ok, Result};
-use flate2::{write::ZlibEncoder, Compression};
-use sha1::{Digest, Sha1};
-use std::fs::{self, File};
-use std::io::{prelude::*, BufReader, Read};
-use std::os::unix::prelude::PermissionsExt;
+use chrono::{Local, Offset};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read};
 use std::path::Path;
 use std::str;
 
-pub(crate) enum Object {
-    Blob(String),
-    Commit(Vec<u8>),
-    Tag,
+use crate::object_id::ObjectId;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Object {
+    Blob(Vec<u8>),
+    Commit(Commit),
+    Tag(Tag),
     Tree(Vec<TreeEntry>),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Blob,
+    Commit,
+    Tag,
+    Tree,
+}
+
+impl fmt::Display for ObjectKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            ObjectKind::Blob => "blob",
+            ObjectKind::Commit => "commit",
+            ObjectKind::Tag => "tag",
+            ObjectKind::Tree => "tree",
+        };
+        f.write_str(kind)
+    }
+}
+
+impl str::FromStr for ObjectKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "blob" => Ok(ObjectKind::Blob),
+            "commit" => Ok(ObjectKind::Commit),
+            "tag" => Ok(ObjectKind::Tag),
+            "tree" => Ok(ObjectKind::Tree),
+            s => Err(anyhow!(
+                "object type should be either blob, commit, tag or tree, got: {}",
+                s
+            )),
+        }
+    }
+}
+
 impl Object {
-    pub(crate) fn blobify(file: &Path) -> Result<Self> {
+    pub fn blobify(file: &Path) -> Result<Self> {
         let f = File::open(file)?;
         let mut reader = BufReader::new(f);
-        let mut contents = String::new();
-        let _bytes = reader.read_to_string(&mut contents)?;
+        let mut contents = Vec::new();
+        let _bytes = reader.read_to_end(&mut contents)?;
         Ok(Self::Blob(contents))
     }
 
-    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (kind, content) = Self::split_header(bytes)?;
+        Self::parse(kind, content)
+    }
+
+    // Returns the kind of the object and its contents, checking them against the declared size
+    pub fn split_header(bytes: &[u8]) -> Result<(ObjectKind, &[u8])> {
         // A git object is stored as follows:
         // - the object type (blob, commit, tag or tree)
         // - an ASCII space
         // - the size of the contents in bytes
         // - a null byte (b"\x00" or '\0')
         // - the contents
-        let Some((obj_type, _size, rest)) = parse_fields(bytes).context("parse fields")? else {
+        let Some((obj_type, size, rest)) = parse_fields(bytes).context("parse fields")? else {
             return Err(anyhow!("No bytes to parse"));
         };
 
-        match obj_type {
-            "blob" => Ok(Self::Blob(
-                String::from_utf8(rest.to_owned()).context("convert blob bytes to UTF8")?,
+        let size: usize = str::from_utf8(size)
+            .context("convert size field to UTF8")?
+            .parse()
+            .context("parse object size")?;
+        if size != rest.len() {
+            return Err(anyhow!(
+                "Object size mismatch: header says {} bytes, got {}",
+                size,
+                rest.len()
+            ));
+        }
+
+        Ok((obj_type.parse()?, rest))
+    }
+
+    // Parses the contents of an object whose kind is already known, i.e. without the header
+    pub fn parse(kind: ObjectKind, content: &[u8]) -> Result<Self> {
+        match kind {
+            ObjectKind::Blob => Ok(Self::Blob(content.to_owned())),
+            ObjectKind::Commit => Ok(Self::Commit(
+                Commit::from_bytes(content).context("parse commit")?,
             )),
-            "commit" => Ok(Self::Commit(rest.to_owned())),
-            "tag" => Ok(Self::Tag),
-            "tree" => {
+            ObjectKind::Tag => Ok(Self::Tag(Tag::from_bytes(content).context("parse tag")?)),
+            ObjectKind::Tree => {
                 let mut entries = Vec::new();
-                let mut bytes = rest;
+                let mut bytes = content;
                 while let Some((entry, rest)) =
                     TreeEntry::from_bytes(bytes).context("parse tree entry")?
                 {
@@ -51,166 +113,111 @@ impl Object {
                 }
                 Ok(Self::Tree(entries))
             }
-            s => Err(anyhow!(
-                "object type should be either blob, commit, tag or tree, got: {}",
-                s
-            )),
         }
     }
 
-    pub(crate) fn to_bytes(&self) -> Vec<u8> {
-        let obj_type = self.kind();
-
-        let contents = self.content_bytes();
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Self::with_header(self.kind(), &self.content_bytes())
+    }
 
-        let mut bytes = format!("{} {}\x00", obj_type, contents.len())
+    // Prepends the `<kind> <size>\0` header to the contents of an object
+    pub fn with_header(kind: ObjectKind, content: &[u8]) -> Vec<u8> {
+        let mut bytes = format!("{} {}\x00", kind, content.len())
             .as_bytes()
             .to_owned();
-        bytes.extend(contents);
+        bytes.extend(content);
 
         bytes
     }
 
-    pub(crate) fn kind(&self) -> &str {
+    pub fn kind(&self) -> ObjectKind {
         match self {
-            Object::Blob(_) => "blob",
-            Object::Tree(_) => "tree",
-            Object::Commit(_) => "commit",
-            Object::Tag => "tag",
+            Object::Blob(_) => ObjectKind::Blob,
+            Object::Tree(_) => ObjectKind::Tree,
+            Object::Commit(_) => ObjectKind::Commit,
+            Object::Tag(_) => ObjectKind::Tag,
         }
     }
 
-    pub(crate) fn content_bytes(&self) -> Vec<u8> {
+    pub fn content_bytes(&self) -> Vec<u8> {
         match self {
-            Object::Blob(blob) => blob.as_bytes().to_owned(),
+            Object::Blob(blob) => blob.clone(),
             Object::Tree(entries) => entries
                 .iter()
                 .flat_map(|entry| entry.to_bytes().into_iter())
                 .collect(),
-            Object::Commit(commit) => commit.clone(),
-            Object::Tag => unimplemented!(),
+            Object::Commit(commit) => commit.to_bytes(),
+            Object::Tag(tag) => tag.to_bytes(),
         }
     }
 
-    pub(crate) fn hash(&self) -> [u8; 20] {
-        let bytes = self.to_bytes();
-        let mut hasher = Sha1::new();
-        hasher.update(bytes);
-        hasher.finalize().into()
+    pub fn hash(&self) -> ObjectId {
+        ObjectId::hash(&self.to_bytes())
     }
-
-    pub(crate) fn write(&self, root: &Path) -> Result<()> {
-        let hash = hex::encode(self.hash());
-
-        let (dir_name, file_name) = hash.split_at(2);
-        // Create dir if necessary
-        let dir_path = root.join(".git").join("objects").join(dir_name);
-        if !dir_path.exists() {
-            fs::create_dir_all(&dir_path).context("Create directory in .git/objects")?;
-        }
-        let file_path = dir_path.join(file_name);
-        // Create file
-        let mut file = File::create(file_path)?;
-
-        // Create encoder and compress object
-        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
-        e.write_all(&self.to_bytes())?;
-        let compressed = e.finish()?;
-
-        file.write_all(&compressed)?;
-
-        Ok(())
-    }
-}
-
-pub(crate) struct Tree(Vec<TreeNode>);
-
-struct TreeNode {
-    name: String,
-    kind: TreeNodeKind,
 }
 
-enum TreeNodeKind {
-    Blob { obj: Object, is_executable: bool },
-    Tree(Tree),
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMode {
+    Regular,
+    Executable,
+    Symlink,
+    Directory,
+    // A commit of a nested repository (submodule)
+    Gitlink,
 }
 
-impl TreeNodeKind {
-    fn mode(&self) -> usize {
+impl FileMode {
+    // The kind of object that an entry with this mode points to
+    pub fn object_kind(&self) -> ObjectKind {
         match self {
-            TreeNodeKind::Blob { is_executable, .. } => {
-                if *is_executable {
-                    100755
-                } else {
-                    100644
-                }
-            }
-            TreeNodeKind::Tree(_) => 40000,
+            FileMode::Regular | FileMode::Executable | FileMode::Symlink => ObjectKind::Blob,
+            FileMode::Directory => ObjectKind::Tree,
+            FileMode::Gitlink => ObjectKind::Commit,
         }
     }
 }
 
-impl Tree {
-    pub(crate) fn from_working_directory(path: &Path) -> Result<Self> {
-        let mut tree = Vec::new();
-        for entry in fs::read_dir(path)? {
-            let entry = entry?.path();
-            if let Some(basename) = entry.file_name().and_then(std::ffi::OsStr::to_str) {
-                if basename.starts_with('.') {
-                    continue;
-                }
-                let kind = if entry.is_dir() {
-                    let sub_tree = Tree::from_working_directory(&entry)?;
-                    TreeNodeKind::Tree(sub_tree)
-                } else {
-                    let blob = Object::blobify(&entry)?;
-                    let mode = entry.metadata()?.permissions().mode();
-                    let is_executable = mode & 0o111 != 0;
-                    TreeNodeKind::Blob {
-                        obj: blob,
-                        is_executable,
-                    }
-                };
-                tree.push(TreeNode {
-                    name: basename.to_string(),
-                    kind,
-                });
-            }
-        }
+// Formats the mode the way it is stored in tree objects, i.e. octal without leading zeros
+impl fmt::Display for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self {
+            FileMode::Regular => "100644",
+            FileMode::Executable => "100755",
+            FileMode::Symlink => "120000",
+            FileMode::Directory => "40000",
+            FileMode::Gitlink => "160000",
+        };
+        f.pad(mode)
+    }
+}
 
-        Ok(Self(tree))
-    }
-
-    pub(crate) fn write(&self, root: &Path) -> Result<[u8; 20]> {
-        let mut entries = Vec::new();
-        for node in &self.0 {
-            let hash = match &node.kind {
-                TreeNodeKind::Blob { obj, .. } => obj.hash(),
-                TreeNodeKind::Tree(tree) => tree.write(root)?,
-            };
-            let tree_entry = TreeEntry {
-                mode: node.kind.mode(),
-                name: node.name.clone(),
-                hash: hash.to_vec(),
-            };
-            entries.push(tree_entry);
+impl str::FromStr for FileMode {
+    type Err = anyhow::Error;
+
+    // Like git, modes other than the canonical ones (which some old versions of git wrote, like
+    // 100664) are read as the closest canonical one
+    fn from_str(s: &str) -> Result<Self> {
+        let mode = u32::from_str_radix(s, 8).map_err(|_| anyhow!("Invalid file mode: {}", s))?;
+        match mode & 0o170000 {
+            0o100000 if mode & 0o100 != 0 => Ok(FileMode::Executable),
+            0o100000 => Ok(FileMode::Regular),
+            0o120000 => Ok(FileMode::Symlink),
+            0o040000 => Ok(FileMode::Directory),
+            0o160000 => Ok(FileMode::Gitlink),
+            _ => Err(anyhow!("Invalid file mode: {}", s)),
         }
-
-        entries.sort_unstable_by_key(|tree_entry| tree_entry.name.clone());
-
-        let tree = Object::Tree(entries);
-        let hash = tree.hash();
-        tree.write(root)?;
-
-        Ok(hash)
     }
 }
 
-#[derive(Debug)]
-pub(crate) struct TreeEntry {
-    pub mode: usize,
-    pub name: String,
-    pub hash: Vec<u8>,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeEntry {
+    pub mode: FileMode,
+    // Like paths in general, names are bytes that don't have to be valid UTF-8
+    pub name: Vec<u8>,
+    pub id: ObjectId,
+    // The mode as found in the tree when it isn't the canonical one, so that the tree is written
+    // back as is and fsck can tell
+    pub raw_mode: Option<String>,
 }
 
 impl TreeEntry {
@@ -225,34 +232,432 @@ impl TreeEntry {
             return Ok(None);
         };
 
-        let mode = mode.parse::<usize>().context("parse mode")?;
+        let parsed: FileMode = mode.parse().context("parse mode")?;
+        let raw_mode = (parsed.to_string() != mode).then(|| mode.to_string());
 
-        let name = name.to_string();
+        let name = name.to_owned();
 
-        let sha1 = hex::encode(&rest[..20]).as_bytes().to_owned();
+        if rest.len() < ObjectId::LEN {
+            return Err(anyhow!(
+                "Tree entry {} is truncated",
+                String::from_utf8_lossy(&name)
+            ));
+        }
+        let (id, rest) = rest.split_at(ObjectId::LEN);
+        let id = ObjectId::from_bytes(id)?;
 
         Ok(Some((
             Self {
-                mode,
+                mode: parsed,
                 name,
-                hash: sha1,
+                id,
+                raw_mode,
             },
-            &rest[20..],
+            rest,
         )))
     }
 
+    // Git sorts tree entries by name, as if directories had a trailing slash
+    pub fn sort_key(&self) -> Vec<u8> {
+        let mut key = self.name.clone();
+        if self.mode == FileMode::Directory {
+            key.push(b'/');
+        }
+        key
+    }
+
     fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = format!("{} {}\x00", self.mode, self.name)
-            .as_bytes()
-            .to_owned();
-        bytes.extend(&self.hash);
+        let mut bytes = match &self.raw_mode {
+            Some(mode) => format!("{} ", mode).into_bytes(),
+            None => format!("{} ", self.mode).into_bytes(),
+        };
+        bytes.extend(&self.name);
+        bytes.push(b'\0');
+        bytes.extend(self.id.as_bytes());
+        bytes
+    }
+}
+
+// A `key value` header of a commit or a tag
+pub type Header = (String, Vec<u8>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    pub tree: ObjectId,
+    pub parents: Vec<ObjectId>,
+    pub author: Signature,
+    pub committer: Signature,
+    // Headers git may add after the committer line (`encoding`, `mergetag`, `gpgsig`...), kept
+    // in their original order so that the commit can be written back byte-for-byte
+    pub extra_headers: Vec<Header>,
+    // Git always writes an empty line before the message, but reads objects without one, whose
+    // message starts at the first line that isn't a header
+    pub blank_line: bool,
+    pub message: Vec<u8>,
+}
+
+impl Commit {
+    // A commit is made up of:
+    // - a header per line (`tree`, then zero or more `parent`, then `author` and `committer`,
+    //   then optional extra headers whose values may span several lines, continuation lines
+    //   starting with an ASCII space)
+    // - an empty line
+    // - the message
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (headers, blank_line, message) = parse_headers(bytes)?;
+        let mut headers = headers.into_iter().peekable();
+
+        let tree = match headers.next() {
+            Some((key, value)) if key == "tree" => parse_hex_id(&value).context("parse tree")?,
+            _ => return Err(anyhow!("Commit should start with a `tree` header")),
+        };
+
+        let mut parents = Vec::new();
+        while let Some((_, value)) = headers.next_if(|(key, _)| key == "parent") {
+            parents.push(parse_hex_id(&value).context("parse parent")?);
+        }
+
+        let author = match headers.next() {
+            Some((key, value)) if key == "author" => {
+                Signature::from_bytes(&value).context("parse author")?
+            }
+            _ => return Err(anyhow!("Missing `author` header in commit")),
+        };
+        let committer = match headers.next() {
+            Some((key, value)) if key == "committer" => {
+                Signature::from_bytes(&value).context("parse committer")?
+            }
+            _ => return Err(anyhow!("Missing `committer` header in commit")),
+        };
+
+        Ok(Self {
+            tree,
+            parents,
+            author,
+            committer,
+            extra_headers: headers.collect(),
+            blank_line,
+            message: message.to_owned(),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("tree {}\n", self.tree).into_bytes();
+        for parent in &self.parents {
+            bytes.extend(format!("parent {}\n", parent).as_bytes());
+        }
+        for (key, signature) in [("author", &self.author), ("committer", &self.committer)] {
+            bytes.extend(format!("{} ", key).as_bytes());
+            bytes.extend(signature.to_bytes());
+            bytes.push(b'\n');
+        }
+        write_headers(&mut bytes, &self.extra_headers);
+        if self.blank_line {
+            bytes.push(b'\n');
+        }
+        bytes.extend(&self.message);
         bytes
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub object: ObjectId,
+    pub kind: ObjectKind,
+    pub name: String,
+    // Very old tags were created without a tagger
+    pub tagger: Option<Signature>,
+    pub extra_headers: Vec<Header>,
+    // Like for commits, the empty line before the message is optional
+    pub blank_line: bool,
+    pub message: Vec<u8>,
+    // A signed tag has its (PGP or SSH) signature appended to the message
+    pub signature: Option<Vec<u8>>,
+}
+
+impl Tag {
+    const SIGNATURE_HEADERS: [&'static [u8]; 2] = [
+        b"-----BEGIN PGP SIGNATURE-----",
+        b"-----BEGIN SSH SIGNATURE-----",
+    ];
+
+    // A tag is made up of:
+    // - an `object` header with the id of the tagged object
+    // - a `type` header with the kind of the tagged object
+    // - a `tag` header with the name of the tag
+    // - an optional `tagger` header
+    // - an empty line
+    // - the message, optionally followed by a signature
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (headers, blank_line, message) = parse_headers(bytes)?;
+        let mut headers = headers.into_iter().peekable();
+
+        let object = match headers.next() {
+            Some((key, value)) if key == "object" => {
+                parse_hex_id(&value).context("parse object")?
+            }
+            _ => return Err(anyhow!("Tag should start with an `object` header")),
+        };
+        let kind = match headers.next() {
+            Some((key, value)) if key == "type" => str::from_utf8(&value)
+                .context("convert type to UTF8")?
+                .parse()?,
+            _ => return Err(anyhow!("Missing `type` header in tag")),
+        };
+        let name = match headers.next() {
+            Some((key, value)) if key == "tag" => {
+                String::from_utf8(value).context("convert tag name to UTF8")?
+            }
+            _ => return Err(anyhow!("Missing `tag` header in tag")),
+        };
+        let tagger = match headers.next_if(|(key, _)| key == "tagger") {
+            Some((_, value)) => Some(Signature::from_bytes(&value).context("parse tagger")?),
+            None => None,
+        };
+
+        // The signature starts at the last line beginning with a signature header
+        let signature_start = (0..message.len())
+            .rev()
+            .filter(|&i| i == 0 || message[i - 1] == b'\n')
+            .find(|&i| {
+                Self::SIGNATURE_HEADERS
+                    .iter()
+                    .any(|header| message[i..].starts_with(header))
+            });
+        let (message, signature) = match signature_start {
+            Some(idx) => (&message[..idx], Some(message[idx..].to_owned())),
+            None => (message, None),
+        };
+
+        Ok(Self {
+            object,
+            kind,
+            name,
+            tagger,
+            extra_headers: headers.collect(),
+            blank_line,
+            message: message.to_owned(),
+            signature,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!(
+            "object {}\ntype {}\ntag {}\n",
+            self.object, self.kind, self.name
+        )
+        .into_bytes();
+        if let Some(tagger) = &self.tagger {
+            bytes.extend(b"tagger ");
+            bytes.extend(tagger.to_bytes());
+            bytes.push(b'\n');
+        }
+        write_headers(&mut bytes, &self.extra_headers);
+        if self.blank_line {
+            bytes.push(b'\n');
+        }
+        bytes.extend(&self.message);
+        if let Some(signature) = &self.signature {
+            bytes.extend(signature);
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    // Kept as bytes: git writes them as they were given, in the encoding of the commit
+    pub name: Vec<u8>,
+    pub email: Vec<u8>,
+    pub time: Time,
+}
+
+impl Signature {
+    pub fn now(name: &str, email: &str) -> Self {
+        Self {
+            name: name.as_bytes().to_vec(),
+            email: email.as_bytes().to_vec(),
+            time: Time::now(),
+        }
+    }
+
+    // A signature looks like `Name <email> 1700000000 +0100`
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let start = bytes
+            .iter()
+            .position(|&b| b == b'<')
+            .ok_or(anyhow!("Could not find the start of the email"))?;
+        let end = bytes
+            .iter()
+            .rposition(|&b| b == b'>')
+            .filter(|&end| end > start)
+            .ok_or(anyhow!("Could not find the end of the email"))?;
+        let name = &bytes[..start];
+        let time = str::from_utf8(&bytes[end + 1..]).context("convert signature time to UTF8")?;
+
+        Ok(Self {
+            name: name.strip_suffix(b" ").unwrap_or(name).to_vec(),
+            email: bytes[start + 1..end].to_vec(),
+            time: time.trim_start().parse().context("parse signature time")?,
+        })
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.name.clone();
+        bytes.extend(b" <");
+        bytes.extend(&self.email);
+        bytes.extend(format!("> {}", self.time).as_bytes());
+        bytes
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} <{}> {}",
+            String::from_utf8_lossy(&self.name),
+            String::from_utf8_lossy(&self.email),
+            self.time
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Time {
+    // Seconds since the Unix epoch
+    pub seconds: i64,
+    // Offset from UTC in minutes
+    pub offset: i32,
+    // `-0000`, which some tools write for an unknown timezone, and which has to be written back
+    // as is
+    pub negative_zero: bool,
+}
+
+impl Time {
+    pub fn now() -> Self {
+        let local = Local::now();
+        Self {
+            seconds: local.timestamp(),
+            offset: local.offset().fix().local_minus_utc() / 60,
+            negative_zero: false,
+        }
+    }
+}
+
+impl str::FromStr for Time {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (seconds, tz) = s
+            .split_once(' ')
+            .ok_or(anyhow!("Expected a timestamp and a timezone, got: {}", s))?;
+        let seconds = seconds.parse().context("parse timestamp")?;
+
+        let (sign, digits) = match tz.split_at(1.min(tz.len())) {
+            ("+", digits) => (1, digits),
+            ("-", digits) => (-1, digits),
+            _ => return Err(anyhow!("Timezone should start with + or -, got: {}", tz)),
+        };
+        if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(anyhow!("Timezone should be of the form +hhmm, got: {}", tz));
+        }
+        let hours: i32 = digits[..2].parse()?;
+        let minutes: i32 = digits[2..].parse()?;
+
+        let offset = sign * (hours * 60 + minutes);
+        Ok(Self {
+            seconds,
+            offset,
+            negative_zero: sign < 0 && offset == 0,
+        })
+    }
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.offset < 0 || self.negative_zero {
+            '-'
+        } else {
+            '+'
+        };
+        let offset = self.offset.abs();
+        write!(
+            f,
+            "{} {}{:02}{:02}",
+            self.seconds,
+            sign,
+            offset / 60,
+            offset % 60
+        )
+    }
+}
+
+// Commits and tags share the same header format: `key value` lines where a value can span
+// several lines, continuation lines starting with an ASCII space. The headers end at the first
+// empty line, and whatever follows is the message. Like git, a line that can't be a header (no
+// space) also starts the message, and tells that there was no empty line.
+fn parse_headers(bytes: &[u8]) -> Result<(Vec<Header>, bool, &[u8])> {
+    let mut headers: Vec<Header> = Vec::new();
+    let mut bytes = bytes;
+
+    loop {
+        if bytes.is_empty() {
+            return Ok((headers, false, bytes));
+        }
+        let (line, rest) = match bytes.iter().position(|&b| b == b'\n') {
+            Some(idx) => (&bytes[..idx], &bytes[idx + 1..]),
+            None => (bytes, &bytes[bytes.len()..]),
+        };
+        if line.is_empty() {
+            return Ok((headers, true, rest));
+        }
+
+        if let Some(continuation) = line.strip_prefix(b" ") {
+            let (_, value) = headers
+                .last_mut()
+                .ok_or(anyhow!("Continuation line without a header"))?;
+            value.push(b'\n');
+            value.extend(continuation);
+            bytes = rest;
+            continue;
+        }
+
+        let Some(space_idx) = line.iter().position(|&b| b == b' ') else {
+            return Ok((headers, false, bytes));
+        };
+        bytes = rest;
+        let key = str::from_utf8(&line[..space_idx]).context("convert header key to UTF8")?;
+        headers.push((key.to_string(), line[space_idx + 1..].to_owned()));
+    }
+}
+
+fn write_headers(bytes: &mut Vec<u8>, headers: &[Header]) {
+    for (key, value) in headers {
+        bytes.extend(key.as_bytes());
+        bytes.push(b' ');
+        for (i, line) in value.split(|&b| b == b'\n').enumerate() {
+            if i > 0 {
+                bytes.extend(b"\n ");
+            }
+            bytes.extend(line);
+        }
+        bytes.push(b'\n');
+    }
+}
+
+fn parse_hex_id(bytes: &[u8]) -> Result<ObjectId> {
+    str::from_utf8(bytes)
+        .context("convert object id to UTF8")?
+        .parse()
+}
+
 // There is a recurring logic of fields to parse:
 // [field] [field]\x00[rest]
-fn parse_fields(bytes: &[u8]) -> Result<Option<(&str, &str, &[u8])>> {
+// The second field is a name or a size, which is left as bytes since names can be anything
+type Fields<'a> = (&'a str, &'a [u8], &'a [u8]);
+
+fn parse_fields(bytes: &[u8]) -> Result<Option<Fields<'_>>> {
     if bytes.is_empty() {
         return Ok(None);
     }
@@ -270,8 +675,90 @@ fn parse_fields(bytes: &[u8]) -> Result<Option<(&str, &str, &[u8])>> {
         .iter()
         .position(|&b| b == b'\0')
         .ok_or(anyhow!("Could not find a null byte"))?;
-    let field2 = str::from_utf8(&bytes[..null_byte_idx]).context("convert name field to UTF8")?;
+    let field2 = &bytes[..null_byte_idx];
     bytes = &bytes[null_byte_idx + 1..];
 
     Ok(Some((field1, field2, bytes)))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_round_trip() -> Result<()> {
+        let commit = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+parent 1111111111111111111111111111111111111111\n\
+parent 2222222222222222222222222222222222222222\n\
+author A U Thor <author@example.com> 1700000000 +0530\n\
+committer C O Mitter <committer@example.com> 1700000100 -0800\n\
+encoding ISO-8859-1\n\
+mergetag object 2222222222222222222222222222222222222222\n \
+type commit\n \
+tag v1.0\n \
+tagger T Agger <tagger@example.com> 1700000050 +0000\n \n \
+Release 1.0\n\
+gpgsig -----BEGIN PGP SIGNATURE-----\n \n \
+iQEzBAABCAAdFiEE\n \
+=abcd\n \
+-----END PGP SIGNATURE-----\n\
+\n\
+Merge branch 'feature'\n\nWith a body.\n";
+
+        let parsed = Commit::from_bytes(commit)?;
+        assert_eq!(parsed.parents.len(), 2);
+        assert_eq!(parsed.author.name, b"A U Thor");
+        assert_eq!(parsed.author.time.offset, 330);
+        assert_eq!(parsed.committer.time.offset, -480);
+        let keys: Vec<_> = parsed
+            .extra_headers
+            .iter()
+            .map(|(k, _)| k.as_str())
+            .collect();
+        assert_eq!(keys, ["encoding", "mergetag", "gpgsig"]);
+        assert_eq!(parsed.message, b"Merge branch 'feature'\n\nWith a body.\n");
+        assert_eq!(parsed.to_bytes(), commit);
+
+        // `-0000` keeps its sign, and like git, the empty line before the message is optional
+        let commit = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author A U Thor <author@example.com> 1700000000 -0000\n\
+committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+Subject\n\nBody\n";
+        let parsed = Commit::from_bytes(commit)?;
+        assert_eq!(parsed.author.time.offset, 0);
+        assert_ne!(parsed.author.time, parsed.committer.time);
+        assert_eq!(parsed.message, b"Subject\n\nBody\n");
+        assert_eq!(parsed.to_bytes(), commit);
+        let headers_only = &commit[..commit.len() - 14];
+        assert_eq!(Commit::from_bytes(headers_only)?.to_bytes(), headers_only);
+
+        Ok(())
+    }
+
+    #[test]
+    fn signed_tag_round_trip() -> Result<()> {
+        let tag = b"object 1111111111111111111111111111111111111111\n\
+type commit\n\
+tag v1.0\n\
+tagger T Agger <tagger@example.com> 1700000050 +0000\n\
+\n\
+Release 1.0\n\
+-----BEGIN PGP SIGNATURE-----\n\
+\n\
+iQEzBAABCAAdFiEE\n\
+=abcd\n\
+-----END PGP SIGNATURE-----\n";
+
+        let parsed = Tag::from_bytes(tag)?;
+        assert_eq!(parsed.kind, ObjectKind::Commit);
+        assert_eq!(parsed.name, "v1.0");
+        assert_eq!(parsed.message, b"Release 1.0\n");
+        assert!(parsed
+            .signature
+            .as_ref()
+            .is_some_and(|signature| signature.starts_with(b"-----BEGIN PGP SIGNATURE-----")));
+        assert_eq!(parsed.to_bytes(), tag);
+
+        Ok(())
+    }
+}
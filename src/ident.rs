@@ -0,0 +1,54 @@
+use anyhow::{anyhow, Result};
+use std::ffi::OsString;
+
+use crate::config::Config;
+use crate::date;
+use crate::git_object::Signature;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Author,
+    Committer,
+}
+
+// Who is writing a commit, tag or reflog entry. Like git, the `GIT_AUTHOR_*`/`GIT_COMMITTER_*`
+// environment variables come first, then `author.*`/`committer.*` and `user.*` in the config,
+// and `$EMAIL` for the email. `GIT_AUTHOR_DATE`/`GIT_COMMITTER_DATE` replace the current time.
+pub(crate) fn signature(
+    config: &Config,
+    role: Role,
+    env: impl Fn(&str) -> Option<OsString>,
+) -> Result<Signature> {
+    let (variable, section) = match role {
+        Role::Author => ("GIT_AUTHOR", "author"),
+        Role::Committer => ("GIT_COMMITTER", "committer"),
+    };
+    let lookup = |field: &str| {
+        env(&format!("{}_{}", variable, field.to_ascii_uppercase()))
+            .map(|value| value.to_string_lossy().into_owned())
+            .or(config
+                .get(&format!("{}.{}", section, field))
+                .map(String::from))
+            .or(config.get(&format!("user.{}", field)).map(String::from))
+    };
+
+    let name = lookup("name");
+    let email = lookup("email").or(env("EMAIL").map(|email| email.to_string_lossy().into_owned()));
+    match (name, email) {
+        (Some(name), Some(email)) if !name.is_empty() => {
+            let mut signature = Signature::now(&name, &email);
+            if let Some(time) = env(&format!("{}_DATE", variable)) {
+                signature.time = date::parse_date(&time.to_string_lossy(), signature.time.seconds)?;
+            }
+            Ok(signature)
+        }
+        _ => Err(anyhow!(
+            "{} identity unknown, please tell me who you are: set user.name and user.email with \
+            git config",
+            match role {
+                Role::Author => "Author",
+                Role::Committer => "Committer",
+            }
+        )),
+    }
+}
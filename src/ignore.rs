@@ -0,0 +1,260 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::repository::Repository;
+use crate::wildmatch::{wildmatch, CASEFOLD, PATHNAME};
+
+// A line of a `.gitignore` file
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern {
+    // The file it comes from, as shown by `check-ignore -v`, and its line number
+    pub(crate) source: String,
+    pub(crate) line: usize,
+    // The line as written
+    pub(crate) text: String,
+    pattern: String,
+    // `!pattern` re-includes what a previous pattern excluded
+    pub(crate) negated: bool,
+    // `pattern/` only matches directories
+    dir_only: bool,
+    // Patterns with a slash are relative to the directory of their file, the others match the
+    // name of a file or directory at any depth
+    anchored: bool,
+    // That directory, relative to the root with a trailing slash
+    base: String,
+}
+
+impl Pattern {
+    fn parse(line: &str, base: &str, source: &str, number: usize) -> Option<Self> {
+        // Trailing spaces are ignored unless escaped
+        let mut end = line.len();
+        while line[..end].ends_with(' ') && !line[..end - 1].ends_with('\\') {
+            end -= 1;
+        }
+        let text = &line[..end];
+        if text.is_empty() || text.starts_with('#') {
+            return None;
+        }
+
+        let (negated, mut pattern) = match text.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, text),
+        };
+        let dir_only = pattern.ends_with('/');
+        pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+        if pattern.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            source: source.to_string(),
+            line: number,
+            text: text.to_string(),
+            pattern: pattern.to_string(),
+            negated,
+            dir_only,
+            anchored,
+            base: base.to_string(),
+        })
+    }
+
+    fn matches(&self, path: &str, is_dir: bool, flags: u8) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let Some(relative) = path.strip_prefix(&self.base) else {
+            return false;
+        };
+        match self.anchored {
+            true => wildmatch(&self.pattern, relative, PATHNAME | flags),
+            false => {
+                let name = relative.rsplit('/').next().unwrap_or(relative);
+                wildmatch(&self.pattern, name, flags)
+            }
+        }
+    }
+}
+
+// The rules deciding which untracked files git doesn't see. From highest to lowest precedence:
+// the `.gitignore` files from the directory of a path up to the root, `$GIT_DIR/info/exclude`
+// and `core.excludesFile`. Within a file the last matching pattern wins.
+pub(crate) struct Ignore {
+    root: Option<PathBuf>,
+    flags: u8,
+    // `info/exclude` then `core.excludesFile`
+    global: Vec<Vec<Pattern>>,
+    // The `.gitignore` files read so far, by directory
+    per_directory: HashMap<String, Vec<Pattern>>,
+}
+
+impl Ignore {
+    pub(crate) fn load(repo: &Repository, env: impl Fn(&str) -> Option<OsString>) -> Result<Self> {
+        let config = repo.config()?;
+        let flags = match config.get_bool("core.ignorecase")? {
+            Some(true) => CASEFOLD,
+            _ => 0,
+        };
+
+        let mut global = Vec::new();
+        let exclude = repo.git_dir().join("info").join("exclude");
+        let source = match repo.work_tree() {
+            Some(root) => exclude.strip_prefix(root).unwrap_or(&exclude),
+            None => &exclude,
+        };
+        global.push(read_patterns(&exclude, "", &source.to_string_lossy())?);
+
+        let home = env("HOME").map(PathBuf::from);
+        let excludes_file = match config.get("core.excludesfile") {
+            Some(path) => match (path.strip_prefix("~/"), &home) {
+                (Some(path), Some(home)) => Some(home.join(path)),
+                _ => Some(PathBuf::from(path)),
+            },
+            None => env("XDG_CONFIG_HOME")
+                .filter(|xdg| !xdg.is_empty())
+                .map(PathBuf::from)
+                .or(home.map(|home| home.join(".config")))
+                .map(|xdg| xdg.join("git").join("ignore")),
+        };
+        if let Some(path) = excludes_file {
+            global.push(read_patterns(&path, "", &path.to_string_lossy())?);
+        }
+
+        Ok(Self {
+            root: repo.work_tree().map(Path::to_path_buf),
+            flags,
+            global,
+            per_directory: HashMap::new(),
+        })
+    }
+
+    // Nothing is ignored, for `add -f`
+    pub(crate) fn none() -> Self {
+        Self {
+            root: None,
+            flags: 0,
+            global: Vec::new(),
+            per_directory: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn is_ignored(&mut self, path: &str, is_dir: bool) -> Result<bool> {
+        Ok(self
+            .matching_pattern(path, is_dir)?
+            .is_some_and(|pattern| !pattern.negated))
+    }
+
+    // The pattern deciding whether `path`, relative to the root, is ignored, which excludes it
+    // unless it is negated. Everything in an ignored directory is ignored, whatever the patterns
+    // for the files themselves say, since git doesn't even look in there.
+    pub(crate) fn matching_pattern(&mut self, path: &str, is_dir: bool) -> Result<Option<Pattern>> {
+        for (i, _) in path.match_indices('/') {
+            if let Some(pattern) = self.last_match(&path[..i], true)? {
+                if !pattern.negated {
+                    return Ok(Some(pattern));
+                }
+            }
+        }
+        self.last_match(path, is_dir)
+    }
+
+    fn last_match(&mut self, path: &str, is_dir: bool) -> Result<Option<Pattern>> {
+        let mut directories: Vec<&str> =
+            path.match_indices('/').map(|(i, _)| &path[..=i]).collect();
+        directories.push("");
+        for directory in directories.into_iter().rev() {
+            self.read_directory(directory)?;
+            let found = self.per_directory[directory]
+                .iter()
+                .rev()
+                .find(|pattern| pattern.matches(path, is_dir, self.flags));
+            if let Some(pattern) = found {
+                return Ok(Some(pattern.clone()));
+            }
+        }
+        Ok(self.global.iter().find_map(|patterns| {
+            patterns
+                .iter()
+                .rev()
+                .find(|pattern| pattern.matches(path, is_dir, self.flags))
+                .cloned()
+        }))
+    }
+
+    fn read_directory(&mut self, directory: &str) -> Result<()> {
+        if self.per_directory.contains_key(directory) {
+            return Ok(());
+        }
+        let source = format!("{}.gitignore", directory);
+        let patterns = match &self.root {
+            Some(root) => read_patterns(&root.join(&source), directory, &source)?,
+            None => Vec::new(),
+        };
+        self.per_directory.insert(directory.to_string(), patterns);
+        Ok(())
+    }
+}
+
+// A missing file has no patterns
+fn read_patterns(path: &Path, base: &str, source: &str) -> Result<Vec<Pattern>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        // A directory named `.gitignore` isn't one
+        Err(_) if path.is_dir() => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    };
+    Ok(String::from_utf8_lossy(&contents)
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| Pattern::parse(line.trim_end_matches('\r'), base, source, i + 1))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        let cases = [
+            ("*.o", "", "src/main.o", false, true),
+            ("/*.o", "", "src/main.o", false, false),
+            ("/*.o", "", "main.o", false, true),
+            ("src/*.o", "", "src/main.o", false, true),
+            ("src/*.o", "", "src/bin/main.o", false, false),
+            ("**/bin", "", "src/bin", true, true),
+            ("src/**/*.o", "", "src/a/b/main.o", false, true),
+            ("target/", "", "target", true, true),
+            ("target/", "", "target", false, false),
+            ("*.o", "src/", "src/main.o", false, true),
+            ("*.o", "src/", "main.o", false, false),
+            ("/main.o", "src/", "src/main.o", false, true),
+            ("\\#file", "", "#file", false, true),
+            ("trailing\\ ", "", "trailing ", false, true),
+            ("trailing  ", "", "trailing", false, true),
+        ];
+        for (line, base, path, is_dir, expected) in cases {
+            let pattern = Pattern::parse(line, base, ".gitignore", 1).unwrap();
+            assert_eq!(
+                pattern.matches(path, is_dir, 0),
+                expected,
+                "{} {}",
+                line,
+                path
+            );
+        }
+        assert!(Pattern::parse("# comment", "", ".gitignore", 1).is_none());
+        assert!(Pattern::parse("   ", "", ".gitignore", 1).is_none());
+        assert!(
+            Pattern::parse("!keep.o", "", ".gitignore", 1)
+                .unwrap()
+                .negated
+        );
+    }
+}
@@ -0,0 +1,758 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self, Metadata};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use crate::git_object::{FileMode, Object, TreeEntry};
+use crate::lockfile::LockFile;
+use crate::object_id::ObjectId;
+use crate::odb::ObjectDatabase;
+use crate::pack::{encode_offset, read_offset_encoding, read_u32, take};
+
+// The staging area, `.git/index`. Its layout is:
+// - the signature `DIRC`, the version (2, 3 or 4) and the number of entries
+// - the entries, sorted by path and stage
+// - extensions: a 4-byte signature, a 32-bit size and the data. Those whose signature starts with
+//   an uppercase letter are optional and can be ignored.
+// - the checksum of everything that precedes it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Index {
+    pub version: u32,
+    entries: Vec<IndexEntry>,
+    // The `TREE` extension: the trees of the staged state, for the directories that didn't change
+    pub cache_tree: Option<CacheTree>,
+    // The `REUC` extension: the conflicting versions of resolved paths, to undo a resolution
+    pub resolve_undo: Vec<ResolveUndo>,
+}
+
+// An entry is made up of:
+// - stat data about the file (ctime, mtime, dev, ino, mode, uid, gid, size) as 32-bit integers
+// - the id of the blob
+// - 16 bits of flags: assume-valid, extended, the stage (2 bits) and the length of the path
+// - starting from version 3, 16 more bits of flags if the extended flag is set: skip-worktree and
+//   intent-to-add
+// - the path, NUL-terminated and padded with NULs to a multiple of 8 bytes in versions 2 and 3.
+//   In version 4 it is prefixed by the number of bytes to remove from the end of the previous
+//   path to get the part it shares with this one, followed by the rest of the path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub ctime: IndexTime,
+    pub mtime: IndexTime,
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: FileMode,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub id: ObjectId,
+    // 0 normally, 1 to 3 for the base, ours and theirs versions of a conflicting path
+    pub stage: u8,
+    pub assume_valid: bool,
+    pub skip_worktree: bool,
+    pub intent_to_add: bool,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexTime {
+    pub seconds: u32,
+    pub nanoseconds: u32,
+}
+
+// A directory of the cache tree: `name` is empty for the root. Without an id it is invalid, and
+// has to be computed again from the entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheTree {
+    pub name: String,
+    pub id: Option<ObjectId>,
+    // The number of index entries below this directory
+    pub entry_count: usize,
+    pub subtrees: Vec<CacheTree>,
+}
+
+// The mode and id of a path in stages 1 to 3 before the conflict was resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveUndo {
+    pub path: String,
+    pub stages: [Option<(FileMode, ObjectId)>; 3],
+}
+
+const SIGNATURE: &[u8] = b"DIRC";
+const ASSUME_VALID: u16 = 0x8000;
+const EXTENDED: u16 = 0x4000;
+const STAGE_MASK: u16 = 0x3000;
+const NAME_MASK: u16 = 0x0fff;
+const SKIP_WORKTREE: u16 = 0x4000;
+const INTENT_TO_ADD: u16 = 0x2000;
+
+impl Default for Index {
+    fn default() -> Self {
+        Self {
+            version: 2,
+            entries: Vec::new(),
+            cache_tree: None,
+            resolve_undo: Vec::new(),
+        }
+    }
+}
+
+impl Index {
+    // A missing index is an empty one, as in a new repository
+    pub fn open(path: &Path) -> Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => {
+                Self::from_bytes(&bytes).with_context(|| format!("parse {}", path.display()))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("read {}", path.display())),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < ObjectId::LEN {
+            return Err(anyhow!("Index is too short"));
+        }
+        let (content, checksum) = bytes.split_at(bytes.len() - ObjectId::LEN);
+        if ObjectId::hash(content) != ObjectId::from_bytes(checksum)? {
+            return Err(anyhow!("Index checksum mismatch"));
+        }
+
+        let mut reader = content;
+        if take(&mut reader, 4)? != SIGNATURE {
+            return Err(anyhow!("Invalid index signature"));
+        }
+        let version = read_u32(&mut reader)?;
+        if !(2..=4).contains(&version) {
+            return Err(anyhow!("Unsupported index version: {}", version));
+        }
+        let count = read_u32(&mut reader)?;
+
+        // Each entry takes at least 62 bytes, so a corrupt count can't reserve more than the file holds
+        let capacity = (count as usize).min(bytes.len() / 62);
+        let mut entries: Vec<IndexEntry> = Vec::with_capacity(capacity);
+        for _ in 0..count {
+            let previous = entries.last().map(|entry| entry.path.as_str());
+            let entry = IndexEntry::from_bytes(&mut reader, version, previous)
+                .with_context(|| format!("parse index entry {}", entries.len()))?;
+            entries.push(entry);
+        }
+
+        let mut index = Self {
+            version,
+            entries,
+            cache_tree: None,
+            resolve_undo: Vec::new(),
+        };
+        while !reader.is_empty() {
+            let signature = take(&mut reader, 4)?;
+            let size = read_u32(&mut reader)? as usize;
+            let mut data = take(&mut reader, size)?;
+            match signature {
+                b"TREE" => {
+                    if !data.is_empty() {
+                        index.cache_tree =
+                            Some(CacheTree::from_bytes(&mut data).context("parse TREE extension")?);
+                    }
+                }
+                b"REUC" => {
+                    while !data.is_empty() {
+                        let entry =
+                            ResolveUndo::from_bytes(&mut data).context("parse REUC extension")?;
+                        index.resolve_undo.push(entry);
+                    }
+                }
+                signature if signature[0].is_ascii_uppercase() => {}
+                signature => {
+                    return Err(anyhow!(
+                        "Index uses the {} extension, which is not supported",
+                        String::from_utf8_lossy(signature)
+                    ))
+                }
+            }
+        }
+
+        Ok(index)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Extended flags need version 3
+        let version = match self.version {
+            2 if self.entries.iter().any(IndexEntry::is_extended) => 3,
+            version => version,
+        };
+
+        let mut bytes = SIGNATURE.to_vec();
+        bytes.extend(version.to_be_bytes());
+        bytes.extend((self.entries.len() as u32).to_be_bytes());
+        let mut previous = "";
+        for entry in &self.entries {
+            entry.write_to(&mut bytes, version, previous);
+            previous = &entry.path;
+        }
+
+        if let Some(cache_tree) = &self.cache_tree {
+            let mut data = Vec::new();
+            cache_tree.write_to(&mut data);
+            write_extension(&mut bytes, b"TREE", &data);
+        }
+        if !self.resolve_undo.is_empty() {
+            let mut data = Vec::new();
+            for entry in &self.resolve_undo {
+                entry.write_to(&mut data);
+            }
+            write_extension(&mut bytes, b"REUC", &data);
+        }
+
+        let checksum = ObjectId::hash(&bytes);
+        bytes.extend(checksum.as_bytes());
+        bytes
+    }
+
+    // Through `index.lock`, like git
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut lock = LockFile::acquire(path)?;
+        lock.write_all(&self.to_bytes())
+            .with_context(|| format!("write {}", path.display()))?;
+        lock.commit()
+    }
+
+    // Sorted by path, then stage
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, path: &str, stage: u8) -> Option<&IndexEntry> {
+        self.position(path, stage).ok().map(|i| &self.entries[i])
+    }
+
+    // Adds the entry, or replaces the one with the same path and stage. Like git, staging a path
+    // resolves its conflict, the conflicting versions being kept in the resolve-undo data, and
+    // replaces any file in the way of its directories or directory in the way of the file.
+    pub fn add(&mut self, entry: IndexEntry) {
+        if entry.stage == 0 {
+            let mut stages = [None; 3];
+            for conflict in &self.entries[self.stages(&entry.path)] {
+                if conflict.stage > 0 {
+                    stages[conflict.stage as usize - 1] = Some((conflict.mode, conflict.id));
+                }
+            }
+            if stages.iter().any(Option::is_some) {
+                self.resolve_undo.retain(|undo| undo.path != entry.path);
+                self.resolve_undo.push(ResolveUndo {
+                    path: entry.path.clone(),
+                    stages,
+                });
+                self.remove(&entry.path);
+            }
+
+            // Files where the directories of the path should be
+            for (i, _) in entry.path.match_indices('/') {
+                self.remove(&entry.path[..i]);
+            }
+            // Files in a directory where the path should be, which are right after it
+            let directory = format!("{}/", entry.path);
+            let start = self.position(&directory, 0).unwrap_or_else(|i| i);
+            let end = start
+                + self.entries[start..]
+                    .iter()
+                    .take_while(|other| other.path.starts_with(&directory))
+                    .count();
+            for other in self.entries.drain(start..end).collect::<Vec<_>>() {
+                self.invalidate(&other.path);
+            }
+        }
+
+        self.invalidate(&entry.path);
+        match self.position(&entry.path, entry.stage) {
+            Ok(i) => self.entries[i] = entry,
+            Err(i) => self.entries.insert(i, entry),
+        }
+    }
+
+    // Updates the stat data of a file whose contents didn't change. The trees stay valid.
+    pub fn refresh(&mut self, path: &str, metadata: &Metadata) {
+        if let Ok(i) = self.position(path, 0) {
+            let entry = &mut self.entries[i];
+            let fresh = IndexEntry::from_metadata(path, metadata, entry.mode, entry.id);
+            entry.ctime = fresh.ctime;
+            entry.mtime = fresh.mtime;
+            entry.dev = fresh.dev;
+            entry.ino = fresh.ino;
+            entry.uid = fresh.uid;
+            entry.gid = fresh.gid;
+            entry.size = fresh.size;
+        }
+    }
+
+    // Removes every stage of `path`, returning whether there was any
+    pub fn remove(&mut self, path: &str) -> bool {
+        let stages = self.stages(path);
+        let removed = !stages.is_empty();
+        if removed {
+            self.entries.drain(stages);
+            self.invalidate(path);
+        }
+        removed
+    }
+
+    // Writes the trees of the staged state and returns the root one, reusing the valid trees of
+    // the cache tree, which is brought up to date. Objects missing from `odb` are an error unless
+    // `missing_ok`, and so are conflicts.
+    pub fn write_tree(&mut self, odb: &dyn ObjectDatabase, missing_ok: bool) -> Result<ObjectId> {
+        if let Some(entry) = self.entries.iter().find(|entry| entry.stage != 0) {
+            return Err(anyhow!("path '{}' is unmerged", entry.path));
+        }
+        let tree = build_tree(
+            self.cache_tree.take(),
+            String::new(),
+            &self.entries,
+            0,
+            odb,
+            missing_ok,
+        )?;
+        let id = tree.id.context("tree was not written")?;
+        self.cache_tree = Some(tree);
+        Ok(id)
+    }
+
+    // The trees containing `path` have to be computed again
+    fn invalidate(&mut self, path: &str) {
+        let Some(mut tree) = self.cache_tree.as_mut() else {
+            return;
+        };
+        tree.id = None;
+        let mut components: Vec<&str> = path.split('/').collect();
+        components.pop();
+        for component in components {
+            match tree
+                .subtrees
+                .iter_mut()
+                .find(|subtree| subtree.name == component)
+            {
+                Some(subtree) => {
+                    subtree.id = None;
+                    tree = subtree;
+                }
+                None => return,
+            }
+        }
+    }
+
+    // The entries of every stage of `path`
+    fn stages(&self, path: &str) -> Range<usize> {
+        let start = self.position(path, 0).unwrap_or_else(|i| i);
+        let end = start
+            + self.entries[start..]
+                .iter()
+                .take_while(|entry| entry.path == path)
+                .count();
+        start..end
+    }
+
+    fn position(&self, path: &str, stage: u8) -> Result<usize, usize> {
+        self.entries.binary_search_by(|entry| {
+            (entry.path.as_bytes(), entry.stage).cmp(&(path.as_bytes(), stage))
+        })
+    }
+}
+
+// The cache tree of the directory whose `entries` all start with the same `base_len` bytes,
+// computed again unless `cached` is still valid
+fn build_tree(
+    cached: Option<CacheTree>,
+    name: String,
+    entries: &[IndexEntry],
+    base_len: usize,
+    odb: &dyn ObjectDatabase,
+    missing_ok: bool,
+) -> Result<CacheTree> {
+    let mut cached_subtrees = Vec::new();
+    if let Some(cached) = cached {
+        match cached.id {
+            Some(id) if cached.entry_count == entries.len() && odb.exists(&id)? => {
+                return Ok(cached)
+            }
+            _ => cached_subtrees = cached.subtrees,
+        }
+    }
+
+    let mut tree_entries = Vec::new();
+    let mut subtrees = Vec::new();
+    let mut i = 0;
+    while i < entries.len() {
+        let entry = &entries[i];
+        let relative = &entry.path[base_len..];
+        if let Some(slash) = relative.find('/') {
+            let dir = &relative[..=slash];
+            let count = entries[i..]
+                .iter()
+                .take_while(|entry| entry.path[base_len..].starts_with(dir))
+                .count();
+            let dir = &dir[..slash];
+            let cached = cached_subtrees
+                .iter()
+                .position(|subtree| subtree.name == dir)
+                .map(|position| cached_subtrees.swap_remove(position));
+            let subtree = build_tree(
+                cached,
+                dir.to_string(),
+                &entries[i..i + count],
+                base_len + slash + 1,
+                odb,
+                missing_ok,
+            )?;
+            let id = subtree.id.context("tree was not written")?;
+            // Directories with nothing but intent-to-add files don't exist yet
+            if id != Object::Tree(Vec::new()).hash() {
+                tree_entries.push(TreeEntry {
+                    mode: FileMode::Directory,
+                    name: dir.as_bytes().to_vec(),
+                    id,
+                    raw_mode: None,
+                });
+            }
+            subtrees.push(subtree);
+            i += count;
+            continue;
+        }
+
+        i += 1;
+        if entry.intent_to_add {
+            continue;
+        }
+        if !missing_ok && entry.mode != FileMode::Gitlink && !odb.exists(&entry.id)? {
+            return Err(anyhow!(
+                "invalid object {} {} for '{}'",
+                entry.mode,
+                entry.id,
+                entry.path
+            ));
+        }
+        tree_entries.push(TreeEntry {
+            mode: entry.mode,
+            name: relative.as_bytes().to_vec(),
+            id: entry.id,
+            raw_mode: None,
+        });
+    }
+
+    tree_entries.sort_by_cached_key(TreeEntry::sort_key);
+    Ok(CacheTree {
+        name,
+        id: Some(odb.write(&Object::Tree(tree_entries))?),
+        entry_count: entries.len(),
+        subtrees,
+    })
+}
+
+impl IndexEntry {
+    // An entry for a file of the working tree, with its stat data
+    pub fn from_metadata(path: &str, metadata: &Metadata, mode: FileMode, id: ObjectId) -> Self {
+        Self {
+            ctime: IndexTime {
+                seconds: metadata.ctime() as u32,
+                nanoseconds: metadata.ctime_nsec() as u32,
+            },
+            mtime: IndexTime {
+                seconds: metadata.mtime() as u32,
+                nanoseconds: metadata.mtime_nsec() as u32,
+            },
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            mode,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+            id,
+            stage: 0,
+            assume_valid: false,
+            skip_worktree: false,
+            intent_to_add: false,
+            path: path.to_string(),
+        }
+    }
+
+    // Whether the file is most likely unchanged since it was staged, going by its stat data
+    // rather than its contents
+    pub fn is_up_to_date(&self, metadata: &Metadata) -> bool {
+        let fresh = Self::from_metadata(&self.path, metadata, self.mode, self.id);
+        let executable = metadata.permissions().mode() & 0o111 != 0;
+        let mode_matches = match self.mode {
+            FileMode::Regular => metadata.is_file() && !executable,
+            FileMode::Executable => metadata.is_file() && executable,
+            FileMode::Symlink => metadata.is_symlink(),
+            FileMode::Directory | FileMode::Gitlink => metadata.is_dir(),
+        };
+        mode_matches
+            && (
+                self.ctime, self.mtime, self.ino, self.uid, self.gid, self.size,
+            ) == (
+                fresh.ctime,
+                fresh.mtime,
+                fresh.ino,
+                fresh.uid,
+                fresh.gid,
+                fresh.size,
+            )
+    }
+
+    fn from_bytes(reader: &mut &[u8], version: u32, previous: Option<&str>) -> Result<Self> {
+        let start_len = reader.len();
+        let mut stat = [0; 10];
+        for field in stat.iter_mut() {
+            *field = read_u32(reader)?;
+        }
+        let id = ObjectId::from_bytes(take(reader, ObjectId::LEN)?)?;
+        let flags = read_u16(reader)?;
+        let extended_flags = match flags & EXTENDED {
+            0 => 0,
+            _ if version < 3 => return Err(anyhow!("Extended flags in a version 2 index")),
+            _ => read_u16(reader)?,
+        };
+
+        let path = match version {
+            4 => {
+                let previous = previous.unwrap_or_default().as_bytes();
+                let strip = read_offset_encoding(reader)? as usize;
+                let prefix_len = previous
+                    .len()
+                    .checked_sub(strip)
+                    .ok_or(anyhow!("Invalid path prefix length"))?;
+                let mut path = previous[..prefix_len].to_vec();
+                path.extend(read_until_nul(reader)?);
+                path
+            }
+            _ => {
+                let path = read_until_nul(reader)?.to_vec();
+                // Padded to a multiple of 8 bytes, including at least one NUL
+                let len = start_len - reader.len();
+                let padded = (len + 7) & !7;
+                take(reader, padded - len)?;
+                path
+            }
+        };
+        let name_len = (flags & NAME_MASK) as usize;
+        if name_len != path.len().min(NAME_MASK as usize) {
+            return Err(anyhow!("Path length doesn't match the flags"));
+        }
+
+        Ok(Self {
+            ctime: IndexTime {
+                seconds: stat[0],
+                nanoseconds: stat[1],
+            },
+            mtime: IndexTime {
+                seconds: stat[2],
+                nanoseconds: stat[3],
+            },
+            dev: stat[4],
+            ino: stat[5],
+            mode: mode_from_u32(stat[6])?,
+            uid: stat[7],
+            gid: stat[8],
+            size: stat[9],
+            id,
+            stage: ((flags & STAGE_MASK) >> 12) as u8,
+            assume_valid: flags & ASSUME_VALID != 0,
+            skip_worktree: extended_flags & SKIP_WORKTREE != 0,
+            intent_to_add: extended_flags & INTENT_TO_ADD != 0,
+            path: String::from_utf8(path).context("convert path to UTF8")?,
+        })
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>, version: u32, previous: &str) {
+        let start_len = bytes.len();
+        let stat = [
+            self.ctime.seconds,
+            self.ctime.nanoseconds,
+            self.mtime.seconds,
+            self.mtime.nanoseconds,
+            self.dev,
+            self.ino,
+            mode_to_u32(self.mode),
+            self.uid,
+            self.gid,
+            self.size,
+        ];
+        for field in stat {
+            bytes.extend(field.to_be_bytes());
+        }
+        bytes.extend(self.id.as_bytes());
+
+        let mut flags =
+            (self.path.len().min(NAME_MASK as usize) as u16) | ((self.stage as u16) << 12);
+        if self.assume_valid {
+            flags |= ASSUME_VALID;
+        }
+        if self.is_extended() {
+            flags |= EXTENDED;
+        }
+        bytes.extend(flags.to_be_bytes());
+        if self.is_extended() {
+            let mut extended_flags = 0u16;
+            if self.skip_worktree {
+                extended_flags |= SKIP_WORKTREE;
+            }
+            if self.intent_to_add {
+                extended_flags |= INTENT_TO_ADD;
+            }
+            bytes.extend(extended_flags.to_be_bytes());
+        }
+
+        match version {
+            4 => {
+                let common = previous
+                    .bytes()
+                    .zip(self.path.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                bytes.extend(encode_offset((previous.len() - common) as u64));
+                bytes.extend(&self.path.as_bytes()[common..]);
+                bytes.push(0);
+            }
+            _ => {
+                bytes.extend(self.path.as_bytes());
+                let len = bytes.len() - start_len;
+                bytes.resize(start_len + ((len + 8) & !7), 0);
+            }
+        }
+    }
+
+    fn is_extended(&self) -> bool {
+        self.skip_worktree || self.intent_to_add
+    }
+}
+
+impl CacheTree {
+    // Each directory is made up of:
+    // - its name, NUL-terminated
+    // - the number of entries it covers (-1 if invalid) in ASCII, a space, the number of
+    //   subtrees in ASCII and a newline
+    // - the id of the tree if valid
+    // - the subtrees, recursively
+    fn from_bytes(reader: &mut &[u8]) -> Result<Self> {
+        let name = String::from_utf8(read_until_nul(reader)?.to_vec())?;
+        let entry_count: i64 = read_ascii_until(reader, b' ')?.parse()?;
+        let subtree_count: usize = read_ascii_until(reader, b'\n')?.parse()?;
+        let id = match entry_count {
+            0.. => Some(ObjectId::from_bytes(take(reader, ObjectId::LEN)?)?),
+            _ => None,
+        };
+        let subtrees = (0..subtree_count)
+            .map(|_| Self::from_bytes(reader))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            name,
+            id,
+            entry_count: entry_count.max(0) as usize,
+            subtrees,
+        })
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        bytes.extend(self.name.as_bytes());
+        bytes.push(0);
+        match self.id {
+            Some(id) => {
+                bytes.extend(format!("{} {}\n", self.entry_count, self.subtrees.len()).bytes());
+                bytes.extend(id.as_bytes());
+            }
+            None => bytes.extend(format!("-1 {}\n", self.subtrees.len()).bytes()),
+        }
+        for subtree in &self.subtrees {
+            subtree.write_to(bytes);
+        }
+    }
+}
+
+impl ResolveUndo {
+    // The path and the modes of stages 1 to 3 as NUL-terminated octal strings, "0" for a missing
+    // stage, followed by the ids of the stages that exist
+    fn from_bytes(reader: &mut &[u8]) -> Result<Self> {
+        let path = String::from_utf8(read_until_nul(reader)?.to_vec())?;
+        let mut modes = [None; 3];
+        for mode in modes.iter_mut() {
+            *mode = match std::str::from_utf8(read_until_nul(reader)?)? {
+                "0" => None,
+                octal => Some(mode_from_u32(u32::from_str_radix(octal, 8)?)?),
+            };
+        }
+        let mut stages = [None; 3];
+        for (stage, mode) in stages.iter_mut().zip(modes) {
+            if let Some(mode) = mode {
+                *stage = Some((mode, ObjectId::from_bytes(take(reader, ObjectId::LEN)?)?));
+            }
+        }
+        Ok(Self { path, stages })
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        bytes.extend(self.path.as_bytes());
+        bytes.push(0);
+        for stage in &self.stages {
+            match stage {
+                Some((mode, _)) => bytes.extend(format!("{:o}", mode_to_u32(*mode)).bytes()),
+                None => bytes.push(b'0'),
+            }
+            bytes.push(0);
+        }
+        for (_, id) in self.stages.iter().flatten() {
+            bytes.extend(id.as_bytes());
+        }
+    }
+}
+
+// Only regular files, symlinks and gitlinks can be staged
+fn mode_from_u32(mode: u32) -> Result<FileMode> {
+    match mode {
+        0o100644 => Ok(FileMode::Regular),
+        0o100755 => Ok(FileMode::Executable),
+        0o120000 => Ok(FileMode::Symlink),
+        0o160000 => Ok(FileMode::Gitlink),
+        mode => Err(anyhow!("Invalid mode in index: {:o}", mode)),
+    }
+}
+
+fn mode_to_u32(mode: FileMode) -> u32 {
+    match mode {
+        FileMode::Regular => 0o100644,
+        FileMode::Executable => 0o100755,
+        FileMode::Symlink => 0o120000,
+        FileMode::Directory => 0o040000,
+        FileMode::Gitlink => 0o160000,
+    }
+}
+
+fn write_extension(bytes: &mut Vec<u8>, signature: &[u8], data: &[u8]) {
+    bytes.extend(signature);
+    bytes.extend((data.len() as u32).to_be_bytes());
+    bytes.extend(data);
+}
+
+fn read_u16(bytes: &mut &[u8]) -> Result<u16> {
+    Ok(u16::from_be_bytes(take(bytes, 2)?.try_into()?))
+}
+
+// Consumes the NUL too
+fn read_until_nul<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let end = bytes
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(anyhow!("Missing NUL terminator"))?;
+    let value = take(bytes, end)?;
+    take(bytes, 1)?;
+    Ok(value)
+}
+
+fn read_ascii_until<'a>(bytes: &mut &'a [u8], terminator: u8) -> Result<&'a str> {
+    let end = bytes
+        .iter()
+        .position(|&b| b == terminator)
+        .ok_or(anyhow!("Missing {:?} terminator", terminator as char))?;
+    let value = take(bytes, end)?;
+    take(bytes, 1)?;
+    std::str::from_utf8(value).context("parse number")
+}
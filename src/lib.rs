@@ -1,274 +1,3388 @@
 #[allow(unused)]
 use anyhow::{anyhow, Context, Result};
-use chrono::Local;
-use flate2::bufread::ZlibDecoder;
-use std::fs::{self, File};
-use std::io::{prelude::*, BufReader};
-use std::path::Path;
+use std::collections::BTreeSet;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 
+mod config;
+mod date;
+mod fsck;
 mod git_object;
+mod ident;
+mod ignore;
+mod index;
+mod lockfile;
+mod object_id;
+mod odb;
+mod pack;
+mod pathspec;
+mod reflog;
+mod refs;
+mod regex;
+mod repository;
+mod revision;
+mod status;
+mod wildmatch;
+mod worktree;
 
-use git_object::{Object, Tree};
+pub use config::{Config, ConfigEntry, ConfigError, ConfigFile};
+pub use git_object::{
+    Commit, FileMode, Header, Object, ObjectKind, Signature, Tag, Time, TreeEntry,
+};
+pub use index::{CacheTree, Index, IndexEntry, IndexTime, ResolveUndo};
+pub use object_id::ObjectId;
+pub use odb::{
+    abbreviate_object_id, resolve_object_id, LayeredObjectDatabase, LooseObjectDatabase,
+    MemoryObjectDatabase, ObjectDatabase,
+};
+pub use pack::{write_pack, Pack, PackIndex, PackedObjectDatabase};
+pub use reflog::ReflogEntry;
+pub use refs::{check_ref_format, PackedRef, Ref, Refs};
+pub use repository::Repository;
+pub use revision::resolve_revision;
 
-pub fn git_init() -> Result<()> {
-    _git_init(Path::new("."))
-}
+use ident::Role;
+use ignore::Ignore;
+use pathspec::{pattern_matches, Pathspec};
+use regex::Regex;
 
-fn _git_init(root: &Path) -> Result<()> {
-    let dot_git = root.join(".git");
-    fs::create_dir(&dot_git).context("Create .git directory")?;
-    fs::create_dir(dot_git.join("objects")).context("Create objects directory")?;
-    fs::create_dir(dot_git.join("refs")).context("Create refs directory")?;
-    fs::write(dot_git.join("HEAD"), "ref: refs/heads/master\n").context("Create HEAD file")?;
-    println!("Initialized git directory");
-    Ok(())
+#[derive(Debug, Clone, Default)]
+pub struct InitOptions {
+    pub bare: bool,
+    pub initial_branch: Option<String>,
+    pub template: Option<PathBuf>,
+    pub object_format: Option<String>,
+    pub quiet: bool,
 }
 
-// Wrapper function to make the underlying logic testable
-pub fn git_cat_file(blob_sha: &str) -> Result<()> {
-    _git_cat_file(blob_sha, Path::new("."), &mut std::io::stdout())
+pub fn git_init(directory: Option<&Path>, options: &InitOptions) -> Result<()> {
+    _git_init(
+        directory.unwrap_or(Path::new(".")),
+        options,
+        |name| env::var_os(name),
+        &mut std::io::stdout(),
+    )
 }
 
-// Implementation based on information in https://wyag.thb.lt/#objects
-fn _git_cat_file<W: Write>(blob_sha: &str, root: &Path, writer: &mut W) -> Result<()> {
-    let bytes = read_object(blob_sha, root)?;
-    let object = Object::from_bytes(&bytes)?;
+// Creates the repository in `directory` (`directory/.git` unless bare), or reinitializes it if it
+// already exists: missing files are added back, and existing ones are left alone
+fn _git_init<W: Write>(
+    directory: &Path,
+    options: &InitOptions,
+    env: impl Fn(&str) -> Option<OsString>,
+    writer: &mut W,
+) -> Result<()> {
+    match options.object_format.as_deref() {
+        None | Some("sha1") => {}
+        Some(format) => return Err(anyhow!("unknown hash algorithm '{}'", format)),
+    }
+    let global = Config::load(None, &env)?;
 
-    writer.write_all(&object.content_bytes())?;
+    fs::create_dir_all(directory)
+        .with_context(|| format!("Create directory {}", directory.display()))?;
+    let git_dir = match options.bare {
+        true => directory.to_path_buf(),
+        // `.git` may be a file pointing to the actual directory
+        false if directory.join(".git").is_file() => {
+            Repository::open(directory)?.git_dir().to_path_buf()
+        }
+        false => directory.join(".git"),
+    };
+    let reinit = git_dir.join("HEAD").is_file();
+
+    // `--template=` means no template at all
+    let template = options
+        .template
+        .clone()
+        .or(env("GIT_TEMPLATE_DIR").map(PathBuf::from))
+        .or(global.get("init.templateDir").map(PathBuf::from))
+        .unwrap_or(PathBuf::from("/usr/share/git-core/templates"));
+    if !template.as_os_str().is_empty() && template.is_dir() {
+        copy_template(&template, &git_dir).context("Copy template")?;
+    }
+
+    for dir in [
+        "objects/info",
+        "objects/pack",
+        "refs/heads",
+        "refs/tags",
+        "info",
+    ] {
+        fs::create_dir_all(git_dir.join(dir))
+            .with_context(|| format!("Create {} directory", dir))?;
+    }
+    let default_files = [
+        (
+            "description",
+            "Unnamed repository; edit this file 'description' to name the repository.\n",
+        ),
+        (
+            "info/exclude",
+            concat!(
+                "# git ls-files --others --exclude-from=.git/info/exclude\n",
+                "# Lines that start with '#' are comments.\n",
+                "# For a project mostly in C, the following would be a good set of\n",
+                "# exclude patterns (uncomment them if you want to use them):\n",
+                "# *.[oa]\n",
+                "# *~\n",
+            ),
+        ),
+    ];
+    for (file, contents) in default_files {
+        if !git_dir.join(file).exists() {
+            fs::write(git_dir.join(file), contents)
+                .with_context(|| format!("Create {} file", file))?;
+        }
+    }
+
+    if reinit {
+        if let Some(branch) = &options.initial_branch {
+            eprintln!("warning: re-init: ignored --initial-branch={}", branch);
+        }
+    } else {
+        let branch = options
+            .initial_branch
+            .as_deref()
+            .or(global.get("init.defaultBranch"))
+            .unwrap_or("master");
+        let head = format!("refs/heads/{}", branch);
+        if check_ref_format(&head).is_err() {
+            return Err(anyhow!("invalid initial branch name: '{}'", branch));
+        }
+        fs::write(git_dir.join("HEAD"), format!("ref: {}\n", head)).context("Create HEAD file")?;
+    }
+
+    ConfigFile::edit(&git_dir.join("config"), |config| {
+        config.set("core.repositoryformatversion", "0", None)?;
+        config.set("core.filemode", "true", None)?;
+        config.set("core.bare", &options.bare.to_string(), None)?;
+        if !options.bare {
+            config.set("core.logallrefupdates", "true", None)?;
+        }
+        Ok(())
+    })
+    .context("Create config file")?;
+
+    if !options.quiet {
+        let git_dir = git_dir.canonicalize().context("resolve git directory")?;
+        writeln!(
+            writer,
+            "{} Git repository in {}/",
+            if reinit {
+                "Reinitialized existing"
+            } else {
+                "Initialized empty"
+            },
+            git_dir.display()
+        )?;
+    }
+    Ok(())
+}
 
+// Copies the files of the template directory that don't exist in the repository yet
+fn copy_template(template: &Path, git_dir: &Path) -> Result<()> {
+    fs::create_dir_all(git_dir)?;
+    for entry in fs::read_dir(template)? {
+        let entry = entry?;
+        let target = git_dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_template(&entry.path(), &target)?;
+        } else if !target.exists() {
+            fs::copy(entry.path(), &target)
+                .with_context(|| format!("copy {}", entry.path().display()))?;
+        }
+    }
     Ok(())
 }
 
-fn read_object(sha: &str, root: &Path) -> Result<Vec<u8>> {
-    // Objects are stored in .git/objects
-    // They are in a folder named after the first two characters of the hash
-    // The remaining characters are used for the file name
-    let path = root
-        .join(".git")
-        .join("objects")
-        .join(&sha[..2])
-        .join(&sha[2..]);
+// Wrapper function to make the underlying logic testable
+pub fn git_cat_file(repo: &Repository, blob_sha: &str) -> Result<()> {
+    _git_cat_file(
+        blob_sha,
+        &repo.objects()?,
+        &repo.refs(),
+        &mut std::io::stdout(),
+    )
+}
 
-    let f = File::open(path)?;
-    let reader = BufReader::new(f);
+// Implementation based on information in https://wyag.thb.lt/#objects
+fn _git_cat_file<W: Write>(
+    blob_sha: &str,
+    odb: &dyn ObjectDatabase,
+    refs: &Refs,
+    writer: &mut W,
+) -> Result<()> {
+    let id = resolve_revision(odb, refs, blob_sha)?;
+    let object = odb.read(&id)?;
 
-    let mut z = ZlibDecoder::new(reader);
-    let mut buffer = Vec::new();
-    z.read_to_end(&mut buffer)?;
+    writer.write_all(&object.content_bytes())?;
 
-    Ok(buffer)
+    Ok(())
 }
 
-pub fn git_hash_object(file: &Path) -> Result<()> {
-    _git_hash_object(file, Path::new("."), &mut std::io::stdout())
+// The file is relative to the current directory
+pub fn git_hash_object(repo: &Repository, file: &Path) -> Result<()> {
+    _git_hash_object(
+        file,
+        Path::new("."),
+        &repo.objects()?,
+        &mut std::io::stdout(),
+    )
 }
 
-fn _git_hash_object<W: Write>(file: &Path, root: &Path, writer: &mut W) -> Result<()> {
+fn _git_hash_object<W: Write>(
+    file: &Path,
+    root: &Path,
+    odb: &dyn ObjectDatabase,
+    writer: &mut W,
+) -> Result<()> {
     let blob = Object::blobify(&root.join(file))?;
-    let hash = blob.hash();
-    blob.write(root)?;
-    writer.write_all(hex::encode(hash).as_bytes())?;
+    let hash = odb.write(&blob)?;
+    writer.write_all(hash.to_hex().as_bytes())?;
 
     Ok(())
 }
 
-pub fn git_ls_tree(tree_sha: &str) -> Result<()> {
-    _git_ls_tree(tree_sha, Path::new("."), &mut std::io::stdout())
+pub fn git_ls_tree(repo: &Repository, tree_sha: &str, name_only: bool) -> Result<()> {
+    let odb = repo.objects()?;
+    _git_ls_tree(
+        tree_sha,
+        name_only,
+        &odb,
+        &repo.refs(),
+        &mut std::io::stdout(),
+    )
 }
 
-fn _git_ls_tree<W: Write>(tree_sha: &str, root: &Path, writer: &mut W) -> Result<()> {
-    let object_bytes = read_object(tree_sha, root).context("read object")?;
-    let object = Object::from_bytes(&object_bytes).context("parse git object")?;
+fn _git_ls_tree<W: Write>(
+    tree_sha: &str,
+    name_only: bool,
+    odb: &dyn ObjectDatabase,
+    refs: &Refs,
+    writer: &mut W,
+) -> Result<()> {
+    let id = resolve_revision(odb, refs, tree_sha)?;
+    let mut object = odb.read(&id).context("read object")?;
+    // Like git, commits and tags are followed to their tree
+    if !matches!(object, Object::Tree(_)) {
+        let tree = resolve_revision(odb, refs, &format!("{}^{{tree}}", id))?;
+        object = odb.read(&tree).context("read tree")?;
+    }
 
     let Object::Tree(entries) = object else {
         return Err(anyhow!("Expected `tree` object, got: {}", object.kind()));
     };
 
-    let bytes: Vec<u8> = entries
-        .into_iter()
-        .flat_map(|entry| format!("{}\n", entry.name).as_bytes().to_owned())
-        .collect();
-
-    writer.write_all(&bytes)?;
+    // Names are written as is, like git does with `core.quotePath=false`
+    for entry in entries {
+        if !name_only {
+            write!(
+                writer,
+                "{:0>6} {} {}\t",
+                entry.mode,
+                entry.mode.object_kind(),
+                entry.id,
+            )?;
+        }
+        writer.write_all(&entry.name)?;
+        writeln!(writer)?;
+    }
 
     Ok(())
 }
 
-pub fn git_write_tree() -> Result<()> {
-    _git_write_tree(Path::new("."), &mut std::io::stdout())
+pub fn git_write_tree(repo: &Repository, prefix: Option<&str>, missing_ok: bool) -> Result<()> {
+    let odb = repo.objects()?;
+    _git_write_tree(
+        &repo.index_path(),
+        prefix,
+        missing_ok,
+        &odb,
+        &mut std::io::stdout(),
+    )
 }
 
-fn _git_write_tree<W: Write>(root: &Path, writer: &mut W) -> Result<()> {
-    let tree = Tree::from_working_directory(root).context("create tree from working directory")?;
-    let hash = tree.write(root)?;
+fn _git_write_tree<W: Write>(
+    index_path: &Path,
+    prefix: Option<&str>,
+    missing_ok: bool,
+    odb: &dyn ObjectDatabase,
+    writer: &mut W,
+) -> Result<()> {
+    let mut index = Index::open(index_path)?;
+    let cache_tree = index.cache_tree.clone();
+    let mut hash = index
+        .write_tree(odb, missing_ok)
+        .context("git-write-tree: error building trees")?;
+    // Save the trees for next time
+    if index.cache_tree != cache_tree {
+        index.write(index_path)?;
+    }
+
+    if let Some(prefix) = prefix {
+        let mut tree = index.cache_tree.as_ref().context("missing cache tree")?;
+        for name in prefix.split('/').filter(|name| !name.is_empty()) {
+            tree = tree
+                .subtrees
+                .iter()
+                .find(|subtree| subtree.name == name)
+                .with_context(|| format!("git-write-tree: prefix {} not found", prefix))?;
+        }
+        hash = tree.id.context("tree was not written")?;
+    }
 
     writer
-        .write_all(hex::encode(hash).as_bytes())
+        .write_all(hash.to_hex().as_bytes())
         .context("write hash")?;
 
     Ok(())
 }
 
-pub fn git_commit_tree(tree_sha: &str, parent_commit: &str, msg: &str) -> Result<()> {
-    _git_commit_tree(
-        tree_sha,
-        parent_commit,
-        msg,
-        Path::new("."),
+#[derive(Debug, Clone, Default)]
+pub struct AddOptions {
+    // Only stage tracked files
+    pub update: bool,
+    pub all: bool,
+    // Make the staged files executable or not, whatever their mode on disk
+    pub chmod: Option<bool>,
+    pub dry_run: bool,
+    // Add ignored files too
+    pub force: bool,
+}
+
+pub fn git_add(repo: &Repository, pathspecs: &[String], options: &AddOptions) -> Result<()> {
+    _git_add(
+        pathspecs,
+        &repo.prefix()?,
+        options,
+        repo,
         &mut std::io::stdout(),
     )
 }
 
-fn _git_commit_tree<W: Write>(
-    tree_sha: &str,
-    parent_commit: &str,
-    msg: &str,
-    root: &Path,
+fn _git_add<W: Write>(
+    pathspecs: &[String],
+    prefix: &str,
+    options: &AddOptions,
+    repo: &Repository,
     writer: &mut W,
 ) -> Result<()> {
-    let author = "bluthej <joffrey.bluthe@e.email>";
-    let committer = author;
-
-    let local = Local::now();
-    let timestamp = local.timestamp();
+    let root = repo.require_work_tree()?;
+    let odb = repo.objects()?;
+    let pathspec = Pathspec::new(pathspecs, prefix)?;
+    if pathspec.is_empty() && !options.update && !options.all {
+        return Err(anyhow!("Nothing specified, nothing added."));
+    }
 
-    let offset = local.offset().local_minus_utc();
-    let (sign, offset) = if offset < 0 {
-        ('-', -offset)
-    } else {
-        ('+', offset)
-    };
-    let sec = offset.rem_euclid(60);
-    let mins = offset.div_euclid(60);
-    let min = mins.rem_euclid(60);
-    let hour = mins.div_euclid(60);
-    let time = if sec == 0 {
-        format!("{} {}{:02}{:02}", timestamp, sign, hour, min)
-    } else {
-        format!("{} {}{:02}{:02}:{:02}", timestamp, sign, hour, min, sec)
+    let index_path = repo.index_path();
+    let mut index = Index::open(&index_path)?;
+    let index_mtime = worktree::index_mtime(&index_path);
+    let mut ignore = match options.force {
+        true => Ignore::none(),
+        false => Ignore::load(repo, |name| env::var_os(name))?,
     };
+    let listing = worktree::list_files(root, &index, &mut ignore)?;
+    let tracked: BTreeSet<String> = index
+        .entries()
+        .iter()
+        .map(|entry| entry.path.clone())
+        .collect();
+    // Ignored files are only an error when they are asked for explicitly
+    let mut ignored = Vec::new();
+    for pattern in pathspec.patterns() {
+        let mut candidates = listing.files.iter().chain(&tracked);
+        if candidates.any(|path| pattern_matches(pattern, path)) {
+            continue;
+        }
+        match listing.ignored.iter().any(|path| {
+            pattern_matches(pattern, path.trim_end_matches('/')) || pattern.starts_with(path)
+        }) {
+            true => ignored.push(pattern.as_str()),
+            false => return Err(anyhow!("pathspec '{}' did not match any files", pattern)),
+        }
+    }
+
+    // Like git, tracked paths come first, in index order, then new files. Since git 2.0, the
+    // files that are gone are staged as removed too.
+    let files: BTreeSet<String> = listing.files.into_iter().collect();
+    let new_files = files
+        .iter()
+        .filter(|path| !options.update && !tracked.contains(*path));
+    for path in tracked
+        .iter()
+        .chain(new_files)
+        .filter(|path| pathspec.matches(path))
+    {
+        if !files.contains(path) {
+            if options.dry_run {
+                writer.write_all(format!("remove '{}'\n", path).as_bytes())?;
+            }
+            index.remove(path);
+            continue;
+        }
+        let file = root.join(path);
+        let metadata = fs::symlink_metadata(&file)?;
+        let conflicted = index.entry(path, 0).is_none() && tracked.contains(path);
+        if let Some(entry) = index.entry(path, 0) {
+            if options.chmod.is_none() && worktree::is_unchanged(entry, &metadata, index_mtime) {
+                continue;
+            }
+        }
 
-    let body = format!(
-        "tree {}\nparent {}\nauthor {} {}\ncommitter {} {}\n\n{}\n",
-        tree_sha, parent_commit, author, time, committer, time, msg
-    );
+        let (mut mode, id) = worktree::hash_file(
+            &file,
+            &metadata,
+            if options.dry_run { None } else { Some(&odb) },
+        )
+        .with_context(|| format!("add {}", path))?;
+        let staged = index.entry(path, 0).map(|entry| (entry.mode, entry.id));
+        if !conflicted && options.chmod.is_none() && staged == Some((mode, id)) {
+            // Only the stat data changed
+            if !options.dry_run {
+                index.refresh(path, &metadata);
+            }
+            continue;
+        }
+        if let Some(executable) = options.chmod {
+            mode = match (mode, executable) {
+                (FileMode::Regular | FileMode::Executable, true) => FileMode::Executable,
+                (FileMode::Regular | FileMode::Executable, false) => FileMode::Regular,
+                _ => {
+                    return Err(anyhow!(
+                        "cannot chmod {}x '{}'",
+                        if executable { '+' } else { '-' },
+                        path
+                    ))
+                }
+            };
+        }
 
-    let commit = Object::Commit(body.as_bytes().to_owned());
+        if options.dry_run {
+            writer.write_all(format!("add '{}'\n", path).as_bytes())?;
+        }
+        index.add(IndexEntry::from_metadata(path, &metadata, mode, id));
+    }
 
-    let hash = commit.hash();
-    commit.write(root)?;
+    if !options.dry_run {
+        index.write(&index_path)?;
+    }
 
-    writer.write_all(hex::encode(hash).as_bytes())?;
+    if !ignored.is_empty() {
+        return Err(anyhow!(
+            "The following paths are ignored by one of your .gitignore files:\n{}\n\
+            hint: Use -f if you really want to add them.\n\
+            hint: Turn this message off by running\n\
+            hint: \"git config advice.addIgnoredFile false\"",
+            ignored.join("\n")
+        ));
+    }
 
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        fs::{self, File},
-        io::Cursor,
-        path::{Path, PathBuf},
-        process::Command,
-    };
+#[derive(Debug, Clone, Default)]
+pub struct RmOptions {
+    // Only remove the files from the index, keeping them in the working tree
+    pub cached: bool,
+    pub recursive: bool,
+    pub force: bool,
+}
 
-    use anyhow::Ok;
+pub fn git_rm(repo: &Repository, pathspecs: &[String], options: &RmOptions) -> Result<()> {
+    _git_rm(
+        pathspecs,
+        &repo.prefix()?,
+        options,
+        repo,
+        &mut std::io::stdout(),
+    )
+}
 
-    use super::*;
+fn _git_rm<W: Write>(
+    pathspecs: &[String],
+    prefix: &str,
+    options: &RmOptions,
+    repo: &Repository,
+    writer: &mut W,
+) -> Result<()> {
+    let root = repo.require_work_tree()?;
+    let pathspec = Pathspec::new(pathspecs, prefix)?;
+    if pathspec.is_empty() {
+        return Err(anyhow!(
+            "No pathspec was given. Which files should I remove?"
+        ));
+    }
 
-    const EMPTY_FILE_HASH: &str = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
-    const REPO_WITH_UNCOMMITED_FILES_HASH: &str = "7fa1ce0ba9e8fcc9d83854e44f48f0f25c477a1c";
+    let index_path = repo.index_path();
+    let mut index = Index::open(&index_path)?;
+    let index_mtime = worktree::index_mtime(&index_path);
+    let mut paths: Vec<String> = index
+        .entries()
+        .iter()
+        .filter(|entry| pathspec.matches(&entry.path))
+        .map(|entry| entry.path.clone())
+        .collect();
+    paths.dedup();
+    if let Some(unmatched) = pathspec.unmatched(paths.iter().map(String::as_str)) {
+        return Err(anyhow!("pathspec '{}' did not match any files", unmatched));
+    }
+    if !options.recursive {
+        for pattern in pathspec.patterns() {
+            if paths.iter().any(|path| {
+                path.len() > pattern.len()
+                    && path.starts_with(pattern.as_str())
+                    && path.as_bytes()[pattern.len()] == b'/'
+            }) {
+                return Err(anyhow!(
+                    "not removing '{}' recursively without -r",
+                    if pattern.is_empty() { "." } else { pattern }
+                ));
+            }
+        }
+    }
 
-    pub fn create_empty_git_repo(path: &Path) -> Result<()> {
-        let output = Command::new("git")
-            .arg("init")
-            .current_dir(path)
-            .output()
-            .context("Initialize git repo")?;
-        if !output.status.success() {
-            return Err(anyhow!("Did not initialize git repo successfully"));
+    if !options.force {
+        let odb = repo.objects()?;
+        let head = match resolve_revision(&odb, &repo.refs(), "HEAD^{tree}") {
+            Ok(tree) => Some(worktree::flatten_tree(&odb, &tree)?),
+            Err(_) => None,
+        };
+        let (mut staged, mut cached, mut local) = (Vec::new(), Vec::new(), Vec::new());
+        for path in &paths {
+            // Removing a conflicted path loses nothing that isn't in the other stages
+            let Some(entry) = index.entry(path, 0) else {
+                continue;
+            };
+            let Ok(metadata) = fs::symlink_metadata(root.join(path)) else {
+                continue;
+            };
+            let local_changes = match metadata.is_dir() && entry.mode != FileMode::Gitlink {
+                true => false,
+                false => {
+                    !worktree::is_unchanged(entry, &metadata, index_mtime)
+                        && worktree::hash_file(&root.join(path), &metadata, None)?
+                            != (entry.mode, entry.id)
+                }
+            };
+            let staged_changes = head
+                .as_ref()
+                .and_then(|head| head.get(path))
+                .is_none_or(|head| *head != (entry.mode, entry.id));
+
+            if local_changes && staged_changes {
+                if !options.cached || !entry.intent_to_add {
+                    staged.push(path.as_str());
+                }
+            } else if !options.cached {
+                if staged_changes {
+                    cached.push(path.as_str());
+                }
+                if local_changes {
+                    local.push(path.as_str());
+                }
+            }
         }
 
-        Ok(())
+        let problem = |paths: &[&str], singular: &str, plural: &str, hint: &str| {
+            anyhow!(
+                "the following {}:\n{}\n({})",
+                if paths.len() == 1 { singular } else { plural },
+                paths
+                    .iter()
+                    .map(|path| format!("    {}", path))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                hint
+            )
+        };
+        if !staged.is_empty() {
+            return Err(problem(
+                &staged,
+                "file has staged content different from both the\nfile and the HEAD",
+                "files have staged content different from both the\nfile and the HEAD",
+                "use -f to force removal",
+            ));
+        }
+        if !cached.is_empty() {
+            return Err(problem(
+                &cached,
+                "file has changes staged in the index",
+                "files have changes staged in the index",
+                "use --cached to keep the file, or -f to force removal",
+            ));
+        }
+        if !local.is_empty() {
+            return Err(problem(
+                &local,
+                "file has local modifications",
+                "files have local modifications",
+                "use --cached to keep the file, or -f to force removal",
+            ));
+        }
     }
 
-    fn create_git_repo(path: &Path) -> Result<()> {
-        create_empty_git_repo(path)?;
+    for path in &paths {
+        writer.write_all(format!("rm '{}'\n", path).as_bytes())?;
+        index.remove(path);
+    }
 
-        let output = Command::new("git")
-            .args(["commit", "--allow-empty", "-m", "'Empty commit'"])
-            .current_dir(path)
-            .output()
-            .context("Make empty commit")?;
-        if !output.status.success() {
-            return Err(anyhow!("Commit was not successful"));
+    if !options.cached {
+        for path in &paths {
+            let file = root.join(path);
+            let removed = match fs::symlink_metadata(&file) {
+                // Nested repositories are only removed when there's nothing in them
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir(&file),
+                Ok(_) => fs::remove_file(&file),
+                Err(_) => continue,
+            };
+            if removed.is_err() {
+                continue;
+            }
+            // The directories left empty go too
+            for dir in file.ancestors().skip(1) {
+                if dir == root || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
         }
+    }
 
-        Ok(())
+    index.write(&index_path)?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StatusFormat {
+    #[default]
+    Long,
+    Short,
+    // The short format with paths relative to the root, for scripts
+    Porcelain,
+    PorcelainV2,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StatusOptions {
+    pub format: StatusFormat,
+    // Show the branch in the short and porcelain formats too
+    pub branch: bool,
+    pub ignored: bool,
+}
+
+pub fn git_status(repo: &Repository, options: &StatusOptions) -> Result<()> {
+    _git_status(&repo.prefix()?, options, repo, &mut std::io::stdout())
+}
+
+fn _git_status<W: Write>(
+    prefix: &str,
+    options: &StatusOptions,
+    repo: &Repository,
+    writer: &mut W,
+) -> Result<()> {
+    status::status(repo, prefix, options, writer)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CheckIgnoreOptions {
+    // Show the pattern that matched, negated ones included
+    pub verbose: bool,
+    // Show the paths that didn't match anything too, with `-v`
+    pub non_matching: bool,
+    // Tracked files are never ignored, unless the index is left out
+    pub no_index: bool,
+}
+
+// Whether any of the paths is ignored, which is the exit status of the command
+pub fn git_check_ignore(
+    repo: &Repository,
+    paths: &[String],
+    options: &CheckIgnoreOptions,
+) -> Result<bool> {
+    _git_check_ignore(
+        paths,
+        &repo.prefix()?,
+        options,
+        repo,
+        &mut std::io::stdout(),
+    )
+}
+
+fn _git_check_ignore<W: Write>(
+    paths: &[String],
+    prefix: &str,
+    options: &CheckIgnoreOptions,
+    repo: &Repository,
+    writer: &mut W,
+) -> Result<bool> {
+    let root = repo.require_work_tree()?;
+    if paths.is_empty() {
+        return Err(anyhow!("no path specified"));
+    }
+    if options.non_matching && !options.verbose {
+        return Err(anyhow!("--non-matching is only valid with --verbose"));
     }
 
-    fn create_git_repo_with_uncommited_files(path: &Path) -> Result<()> {
-        create_empty_git_repo(path)?;
+    let index = match options.no_index {
+        true => Index::default(),
+        false => Index::open(&repo.index_path())?,
+    };
+    let mut ignore = Ignore::load(repo, |name| env::var_os(name))?;
+    let mut any_ignored = false;
+    for path in paths {
+        let pathspec = Pathspec::new(std::slice::from_ref(path), prefix)?;
+        let relative = &pathspec.patterns()[0];
+        let is_dir = path.ends_with('/')
+            || fs::symlink_metadata(root.join(relative)).is_ok_and(|metadata| metadata.is_dir());
+        let pattern = match index.entry(relative, 0) {
+            Some(_) => None,
+            None => ignore.matching_pattern(relative, is_dir)?,
+        };
+        let pattern = pattern.filter(|pattern| options.verbose || !pattern.negated);
 
-        fs::create_dir(path.join("src"))?;
-        let _ = File::create(path.join("src").join("main.rs"))?;
-        let _ = File::create(path.join("Cargo.toml"))?;
+        match (&pattern, options.verbose) {
+            (Some(pattern), true) => writer.write_all(
+                format!(
+                    "{}:{}:{}\t{}\n",
+                    pattern.source, pattern.line, pattern.text, path
+                )
+                .as_bytes(),
+            )?,
+            (Some(_), false) => writer.write_all(format!("{}\n", path).as_bytes())?,
+            (None, true) if options.non_matching => {
+                writer.write_all(format!("::\t{}\n", path).as_bytes())?
+            }
+            (None, _) => {}
+        }
+        any_ignored |= pattern.is_some();
+    }
 
-        Ok(())
+    Ok(any_ignored)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CommitOptions {
+    // Each `-m` is a paragraph of the message
+    pub messages: Vec<String>,
+    // `-F`, where `-` is the standard input
+    pub file: Option<PathBuf>,
+    // Replace the commit HEAD points to, keeping its parents, author and (without a new one)
+    // message
+    pub amend: bool,
+    pub allow_empty: bool,
+    // `Name <email>`
+    pub author: Option<String>,
+    pub date: Option<String>,
+}
+
+pub fn git_commit(repo: &Repository, options: &CommitOptions) -> Result<()> {
+    _git_commit(
+        options,
+        &repo.prefix()?,
+        repo,
+        &mut std::io::stdin(),
+        &mut std::io::stdout(),
+    )
+}
+
+fn _git_commit<R: Read, W: Write>(
+    options: &CommitOptions,
+    prefix: &str,
+    repo: &Repository,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<()> {
+    repo.require_work_tree()?;
+    let odb = repo.objects()?;
+    let refs = repo.refs();
+
+    let message = match (&options.messages[..], &options.file) {
+        ([_, ..], Some(_)) => return Err(anyhow!("Option -m cannot be combined with -F")),
+        ([], Some(file)) => {
+            let mut bytes = Vec::new();
+            match file.as_os_str() == "-" {
+                true => reader
+                    .read_to_end(&mut bytes)
+                    .map(|_| ())
+                    .context("read commit message from standard input")?,
+                false => {
+                    bytes = fs::read(file)
+                        .with_context(|| format!("could not read log file '{}'", file.display()))?
+                }
+            }
+            // Kept as bytes: like git, the message is stored in whatever encoding it was given
+            Some(bytes)
+        }
+        ([], None) if !options.amend => {
+            return Err(anyhow!("no commit message given, use -m or -F"))
+        }
+        ([], None) => None,
+        (messages, None) => Some(messages.join("\n\n").into_bytes()),
+    };
+
+    let head = refs.resolve("HEAD")?;
+    let amended = match (options.amend, head) {
+        (false, _) => None,
+        (true, None) => return Err(anyhow!("You have nothing to amend.")),
+        (true, Some(head)) => match odb.read(&head)? {
+            Object::Commit(commit) => Some(commit),
+            _ => return Err(anyhow!("HEAD {} is not a commit", head)),
+        },
+    };
+    let message = match (message, &amended) {
+        (Some(message), _) => cleanup_message(&message),
+        (None, Some(amended)) => amended.message.clone(),
+        (None, None) => unreachable!("a message is required unless amending"),
+    };
+    if message.is_empty() {
+        return Err(anyhow!("Aborting commit due to empty commit message."));
     }
 
-    fn create_git_repo_with_files(path: &Path) -> Result<()> {
-        create_git_repo_with_uncommited_files(path)
-            .context("Create git repo with uncommited files")?;
+    let index_path = repo.index_path();
+    let mut index = Index::open(&index_path)?;
+    if index.entries().iter().any(|entry| entry.stage != 0) {
+        return Err(anyhow!(
+            "Committing is not possible because you have unmerged files."
+        ));
+    }
+    let cache_tree = index.cache_tree.clone();
+    let tree = index.write_tree(&odb, false)?;
+    if index.cache_tree != cache_tree {
+        index.write(&index_path)?;
+    }
 
-        let output = Command::new("git")
-            .args(["add", "."])
-            .current_dir(path)
-            .output()
-            .context("Stage new files")?;
-        if !output.status.success() {
-            return Err(anyhow!("Staging was not successful"));
+    let parents = match &amended {
+        Some(amended) => amended.parents.clone(),
+        None => head.into_iter().collect(),
+    };
+    if !options.allow_empty && amended.is_none() {
+        let parent_tree = match parents.first() {
+            Some(parent) => resolve_revision(&odb, &refs, &format!("{}^{{tree}}", parent))?,
+            None => Object::Tree(Vec::new()).hash(),
+        };
+        if tree == parent_tree {
+            status::status(repo, prefix, &StatusOptions::default(), writer)?;
+            return Err(anyhow!("nothing to commit"));
         }
+    }
 
-        let output = Command::new("git")
-            .args(["commit", "-m", "'Add files'"])
-            .current_dir(path)
-            .output()
-            .context("Commit changes")?;
-        if !output.status.success() {
-            return Err(anyhow!("Commit was not successful"));
+    let mut author = match (&options.author, &amended) {
+        (Some(author), _) => parse_author(author)?,
+        (None, Some(amended)) => amended.author.clone(),
+        (None, None) => signature(refs.git_dir(), Role::Author)?,
+    };
+    if let Some(date) = &options.date {
+        author.time = date::parse_date(date, Time::now().seconds)?;
+    }
+    let committer = signature(refs.git_dir(), Role::Committer)?;
+
+    let root = parents.is_empty();
+    let subject = message.split(|&b| b == b'\n').next().unwrap_or_default();
+    let subject = String::from_utf8_lossy(subject).into_owned();
+    let hash = odb.write(&Object::Commit(Commit {
+        tree,
+        parents,
+        author,
+        committer: committer.clone(),
+        extra_headers: Vec::new(),
+        blank_line: true,
+        message,
+    }))?;
+
+    let initial = root && amended.is_none();
+    let kind = match (&amended, initial) {
+        (Some(_), _) => " (amend)",
+        (None, true) => " (initial)",
+        (None, false) => "",
+    };
+    refs.update(
+        "HEAD",
+        &hash,
+        head.as_ref(),
+        &committer,
+        &format!("commit{}: {}", kind, subject),
+    )?;
+
+    let branch = refs.resolve_name("HEAD")?;
+    writeln!(
+        writer,
+        "[{}{} {}] {}",
+        branch
+            .strip_prefix("refs/heads/")
+            .unwrap_or("detached HEAD"),
+        if initial { " (root-commit)" } else { "" },
+        abbreviate_object_id(&odb, &hash, 7)?,
+        subject
+    )?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CommitTreeOptions {
+    // None for a root commit, several for a merge
+    pub parents: Vec<String>,
+    // Each `-m` is a paragraph of the message
+    pub messages: Vec<String>,
+    // `-F`, where `-` is the standard input, which is also read without `-m` or `-F`
+    pub file: Option<PathBuf>,
+}
+
+pub fn git_commit_tree(
+    repo: &Repository,
+    tree_sha: &str,
+    options: &CommitTreeOptions,
+) -> Result<()> {
+    let odb = repo.objects()?;
+    let refs = repo.refs();
+    _git_commit_tree(
+        tree_sha,
+        options,
+        &odb,
+        &refs,
+        &mut std::io::stdin(),
+        &mut std::io::stdout(),
+    )
+}
+
+fn _git_commit_tree<R: Read, W: Write>(
+    tree_sha: &str,
+    options: &CommitTreeOptions,
+    odb: &dyn ObjectDatabase,
+    refs: &Refs,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<()> {
+    let tree = resolve_revision(odb, refs, &format!("{}^{{tree}}", tree_sha))
+        .context("resolve tree id")?;
+    let mut parents = Vec::new();
+    for parent in &options.parents {
+        let id = resolve_revision(odb, refs, &format!("{}^{{commit}}", parent))
+            .with_context(|| format!("resolve parent id {}", parent))?;
+        match parents.contains(&id) {
+            true => eprintln!("error: duplicate parent {} ignored", id),
+            false => parents.push(id),
         }
+    }
 
-        Ok(())
+    // Like git, the paragraphs are used as they are, with no cleanup
+    let mut message = Vec::new();
+    for paragraph in &options.messages {
+        if !message.is_empty() {
+            message.push(b'\n');
+        }
+        message.extend_from_slice(paragraph.as_bytes());
+        if !message.ends_with(b"\n") {
+            message.push(b'\n');
+        }
+    }
+    match &options.file {
+        Some(file) if file.as_os_str() != "-" => {
+            if !message.is_empty() {
+                message.push(b'\n');
+            }
+            message.extend(
+                fs::read(file).with_context(|| format!("could not read {}", file.display()))?,
+            );
+        }
+        None if !options.messages.is_empty() => {}
+        _ => {
+            if !message.is_empty() {
+                message.push(b'\n');
+            }
+            reader
+                .read_to_end(&mut message)
+                .context("read commit message from standard input")?;
+        }
     }
 
-    fn get_sha(git_ref: &str, path: &Path) -> Result<String> {
-        let output = Command::new("git")
-            .args(["rev-parse", git_ref])
-            .current_dir(path)
-            .output()
-            .context(format!("Get hash of {}", git_ref))?;
-        if !output.status.success() {
-            return Err(anyhow!("Did not get last hash successfully"));
+    let author = signature(refs.git_dir(), Role::Author)?;
+    let committer = signature(refs.git_dir(), Role::Committer)?;
+
+    let commit = Object::Commit(Commit {
+        tree,
+        parents,
+        author,
+        committer,
+        extra_headers: Vec::new(),
+        blank_line: true,
+        message,
+    });
+
+    let hash = odb.write(&commit)?;
+
+    writer.write_all(hash.to_hex().as_bytes())?;
+
+    Ok(())
+}
+
+pub fn git_mktag(repo: &Repository) -> Result<()> {
+    let odb = repo.objects()?;
+    _git_mktag(&odb, &mut std::io::stdin(), &mut std::io::stdout())
+}
+
+fn _git_mktag<R: Read, W: Write>(
+    odb: &dyn ObjectDatabase,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<()> {
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .context("read tag from input")?;
+
+    let tag = Tag::from_bytes(&bytes).context("parse tag")?;
+    if tag.tagger.is_none() {
+        return Err(anyhow!("Missing `tagger` header in tag"));
+    }
+    let target = odb
+        .read(&tag.object)
+        .with_context(|| format!("read tagged object {}", tag.object))?;
+    if target.kind() != tag.kind {
+        return Err(anyhow!(
+            "Object {} is a {}, not a {}",
+            tag.object,
+            target.kind(),
+            tag.kind
+        ));
+    }
+
+    let tag = Object::Tag(tag);
+    if tag.content_bytes() != bytes {
+        return Err(anyhow!("Tag is not in canonical format"));
+    }
+
+    let hash = odb.write(&tag)?;
+
+    writer.write_all(hash.to_hex().as_bytes())?;
+
+    Ok(())
+}
+
+pub fn git_tag(
+    repo: &Repository,
+    name: &str,
+    object: Option<&str>,
+    message: Option<&str>,
+    force: bool,
+) -> Result<()> {
+    _git_tag(
+        name,
+        object,
+        message,
+        force,
+        &repo.objects()?,
+        &repo.refs(),
+        &mut std::io::stdout(),
+    )
+}
+
+// Without a message this creates a lightweight tag, i.e. a ref pointing directly to the object
+fn _git_tag<W: Write>(
+    name: &str,
+    object: Option<&str>,
+    message: Option<&str>,
+    force: bool,
+    odb: &dyn ObjectDatabase,
+    refs: &Refs,
+    writer: &mut W,
+) -> Result<()> {
+    let ref_name = format!("refs/tags/{}", name);
+    if name.starts_with('-') || check_ref_format(&ref_name).is_err() {
+        return Err(anyhow!("'{}' is not a valid tag name", name));
+    }
+
+    let previous = refs.resolve(&ref_name)?;
+    if previous.is_some() && !force {
+        return Err(anyhow!("tag '{}' already exists", name));
+    }
+
+    let object = match object {
+        Some(object) => resolve_revision(odb, refs, object)?,
+        None => refs
+            .resolve("HEAD")?
+            .ok_or(anyhow!("HEAD does not point to any commit"))?,
+    };
+
+    let tagger = signature(refs.git_dir(), Role::Committer)?;
+    let hash = match message {
+        Some(message) => {
+            let target = odb
+                .read(&object)
+                .with_context(|| format!("read object {}", object))?;
+            let tag = Object::Tag(Tag {
+                object,
+                kind: target.kind(),
+                name: name.to_string(),
+                tagger: Some(tagger.clone()),
+                extra_headers: Vec::new(),
+                blank_line: true,
+                message: format!("{}\n", message).into_bytes(),
+                signature: None,
+            });
+            odb.write(&tag)?
+        }
+        None => object,
+    };
+
+    // Fail rather than overwrite a tag created concurrently
+    let expected = previous.unwrap_or(ObjectId::null());
+    refs.update(
+        &ref_name,
+        &hash,
+        Some(&expected),
+        &tagger,
+        &format!("tag: tagging {}", object),
+    )?;
+
+    if let Some(previous) = previous.filter(|&previous| previous != hash) {
+        writeln!(
+            writer,
+            "Updated tag '{}' (was {})",
+            name,
+            previous.abbrev(7)
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn git_repack(repo: &Repository, all: bool, delete: bool) -> Result<()> {
+    _git_repack(all, delete, repo, &mut std::io::stdout())
+}
+
+// Packs loose objects (and with `all`, the objects of existing packs too) into a new pack. With
+// `delete`, the objects that were packed are removed from where they were before.
+fn _git_repack<W: Write>(all: bool, delete: bool, repo: &Repository, writer: &mut W) -> Result<()> {
+    let objects = repo.git_dir().join("objects");
+    let loose = LooseObjectDatabase::new(&objects);
+    let packs = PackedObjectDatabase::open(objects.join("pack")).context("open packs")?;
+
+    let loose_ids: Vec<_> = loose.iter()?.collect();
+    let mut ids = loose_ids.clone();
+    if all {
+        ids.extend(packs.iter()?);
+    }
+    if ids.is_empty() {
+        writeln!(writer, "Nothing new to pack.")?;
+        return Ok(());
+    }
+
+    let odb = repo.objects()?;
+    let checksum = write_pack(&odb, &ids, &objects.join("pack")).context("write pack")?;
+
+    if delete {
+        for id in &loose_ids {
+            loose.remove(id)?;
+        }
+        if all {
+            let old_packs = packs
+                .packs()
+                .iter()
+                .filter(|pack| pack.index().pack_checksum() != checksum);
+            for pack in old_packs {
+                fs::remove_file(pack.path().with_extension("idx")).context("remove old index")?;
+                fs::remove_file(pack.path()).context("remove old pack")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn git_gc(repo: &Repository) -> Result<()> {
+    _git_repack(true, true, repo, &mut std::io::stdout())
+}
+
+pub fn git_fsck(repo: &Repository, unreachable: bool, strict: bool) -> Result<()> {
+    _git_fsck(unreachable, strict, repo, &mut std::io::stdout())
+}
+
+fn _git_fsck<W: Write>(
+    unreachable: bool,
+    strict: bool,
+    repo: &Repository,
+    writer: &mut W,
+) -> Result<()> {
+    fsck::fsck(repo.git_dir(), unreachable, strict, writer)
+}
+
+pub fn git_update_ref(
+    repo: &Repository,
+    name: &str,
+    new: Option<&str>,
+    old: Option<&str>,
+    message: Option<&str>,
+) -> Result<()> {
+    let odb = repo.objects()?;
+    _git_update_ref(name, new, old, message, &odb, &repo.refs())
+}
+
+// Without a new value the ref is deleted. An empty or null old value means the ref must not exist.
+fn _git_update_ref(
+    name: &str,
+    new: Option<&str>,
+    old: Option<&str>,
+    message: Option<&str>,
+    odb: &dyn ObjectDatabase,
+    refs: &Refs,
+) -> Result<()> {
+    let old = match old {
+        Some("") => Some(ObjectId::null()),
+        Some(old) => Some(resolve_revision(odb, refs, old)?),
+        None => None,
+    };
+
+    match new {
+        Some(new) => {
+            let new = resolve_revision(odb, refs, new)?;
+            let message = message.unwrap_or_default();
+            let committer = signature(refs.git_dir(), Role::Committer)?;
+            refs.update(name, &new, old.as_ref(), &committer, message)
+        }
+        None => refs.delete(name, old.as_ref()),
+    }
+}
+
+pub fn git_reflog_show(repo: &Repository, name: Option<&str>) -> Result<()> {
+    let odb = repo.objects()?;
+    _git_reflog_show(name, &odb, &repo.refs(), &mut std::io::stdout())
+}
+
+// Shows the updates of a ref (HEAD by default), most recent first
+fn _git_reflog_show<W: Write>(
+    name: Option<&str>,
+    odb: &dyn ObjectDatabase,
+    refs: &Refs,
+    writer: &mut W,
+) -> Result<()> {
+    let name = name.unwrap_or("HEAD");
+    let full_name = refs
+        .dwim(name)?
+        .ok_or(anyhow!("ambiguous argument '{}': unknown revision", name))?;
+
+    let entries = refs.read_reflog(&full_name)?;
+    for (i, entry) in entries.iter().rev().enumerate() {
+        writeln!(
+            writer,
+            "{} {}@{{{}}}: {}",
+            abbreviate_object_id(odb, &entry.new, 7)?,
+            name,
+            i,
+            entry.message
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn git_reflog_expire(
+    repo: &Repository,
+    expire: Option<&str>,
+    all: bool,
+    names: &[String],
+) -> Result<()> {
+    _git_reflog_expire(expire, all, names, &repo.refs())
+}
+
+// Removes the reflog entries older than `expire` (90 days by default, `never` keeps everything)
+// from the given reflogs, or all of them
+fn _git_reflog_expire(
+    expire: Option<&str>,
+    all: bool,
+    names: &[String],
+    refs: &Refs,
+) -> Result<()> {
+    let now = Time::now().seconds;
+    let cutoff = match expire.unwrap_or("90.days.ago") {
+        "never" | "false" => return Ok(()),
+        // Like git, even entries from this very second are expired
+        "all" | "now" => i64::MAX,
+        expire => date::parse_approxidate(expire, now)?,
+    };
+
+    let names = if all {
+        refs.reflogs()?
+    } else {
+        names
+            .iter()
+            .map(|name| Ok(refs.dwim(name)?.unwrap_or(name.clone())))
+            .collect::<Result<_>>()?
+    };
+    for name in names {
+        refs.expire_reflog(&name, |entry| entry.committer.time.seconds >= cutoff)?;
+    }
+
+    Ok(())
+}
+
+pub fn git_symbolic_ref(
+    repo: &Repository,
+    name: &str,
+    target: Option<&str>,
+    short: bool,
+) -> Result<()> {
+    _git_symbolic_ref(name, target, short, &repo.refs(), &mut std::io::stdout())
+}
+
+// Reads the ref `name` points to, or makes it point to `target`
+fn _git_symbolic_ref<W: Write>(
+    name: &str,
+    target: Option<&str>,
+    short: bool,
+    refs: &Refs,
+    writer: &mut W,
+) -> Result<()> {
+    if let Some(target) = target {
+        return refs.set_symbolic(name, target);
+    }
+
+    let Some(Ref::Symbolic(target)) = refs.read(name)? else {
+        return Err(anyhow!("ref {} is not a symbolic ref", name));
+    };
+    if short {
+        writeln!(writer, "{}", shorten_ref_name(&target))?;
+    } else {
+        writeln!(writer, "{}", target)?;
+    }
+    Ok(())
+}
+
+pub struct ShowRefOptions {
+    pub head: bool,
+    pub heads: bool,
+    pub tags: bool,
+    pub dereference: bool,
+    pub verify: bool,
+}
+
+pub fn git_show_ref(
+    repo: &Repository,
+    patterns: &[String],
+    options: &ShowRefOptions,
+) -> Result<()> {
+    _git_show_ref(
+        patterns,
+        options,
+        &repo.objects()?,
+        &repo.refs(),
+        &mut std::io::stdout(),
+    )
+}
+
+// Lists refs as `<id> <name>`. Patterns match the end of ref names on a component boundary, e.g.
+// `master` matches `refs/heads/master` and `refs/remotes/origin/master`. With `verify`, patterns
+// are full ref names instead.
+fn _git_show_ref<W: Write>(
+    patterns: &[String],
+    options: &ShowRefOptions,
+    odb: &dyn ObjectDatabase,
+    refs: &Refs,
+    writer: &mut W,
+) -> Result<()> {
+    let mut found = Vec::new();
+    if options.verify {
+        for pattern in patterns {
+            let id = match pattern.as_str() {
+                "HEAD" => refs.resolve("HEAD")?,
+                name if name.starts_with("refs/") => match refs.read(name)? {
+                    Some(_) => refs.resolve(name)?,
+                    None => None,
+                },
+                _ => None,
+            };
+            let id = id.ok_or(anyhow!("'{}' - not a valid ref", pattern))?;
+            found.push((pattern.clone(), id));
+        }
+    } else {
+        if options.head {
+            if let Some(head) = refs.resolve("HEAD")? {
+                found.push(("HEAD".to_string(), head));
+            }
+        }
+        let matches = |name: &str| {
+            let prefix_ok = (!options.heads && !options.tags)
+                || (options.heads && name.starts_with("refs/heads/"))
+                || (options.tags && name.starts_with("refs/tags/"));
+            let pattern_ok = patterns.is_empty()
+                || patterns.iter().any(|pattern| {
+                    name == pattern
+                        || name
+                            .strip_suffix(pattern.as_str())
+                            .is_some_and(|rest| rest.ends_with('/'))
+                });
+            prefix_ok && pattern_ok
+        };
+        found.extend(
+            refs.list("refs/")?
+                .into_iter()
+                .filter(|(name, _)| matches(name)),
+        );
+    }
+
+    if found.is_empty() {
+        return Err(anyhow!("No matching refs"));
+    }
+
+    for (name, id) in found {
+        writeln!(writer, "{} {}", id, name)?;
+        if !options.dereference {
+            continue;
+        }
+        // Annotated tags are followed by the object they point to
+        let mut peeled = id;
+        while let Object::Tag(tag) = odb.read(&peeled)? {
+            peeled = tag.object;
+        }
+        if peeled != id {
+            writeln!(writer, "{} {}^{{}}", peeled, name)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn git_rev_parse(
+    repo: &Repository,
+    revisions: &[String],
+    verify: bool,
+    short: Option<usize>,
+) -> Result<()> {
+    let odb = repo.objects()?;
+    let refs = repo.refs();
+    _git_rev_parse(
+        revisions,
+        verify,
+        short,
+        &odb,
+        &refs,
+        &mut std::io::stdout(),
+    )
+}
+
+// Prints the object id of each revision. `^<rev>` (excluded revision) and `<a>..<b>` (range) are
+// printed as git does, `<b>` then `^<a>`, with HEAD when one end is missing.
+fn _git_rev_parse<W: Write>(
+    revisions: &[String],
+    verify: bool,
+    short: Option<usize>,
+    odb: &dyn ObjectDatabase,
+    refs: &Refs,
+    writer: &mut W,
+) -> Result<()> {
+    // Like git, abbreviating implies `--verify`
+    let verify = verify || short.is_some();
+    if verify && revisions.len() != 1 {
+        return Err(anyhow!("Needed a single revision"));
+    }
+
+    let mut output = Vec::new();
+    for revision in revisions {
+        if verify {
+            output.push(("", resolve_revision(odb, refs, revision)?));
+        } else if let Some((from, to)) = split_range(revision) {
+            let or_head = |rev: &str| if rev.is_empty() { "HEAD" } else { rev }.to_string();
+            if to.starts_with('.') {
+                return Err(anyhow!(
+                    "Symmetric differences are not supported: {}",
+                    revision
+                ));
+            }
+            // Like git, the dots may belong to a single revision after all
+            let range = resolve_revision(odb, refs, &or_head(to))
+                .and_then(|to| Ok((to, resolve_revision(odb, refs, &or_head(from))?)));
+            match range {
+                Ok((to, from)) => output.extend([("", to), ("^", from)]),
+                Err(e) => match resolve_revision(odb, refs, revision) {
+                    Ok(id) => output.push(("", id)),
+                    Err(_) => return Err(e),
+                },
+            }
+        } else if let Some(excluded) = revision.strip_prefix('^') {
+            output.push(("^", resolve_revision(odb, refs, excluded)?));
+        } else {
+            output.push(("", resolve_revision(odb, refs, revision)?));
+        }
+    }
+
+    for (prefix, id) in output {
+        match short {
+            Some(len) => writeln!(writer, "{}{}", prefix, abbreviate_object_id(odb, &id, len)?)?,
+            None => writeln!(writer, "{}{}", prefix, id)?,
+        }
+    }
+
+    Ok(())
+}
+
+// `<a>..<b>`, where the dots can't be in the path of a `<rev>:<path>`, in a `:/<text>` search or
+// in a `<rev>^{<something>}`
+fn split_range(revision: &str) -> Option<(&str, &str)> {
+    let mut braces = 0;
+    for (i, c) in revision.char_indices() {
+        match c {
+            ':' if braces == 0 => return None,
+            '{' if braces > 0 || revision[..i].ends_with('^') => braces += 1,
+            '}' if braces > 0 => braces -= 1,
+            '.' if braces == 0 && revision[i..].starts_with("..") => {
+                return Some((&revision[..i], &revision[i + 2..]))
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Which files `git config` reads or writes. Without one, it reads all of them and writes to the
+// repository's.
+pub enum ConfigLocation {
+    Global,
+    Local,
+    File(PathBuf),
+}
+
+pub enum ConfigAction {
+    Get {
+        key: String,
+        value_pattern: Option<String>,
+    },
+    GetAll {
+        key: String,
+        value_pattern: Option<String>,
+    },
+    GetRegexp {
+        name_pattern: String,
+        value_pattern: Option<String>,
+    },
+    List,
+    Set {
+        key: String,
+        value: String,
+        value_pattern: Option<String>,
+    },
+    Add {
+        key: String,
+        value: String,
+    },
+    Unset {
+        key: String,
+        value_pattern: Option<String>,
+    },
+}
+
+// Works outside of a repository too, for global settings
+pub fn git_config(
+    repo: Option<&Repository>,
+    location: Option<&ConfigLocation>,
+    action: &ConfigAction,
+) -> Result<()> {
+    _git_config(
+        action,
+        location,
+        repo.map(Repository::git_dir),
+        |name| env::var_os(name),
+        &mut std::io::stdout(),
+    )
+}
+
+fn _git_config<W: Write>(
+    action: &ConfigAction,
+    location: Option<&ConfigLocation>,
+    git_dir: Option<&Path>,
+    env: impl Fn(&str) -> Option<OsString>,
+    writer: &mut W,
+) -> Result<()> {
+    let local = || {
+        git_dir
+            .map(|git_dir| git_dir.join("config"))
+            .ok_or(anyhow!("not in a git directory"))
+    };
+    let files = match location {
+        None => None,
+        Some(ConfigLocation::Global) => Some(Config::global_files(&env)),
+        Some(ConfigLocation::Local) => Some(vec![local()?]),
+        Some(ConfigLocation::File(path)) => Some(vec![path.clone()]),
+    };
+
+    // Like git, global settings go to `~/.gitconfig` unless only the XDG file exists
+    let edit = |edit: &dyn Fn(&mut ConfigFile) -> Result<()>| -> Result<()> {
+        let path = match &files {
+            Some(files) => files
+                .iter()
+                .rev()
+                .find(|file| file.exists())
+                .or(files.last())
+                .cloned()
+                .ok_or(anyhow!("no config file to write to"))?,
+            None => local()?,
+        };
+        ConfigFile::edit(&path, edit)
+    };
+    match action {
+        ConfigAction::Set {
+            key,
+            value,
+            value_pattern,
+        } => return edit(&|file| file.set(key, value, value_pattern.as_deref())),
+        ConfigAction::Add { key, value } => return edit(&|file| file.add(key, value)),
+        ConfigAction::Unset { key, value_pattern } => {
+            return edit(&|file| file.unset(key, value_pattern.as_deref()))
+        }
+        _ => {}
+    }
+
+    let config = match &files {
+        Some(files) => Config::from_files(files)?,
+        None => Config::load(git_dir, env)?,
+    };
+    let value = |entry: &ConfigEntry| entry.value.clone().unwrap_or_default();
+    match action {
+        ConfigAction::Get { key, value_pattern } | ConfigAction::GetAll { key, value_pattern } => {
+            // Like git, reading a malformed key fails like reading a missing one, with exit code 1
+            config::split_key(key).map_err(|e| anyhow!("{}", e))?;
+            let value_matches = config::value_matcher(value_pattern.as_deref())?;
+            let values: Vec<&str> = config
+                .get_all(key)
+                .into_iter()
+                .filter(|value| value_matches(value))
+                .collect();
+            let values = match action {
+                ConfigAction::Get { .. } => values.last().map(|value| vec![*value]),
+                _ => Some(values).filter(|values| !values.is_empty()),
+            };
+            for value in values.ok_or(anyhow!("{} is not set", key))? {
+                writeln!(writer, "{}", value)?;
+            }
+        }
+        ConfigAction::GetRegexp {
+            name_pattern,
+            value_pattern,
+        } => {
+            let name_regex = Regex::new(name_pattern)?;
+            let value_matches = config::value_matcher(value_pattern.as_deref())?;
+            let mut found = false;
+            for entry in config.entries() {
+                if !name_regex.is_match(&entry.key) || !value_matches(&value(entry)) {
+                    continue;
+                }
+                found = true;
+                match &entry.value {
+                    Some(value) => writeln!(writer, "{} {}", entry.key, value)?,
+                    None => writeln!(writer, "{}", entry.key)?,
+                }
+            }
+            if !found {
+                return Err(anyhow!("No key matches {}", name_pattern));
+            }
+        }
+        ConfigAction::List => {
+            for entry in config.entries() {
+                match &entry.value {
+                    Some(value) => writeln!(writer, "{}={}", entry.key, value)?,
+                    None => writeln!(writer, "{}", entry.key)?,
+                }
+            }
+        }
+        ConfigAction::Set { .. } | ConfigAction::Add { .. } | ConfigAction::Unset { .. } => {
+            unreachable!("handled above")
+        }
+    }
+
+    Ok(())
+}
+
+// `refs/heads/master` -> `master`, `refs/tags/v1.0` -> `v1.0`...
+fn shorten_ref_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+// `--author "Name <email>"`
+fn parse_author(author: &str) -> Result<Signature> {
+    let invalid = || anyhow!("--author '{}' is not 'Name <email>'", author);
+    let (name, email) = author.split_once('<').ok_or_else(invalid)?;
+    let email = email.strip_suffix('>').ok_or_else(invalid)?;
+    let name = name.trim();
+    if name.is_empty() || email.contains(['<', '>']) {
+        return Err(invalid());
+    }
+    Ok(Signature::now(name, email))
+}
+
+// Like git's default `whitespace` cleanup: trailing whitespace is stripped, runs of blank lines
+// collapse into one, and there are no blank lines at the start and end
+fn cleanup_message(message: &[u8]) -> Vec<u8> {
+    let mut cleaned = Vec::new();
+    let mut blank = false;
+    for line in message.split(|&b| b == b'\n').map(<[u8]>::trim_ascii_end) {
+        if line.is_empty() {
+            blank = !cleaned.is_empty();
+            continue;
+        }
+        if blank {
+            cleaned.push(b'\n');
+            blank = false;
+        }
+        cleaned.extend(line);
+        cleaned.push(b'\n');
+    }
+    cleaned
+}
+
+// The identity from the environment and the config of the repository at `git_dir`
+fn signature(git_dir: &Path, role: Role) -> Result<Signature> {
+    let config = Config::load(Some(git_dir), |name| env::var_os(name))?;
+    ident::signature(&config, role, |name| env::var_os(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        io::Cursor,
+        os::unix::fs::{MetadataExt, PermissionsExt},
+        path::{Path, PathBuf},
+        process::Command,
+    };
+
+    use anyhow::Ok;
+
+    use super::*;
+
+    const EMPTY_FILE_HASH: &str = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+    const REPO_WITH_UNCOMMITED_FILES_HASH: &str = "7fa1ce0ba9e8fcc9d83854e44f48f0f25c477a1c";
+
+    pub fn create_empty_git_repo(path: &Path) -> Result<()> {
+        let output = Command::new("git")
+            .arg("init")
+            .current_dir(path)
+            .output()
+            .context("Initialize git repo")?;
+        if !output.status.success() {
+            return Err(anyhow!("Did not initialize git repo successfully"));
+        }
+
+        Ok(())
+    }
+
+    fn create_git_repo(path: &Path) -> Result<()> {
+        create_empty_git_repo(path)?;
+
+        let output = Command::new("git")
+            .args(["commit", "--allow-empty", "-m", "'Empty commit'"])
+            .current_dir(path)
+            .output()
+            .context("Make empty commit")?;
+        if !output.status.success() {
+            return Err(anyhow!("Commit was not successful"));
+        }
+
+        Ok(())
+    }
+
+    fn create_git_repo_with_uncommited_files(path: &Path) -> Result<()> {
+        create_empty_git_repo(path)?;
+
+        fs::create_dir(path.join("src"))?;
+        let _ = File::create(path.join("src").join("main.rs"))?;
+        let _ = File::create(path.join("Cargo.toml"))?;
+
+        Ok(())
+    }
+
+    fn create_git_repo_with_files(path: &Path) -> Result<()> {
+        create_git_repo_with_uncommited_files(path)
+            .context("Create git repo with uncommited files")?;
+
+        let output = Command::new("git")
+            .args(["add", "."])
+            .current_dir(path)
+            .output()
+            .context("Stage new files")?;
+        if !output.status.success() {
+            return Err(anyhow!("Staging was not successful"));
+        }
+
+        let output = Command::new("git")
+            .args(["commit", "-m", "'Add files'"])
+            .current_dir(path)
+            .output()
+            .context("Commit changes")?;
+        if !output.status.success() {
+            return Err(anyhow!("Commit was not successful"));
+        }
+
+        Ok(())
+    }
+
+    fn git(args: &[&str], path: &Path) -> Result<String> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(path)
+            .output()
+            .with_context(|| format!("Run git {}", args.join(" ")))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "`git {}` was not successful: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        String::from_utf8(output.stdout).map_err(From::from)
+    }
+
+    // A few commits modifying a large file so that `git gc` stores them as deltas
+    fn create_git_repo_with_history(path: &Path) -> Result<()> {
+        create_empty_git_repo(path)?;
+
+        let mut lines: Vec<String> = (0..200).map(|i| format!("line {}", i)).collect();
+        for i in 0..5 {
+            lines[i * 40] = format!("changed in commit {}", i);
+            fs::write(path.join("file.txt"), lines.join("\n"))?;
+            git(&["add", "file.txt"], path)?;
+            git(&["commit", "-m", &format!("Commit {}", i)], path)?;
+        }
+        git(&["tag", "-a", "v1.0", "-m", "Release 1.0"], path)?;
+
+        Ok(())
+    }
+
+    fn open_object_database(root: &Path) -> Result<LayeredObjectDatabase> {
+        Repository::open(root)?.objects()
+    }
+
+    fn open_refs(root: &Path) -> Refs {
+        Refs::new(root.join(".git"))
+    }
+
+    fn get_sha(git_ref: &str, path: &Path) -> Result<String> {
+        let output = Command::new("git")
+            .args(["rev-parse", git_ref])
+            .current_dir(path)
+            .output()
+            .context(format!("Get hash of {}", git_ref))?;
+        if !output.status.success() {
+            return Err(anyhow!("Did not get last hash successfully"));
+        }
+
+        String::from_utf8(output.stdout)
+            .map(|sha| sha.trim().to_string())
+            .map_err(From::from)
+    }
+
+    #[test]
+    fn initialize_repo() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+
+        let home = root.join("home");
+        let env = |name: &str| match name {
+            "HOME" => Some(home.clone().into()),
+            "GIT_CONFIG_NOSYSTEM" => Some("1".into()),
+            _ => None,
+        };
+        let init = |directory: &Path, options: &InitOptions| -> Result<String> {
+            let mut buff = Cursor::new(Vec::new());
+            _git_init(directory, options, env, &mut buff)?;
+            Ok(String::from_utf8(buff.into_inner())?)
+        };
+        let no_template = InitOptions {
+            template: Some(PathBuf::new()),
+            ..Default::default()
+        };
+        let files = |git_dir: &Path| -> Result<Vec<PathBuf>> {
+            let mut files = Vec::new();
+            let mut dirs = vec![git_dir.to_path_buf()];
+            while let Some(dir) = dirs.pop() {
+                for entry in fs::read_dir(dir)? {
+                    let path = entry?.path();
+                    if path.is_dir() {
+                        dirs.push(path.clone());
+                    }
+                    files.push(path.strip_prefix(git_dir)?.to_path_buf());
+                }
+            }
+            files.sort();
+            Ok(files)
+        };
+
+        init(root, &no_template)?;
+
+        let dot_git = root.join(".git");
+        assert!(dot_git.is_dir());
+        assert!(dot_git.join("objects").is_dir());
+        assert!(dot_git.join("refs").is_dir());
+        assert!(dot_git.join("HEAD").is_file());
+
+        // Same layout as git's, along with the files its default template provides
+        let theirs = root.join("theirs");
+        fs::create_dir(&theirs)?;
+        git(&["init", "-q", "--template=", "-b", "master"], &theirs)?;
+        fs::create_dir(theirs.join(".git/info"))?;
+        fs::write(theirs.join(".git/info/exclude"), "")?;
+        fs::write(theirs.join(".git/description"), "")?;
+        assert_eq!(files(&dot_git)?, files(&theirs.join(".git"))?);
+        assert_eq!(
+            fs::read_to_string(dot_git.join("HEAD"))?,
+            fs::read_to_string(theirs.join(".git/HEAD"))?
+        );
+        assert_eq!(
+            fs::read_to_string(dot_git.join("config"))?,
+            fs::read_to_string(theirs.join(".git/config"))?
+        );
+
+        // Reinitializing keeps everything, HEAD included
+        fs::write(dot_git.join("HEAD"), "ref: refs/heads/other\n")?;
+        fs::write(dot_git.join("description"), "Mine\n")?;
+        let options = InitOptions {
+            initial_branch: Some("main".to_string()),
+            ..no_template.clone()
+        };
+        let message = init(root, &options)?;
+        assert_eq!(
+            message,
+            format!(
+                "Reinitialized existing Git repository in {}/\n",
+                dot_git.canonicalize()?.display()
+            )
+        );
+        assert_eq!(
+            fs::read_to_string(dot_git.join("HEAD"))?,
+            "ref: refs/heads/other\n"
+        );
+        assert_eq!(fs::read_to_string(dot_git.join("description"))?, "Mine\n");
+
+        // Bare repositories, with a template and the initial branch from the global config
+        let template = root.join("template");
+        fs::create_dir_all(template.join("hooks"))?;
+        fs::write(template.join("hooks/pre-commit"), "#!/bin/sh\n")?;
+        fs::write(template.join("description"), "From template\n")?;
+        fs::create_dir(&home)?;
+        fs::write(home.join(".gitconfig"), "[init]\n\tdefaultBranch = trunk\n")?;
+        let bare = root.join("new/bare.git");
+        let options = InitOptions {
+            bare: true,
+            template: Some(template),
+            ..Default::default()
+        };
+        let message = init(&bare, &options)?;
+        assert_eq!(
+            message,
+            format!(
+                "Initialized empty Git repository in {}/\n",
+                bare.canonicalize()?.display()
+            )
+        );
+        assert_eq!(
+            fs::read_to_string(bare.join("HEAD"))?,
+            "ref: refs/heads/trunk\n"
+        );
+        assert_eq!(
+            fs::read_to_string(bare.join("description"))?,
+            "From template\n"
+        );
+        assert!(bare.join("hooks/pre-commit").is_file());
+        assert_eq!(git(&["config", "core.bare"], &bare)?, "true\n");
+        assert!(Repository::open(&bare)?.is_bare());
+
+        let quiet = InitOptions {
+            quiet: true,
+            ..no_template.clone()
+        };
+        assert_eq!(init(&root.join("quiet"), &quiet)?, "");
+        for invalid in [
+            InitOptions {
+                initial_branch: Some("bad..name".to_string()),
+                ..no_template.clone()
+            },
+            InitOptions {
+                object_format: Some("sha256".to_string()),
+                ..no_template
+            },
+        ] {
+            assert!(init(&root.join("invalid"), &invalid).is_err());
+        }
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn cat_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo(root)?;
+        let hash = get_sha("HEAD", root)?;
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_cat_file(
+            &hash,
+            &open_object_database(root)?,
+            &open_refs(root),
+            &mut buff,
+        )?;
+
+        buff.set_position(0);
+        let mut lines = buff.lines();
+        assert!(lines
+            .next()
+            .is_some_and(|line| line.is_ok_and(|line| line.starts_with("tree"))));
+        assert!(lines
+            .next()
+            .is_some_and(|line| line.is_ok_and(|line| line.starts_with("author"))));
+        assert!(lines
+            .next()
+            .is_some_and(|line| line.is_ok_and(|line| line.starts_with("committer"))));
+        assert!(lines
+            .next()
+            .is_some_and(|line| line.is_ok_and(|line| line.is_empty())));
+        assert!(lines
+            .next()
+            .is_some_and(|line| line.is_ok_and(|line| !line.is_empty())));
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_merge_commit() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo(root)?;
+        for args in [
+            &["checkout", "-q", "-b", "feature"][..],
+            &["commit", "-q", "--allow-empty", "-m", "Feature"],
+            &["checkout", "-q", "-"],
+            &["commit", "-q", "--allow-empty", "-m", "Main"],
+            &["merge", "-q", "--no-ff", "-m", "Merge feature", "feature"],
+        ] {
+            let output = Command::new("git").args(args).current_dir(root).output()?;
+            if !output.status.success() {
+                return Err(anyhow!("`git {}` was not successful", args.join(" ")));
+            }
+        }
+        let hash = get_sha("HEAD", root)?.parse()?;
+
+        let bytes = LooseObjectDatabase::new(root.join(".git/objects")).read_loose(&hash)?;
+        let Object::Commit(commit) = Object::from_bytes(&bytes)? else {
+            return Err(anyhow!("Expected a commit"));
+        };
+        assert_eq!(commit.tree, get_sha("HEAD^{tree}", root)?.parse()?);
+        assert_eq!(
+            commit.parents,
+            [
+                get_sha("HEAD^1", root)?.parse()?,
+                get_sha("HEAD^2", root)?.parse()?
+            ]
+        );
+        assert_eq!(commit.message, b"Merge feature\n");
+        assert_eq!(Object::Commit(commit).to_bytes(), bytes);
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn mktag() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo(root)?;
+        let output = Command::new("git")
+            .args(["tag", "-a", "v1.0", "-m", "Release 1.0"])
+            .current_dir(root)
+            .output()
+            .context("Create annotated tag")?;
+        if !output.status.success() {
+            return Err(anyhow!("Tagging was not successful"));
+        }
+        let hash = get_sha("v1.0", root)?;
+        let output = Command::new("git")
+            .args(["cat-file", "tag", &hash])
+            .current_dir(root)
+            .output()
+            .context("Read tag")?;
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_mktag(
+            &open_object_database(root)?,
+            &mut Cursor::new(&output.stdout),
+            &mut buff,
+        )?;
+        assert_eq!(String::from_utf8(buff.into_inner())?, hash);
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_cat_file(
+            &hash,
+            &open_object_database(root)?,
+            &open_refs(root),
+            &mut buff,
+        )?;
+        assert_eq!(buff.into_inner(), output.stdout);
+
+        // The tagged object must have the declared type
+        let tree = get_sha("HEAD^{tree}", root)?;
+        let bad_tag = String::from_utf8(output.stdout)?.replace(&get_sha("HEAD", root)?, &tree);
+        assert!(_git_mktag(
+            &open_object_database(root)?,
+            &mut Cursor::new(bad_tag),
+            &mut Vec::new()
+        )
+        .is_err());
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn tag() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo(root)?;
+        let head = get_sha("HEAD", root)?;
+        let odb = open_object_database(root)?;
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_tag(
+            "v1.0",
+            None,
+            Some("Release 1.0"),
+            false,
+            &odb,
+            &open_refs(root),
+            &mut buff,
+        )?;
+        _git_tag(
+            "light",
+            Some(&head),
+            None,
+            false,
+            &odb,
+            &open_refs(root),
+            &mut buff,
+        )?;
+        assert!(_git_tag(
+            "v1.0",
+            None,
+            Some("Again"),
+            false,
+            &odb,
+            &open_refs(root),
+            &mut buff
+        )
+        .is_err());
+        assert!(buff.into_inner().is_empty());
+
+        let tag = get_sha("v1.0", root)?;
+        assert_ne!(tag, head);
+        assert_eq!(get_sha("v1.0^{commit}", root)?, head);
+        assert_eq!(get_sha("light", root)?, head);
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_tag(
+            "light",
+            Some(&tag),
+            None,
+            true,
+            &odb,
+            &open_refs(root),
+            &mut buff,
+        )?;
+        assert_eq!(
+            String::from_utf8(buff.into_inner())?,
+            format!("Updated tag 'light' (was {})\n", &head[..7])
+        );
+
+        let output = Command::new("git")
+            .args(["cat-file", "-p", "v1.0"])
+            .current_dir(root)
+            .output()
+            .context("Read tag")?;
+        let contents = String::from_utf8(output.stdout)?;
+        assert!(contents.starts_with(&format!("object {}\ntype commit\ntag v1.0\ntagger ", head)));
+        assert!(contents.ends_with("\n\nRelease 1.0\n"));
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn refs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo_with_history(root)?;
+        git(&["branch", "feature/a", "HEAD~2"], root)?;
+        git(&["tag", "light", "HEAD~1"], root)?;
+        // Mix packed and loose refs, with a loose ref shadowing its packed version
+        git(&["pack-refs", "--all"], root)?;
+        git(&["update-ref", "refs/heads/feature/a", "HEAD~3"], root)?;
+        git(&["branch", "loose", "HEAD~4"], root)?;
+        let odb = open_object_database(root)?;
+
+        let show_ref = |patterns: &[&str], args: &[&str]| -> Result<()> {
+            let options = ShowRefOptions {
+                head: args.contains(&"--head"),
+                heads: args.contains(&"--heads"),
+                tags: args.contains(&"--tags"),
+                dereference: args.contains(&"-d"),
+                verify: args.contains(&"--verify"),
+            };
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            let mut buff = Cursor::new(Vec::new());
+            _git_show_ref(&patterns, &options, &odb, &open_refs(root), &mut buff)?;
+            let mut git_args = vec!["show-ref"];
+            git_args.extend(args);
+            git_args.extend(patterns.iter().map(String::as_str));
+            assert_eq!(String::from_utf8(buff.into_inner())?, git(&git_args, root)?);
+            Ok(())
+        };
+        show_ref(&[], &[])?;
+        show_ref(&[], &["--head", "-d"])?;
+        show_ref(&[], &["--heads"])?;
+        show_ref(&[], &["--tags", "-d"])?;
+        show_ref(&["a", "v1.0"], &[])?;
+        show_ref(&["refs/heads/loose", "HEAD"], &["--verify"])?;
+        assert!(show_ref(&["loose"], &["--verify"]).is_err());
+        assert!(show_ref(&["nothing"], &[]).is_err());
+
+        // Updates check the old value, deletions remove both loose and packed versions
+        let head = get_sha("HEAD", root)?;
+        let first = get_sha("HEAD~4", root)?;
+        _git_update_ref(
+            "refs/heads/new",
+            Some(&head),
+            Some(""),
+            None,
+            &odb,
+            &open_refs(root),
+        )?;
+        assert!(_git_update_ref(
+            "refs/heads/new",
+            Some(&first),
+            Some(""),
+            None,
+            &odb,
+            &open_refs(root)
+        )
+        .is_err());
+        assert!(_git_update_ref(
+            "refs/heads/new",
+            Some(&first),
+            Some(&first),
+            None,
+            &odb,
+            &open_refs(root)
+        )
+        .is_err());
+        _git_update_ref(
+            "refs/heads/new",
+            Some(&first),
+            Some(&head),
+            None,
+            &odb,
+            &open_refs(root),
+        )?;
+        assert_eq!(get_sha("new", root)?, first);
+        assert!(_git_update_ref(
+            "refs/heads/a..b",
+            Some(&head),
+            None,
+            None,
+            &odb,
+            &open_refs(root)
+        )
+        .is_err());
+        assert!(!root.join(".git/refs/heads/new.lock").exists());
+
+        let feature = get_sha("feature/a", root)?;
+        _git_update_ref(
+            "refs/heads/feature/a",
+            None,
+            Some(&feature),
+            None,
+            &odb,
+            &open_refs(root),
+        )?;
+        assert!(get_sha("feature/a", root).is_err());
+        assert!(!root.join(".git/refs/heads/feature").exists());
+        assert!(!fs::read_to_string(root.join(".git/packed-refs"))?.contains("feature/a"));
+        _git_update_ref("refs/tags/v1.0", None, None, None, &odb, &open_refs(root))?;
+        assert_eq!(git(&["tag"], root)?, "light\n");
+
+        // Updating HEAD updates the branch it points to
+        let branch = git(&["symbolic-ref", "HEAD"], root)?;
+        _git_update_ref("HEAD", Some(&first), None, None, &odb, &open_refs(root))?;
+        assert_eq!(get_sha(branch.trim(), root)?, first);
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_symbolic_ref("HEAD", None, false, &open_refs(root), &mut buff)?;
+        _git_symbolic_ref("HEAD", None, true, &open_refs(root), &mut buff)?;
+        assert_eq!(
+            String::from_utf8(buff.into_inner())?,
+            git(&["symbolic-ref", "HEAD"], root)?
+                + &git(&["symbolic-ref", "--short", "HEAD"], root)?
+        );
+        _git_symbolic_ref(
+            "HEAD",
+            Some("refs/heads/loose"),
+            false,
+            &open_refs(root),
+            &mut std::io::sink(),
+        )?;
+        assert_eq!(git(&["symbolic-ref", "HEAD"], root)?, "refs/heads/loose\n");
+        assert_eq!(get_sha("HEAD", root)?, first);
+        assert!(_git_symbolic_ref(
+            "HEAD",
+            Some("loose"),
+            false,
+            &open_refs(root),
+            &mut std::io::sink()
+        )
+        .is_err());
+        assert!(_git_symbolic_ref(
+            "refs/heads/loose",
+            None,
+            false,
+            &open_refs(root),
+            &mut std::io::sink()
+        )
+        .is_err());
+
+        // A leftover lock file means another process is updating the ref
+        fs::write(root.join(".git/refs/heads/loose.lock"), "")?;
+        assert!(_git_update_ref("HEAD", Some(&head), None, None, &odb, &open_refs(root)).is_err());
+        assert_eq!(get_sha("HEAD", root)?, first);
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn rev_parse() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo_with_history(root)?;
+        git(&["checkout", "-q", "-b", "side", "HEAD~2"], root)?;
+        git(&["commit", "-q", "--allow-empty", "-m", "Side: work"], root)?;
+        git(&["checkout", "-q", "-"], root)?;
+        git(
+            &["merge", "-q", "--no-ff", "-m", "Merge side", "side"],
+            root,
+        )?;
+        git(&["pack-refs", "--all"], root)?;
+        let odb = open_object_database(root)?;
+        let refs = open_refs(root);
+
+        let revisions = [
+            "HEAD",
+            "@",
+            "side",
+            "heads/side",
+            "refs/heads/side",
+            "v1.0",
+            "v1.0^{}",
+            "v1.0^{commit}",
+            "v1.0^{tree}",
+            "v1.0~2",
+            "HEAD^",
+            "HEAD^2",
+            "HEAD^2^",
+            "HEAD^0",
+            "HEAD~3^{tree}",
+            "HEAD^{/Commit 1}",
+            "HEAD:file.txt",
+            "HEAD~2:./file.txt",
+            "HEAD:",
+            "v1.0:file.txt",
+            ":/^Side",
+            ":/Commit [0-2]",
+            ":/!-Commit",
+            "HEAD@{0}",
+            "HEAD@{1}",
+            "side@{1}",
+            "@{2}",
+        ];
+        for revision in revisions {
+            let mut buff = Cursor::new(Vec::new());
+            _git_rev_parse(&[revision.to_string()], true, None, &odb, &refs, &mut buff)
+                .with_context(|| format!("rev-parse {}", revision))?;
+            let expected = git(&["rev-parse", "--verify", revision], root)?;
+            assert_eq!(
+                String::from_utf8(buff.into_inner())?,
+                expected,
+                "{}",
+                revision
+            );
+        }
+
+        let args = ["HEAD~2..side", "^v1.0", "side..", "HEAD"].map(String::from);
+        let mut buff = Cursor::new(Vec::new());
+        _git_rev_parse(&args, false, None, &odb, &refs, &mut buff)?;
+        let mut git_args = vec!["rev-parse"];
+        git_args.extend(args.iter().map(String::as_str));
+        assert_eq!(String::from_utf8(buff.into_inner())?, git(&git_args, root)?);
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_rev_parse(
+            &["side".to_string()],
+            false,
+            Some(4),
+            &odb,
+            &refs,
+            &mut buff,
+        )?;
+        assert_eq!(
+            String::from_utf8(buff.into_inner())?,
+            git(&["rev-parse", "--short=4", "side"], root)?
+        );
+
+        for invalid in [
+            "nothing",
+            "HEAD~10",
+            "HEAD^3",
+            "HEAD:missing",
+            ":/nothing matches",
+            "HEAD@{99}",
+        ] {
+            let result = _git_rev_parse(
+                &[invalid.to_string()],
+                true,
+                None,
+                &odb,
+                &refs,
+                &mut Vec::new(),
+            );
+            assert!(result.is_err(), "{}", invalid);
+        }
+
+        // Commands take revisions too
+        let mut buff = Cursor::new(Vec::new());
+        _git_cat_file("v1.0~1:file.txt", &odb, &refs, &mut buff)?;
+        assert_eq!(
+            String::from_utf8(buff.into_inner())?,
+            git(&["cat-file", "-p", "v1.0~1:file.txt"], root)?
+        );
+        let mut buff = Cursor::new(Vec::new());
+        _git_ls_tree("side", true, &odb, &refs, &mut buff)?;
+        assert_eq!(String::from_utf8(buff.into_inner())?, "file.txt\n");
+
+        // Searching a long message takes neither a deep stack nor exponential time
+        git(
+            &["commit", "-q", "--allow-empty", "-m", &"x".repeat(20_000)],
+            root,
+        )?;
+        for pattern in [":/x.*y", ":/(a|x)+z", ":/(x*)*y"] {
+            let result = _git_rev_parse(
+                &[pattern.to_string()],
+                true,
+                None,
+                &odb,
+                &refs,
+                &mut Vec::new(),
+            );
+            assert!(result.is_err(), "{}", pattern);
+        }
+        let mut buff = Cursor::new(Vec::new());
+        _git_rev_parse(&[":/^x+".to_string()], true, None, &odb, &refs, &mut buff)?;
+        assert_eq!(
+            String::from_utf8(buff.into_inner())?,
+            git(&["rev-parse", "HEAD"], root)?
+        );
+
+        // Dots in a path or a search are not a range
+        fs::write(root.join("a..b"), "dots\n")?;
+        git(&["add", "a..b"], root)?;
+        git(&["commit", "-q", "-m", "Add a..b"], root)?;
+        let args = [
+            "HEAD:a..b",
+            "HEAD~1..HEAD:a..b",
+            "HEAD^{/a..b}",
+            "HEAD~1..HEAD^{/a..b}",
+        ]
+        .map(String::from);
+        let mut buff = Cursor::new(Vec::new());
+        _git_rev_parse(&args, false, None, &odb, &refs, &mut buff)?;
+        let mut git_args = vec!["rev-parse"];
+        git_args.extend(args.iter().map(String::as_str));
+        assert_eq!(String::from_utf8(buff.into_inner())?, git(&git_args, root)?);
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn reflog() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo_with_history(root)?;
+        let odb = open_object_database(root)?;
+        let refs = open_refs(root);
+        let branch = git(&["symbolic-ref", "--short", "HEAD"], root)?;
+        let branch = branch.trim();
+
+        let check_reflog = |name: Option<&str>| -> Result<()> {
+            let mut buff = Cursor::new(Vec::new());
+            _git_reflog_show(name, &odb, &refs, &mut buff)?;
+            let expected = git(&["reflog", "show", name.unwrap_or("HEAD")], root)?;
+            assert_eq!(String::from_utf8(buff.into_inner())?, expected);
+            Ok(())
+        };
+        check_reflog(None)?;
+        check_reflog(Some(branch))?;
+
+        // Updating the branch through HEAD, or directly, is logged for both
+        let first = get_sha("HEAD~4", root)?;
+        let second = get_sha("HEAD~3", root)?;
+        _git_update_ref(
+            "HEAD",
+            Some(&first),
+            None,
+            Some("reset: moving to HEAD~4"),
+            &odb,
+            &open_refs(root),
+        )?;
+        let full_branch = format!("refs/heads/{}", branch);
+        _git_update_ref(
+            &full_branch,
+            Some(&second),
+            None,
+            None,
+            &odb,
+            &open_refs(root),
+        )?;
+        check_reflog(None)?;
+        check_reflog(Some(branch))?;
+        assert_eq!(get_sha("HEAD@{1}", root)?, first);
+        assert_eq!(
+            refs.read_reflog("HEAD")?
+                .last()
+                .map(|entry| (entry.old, entry.new)),
+            Some((first.parse()?, second.parse()?))
+        );
+
+        // Only branches get a reflog by default, and it is deleted with them
+        _git_update_ref(
+            "refs/heads/new",
+            Some(&first),
+            None,
+            Some("branch: Created"),
+            &odb,
+            &open_refs(root),
+        )?;
+        _git_update_ref(
+            "refs/tags/new",
+            Some(&first),
+            None,
+            None,
+            &odb,
+            &open_refs(root),
+        )?;
+        assert_eq!(
+            git(&["reflog", "show", "refs/heads/new"], root)?,
+            format!("{} refs/heads/new@{{0}}: branch: Created\n", &first[..7])
+        );
+        assert!(refs.read_reflog("refs/tags/new")?.is_empty());
+        _git_update_ref("refs/heads/new", None, None, None, &odb, &open_refs(root))?;
+        assert!(!root.join(".git/logs/refs/heads/new").exists());
+
+        // `core.logAllRefUpdates` decides which refs get a new reflog, and existing ones are kept
+        // up to date regardless
+        let update = |name: &str| _git_update_ref(name, Some(&first), None, None, &odb, &refs);
+        git(&["config", "core.logAllRefUpdates", "always"], root)?;
+        update("refs/tags/always")?;
+        assert!(root.join(".git/logs/refs/tags/always").exists());
+        git(&["config", "core.logAllRefUpdates", "false"], root)?;
+        update("refs/heads/unlogged")?;
+        assert!(!root.join(".git/logs/refs/heads/unlogged").exists());
+        let count = refs.read_reflog(&full_branch)?.len();
+        update(&full_branch)?;
+        assert_eq!(refs.read_reflog(&full_branch)?.len(), count + 1);
+        git(&["config", "--unset", "core.logAllRefUpdates"], root)?;
+        git(&["update-ref", "-d", "refs/heads/unlogged"], root)?;
+        git(&["update-ref", "-d", "refs/tags/always"], root)?;
+
+        // Bare repositories don't get new reflogs unless asked to
+        let bare = dir.path().join("bare.git");
+        git(&["clone", "-q", "--bare", ".", "bare.git"], root)?;
+        let bare_refs = Refs::new(&bare);
+        _git_update_ref(
+            "refs/heads/bare",
+            Some(&first),
+            None,
+            None,
+            &odb,
+            &bare_refs,
+        )?;
+        assert!(!bare.join("logs/refs/heads/bare").exists());
+        git(&["config", "core.logAllRefUpdates", "true"], &bare)?;
+        _git_update_ref(
+            "refs/heads/logged",
+            Some(&first),
+            None,
+            None,
+            &odb,
+            &bare_refs,
+        )?;
+        assert!(bare.join("logs/refs/heads/logged").exists());
+
+        // Old entries expire
+        let log_path = root.join(".git/logs").join(&full_branch);
+        let log = fs::read_to_string(&log_path)?;
+        let mut entry: ReflogEntry = log.lines().next().unwrap_or_default().parse()?;
+        entry.committer.time.seconds = 1_000_000_000;
+        fs::write(&log_path, format!("{}\n{}", entry, log))?;
+        check_reflog(Some(branch))?;
+        let count = refs.read_reflog(&full_branch)?.len();
+
+        _git_reflog_expire(Some("never"), true, &[], &refs)?;
+        assert_eq!(refs.read_reflog(&full_branch)?.len(), count);
+        _git_reflog_expire(None, false, &[branch.to_string()], &refs)?;
+        assert_eq!(refs.read_reflog(&full_branch)?.len(), count - 1);
+        check_reflog(Some(branch))?;
+        _git_reflog_expire(Some("1.hour.ago"), true, &[], &refs)?;
+        assert_eq!(refs.read_reflog(&full_branch)?.len(), count - 1);
+        _git_reflog_expire(Some("all"), true, &[], &refs)?;
+        assert!(git(&["reflog", "show", "HEAD"], root)?.is_empty());
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn repository_discovery() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().canonicalize()?;
+        let no_env = |_: &str| None;
+
+        let work = root.join("work");
+        fs::create_dir_all(work.join("src/nested"))?;
+        create_git_repo(&work)?;
+        let repo = Repository::discover_with_env(&work.join("src/nested"), None, no_env)?;
+        assert_eq!(repo.git_dir(), work.join(".git"));
+        assert_eq!(repo.work_tree(), Some(work.as_path()));
+        assert!(Repository::discover_with_env(&root, None, no_env).is_err());
+
+        // Bare repositories are found from within
+        let bare = root.join("bare.git");
+        fs::create_dir(&bare)?;
+        git(&["init", "--bare", "-q"], &bare)?;
+        let repo = Repository::discover_with_env(&bare.join("refs"), None, no_env)?;
+        assert_eq!(repo.git_dir(), bare);
+        assert!(repo.is_bare());
+        assert!(repo.require_work_tree().is_err());
+
+        // `.git` files point to the actual git directory
+        let separate = root.join("separate");
+        fs::create_dir(&separate)?;
+        git(
+            &["init", "-q", "--separate-git-dir", "../separate.git"],
+            &separate,
+        )?;
+        let repo = Repository::discover_with_env(&separate, None, no_env)?;
+        assert_eq!(repo.git_dir(), root.join("separate.git"));
+        assert_eq!(repo.work_tree(), Some(separate.as_path()));
+        assert!(repo.refs().resolve_name("HEAD")?.starts_with("refs/heads/"));
+
+        // The git directory and working tree can be given explicitly, with the current directory
+        // as the working tree unless the repository is bare or has `core.worktree`
+        let repo = Repository::discover_with_env(&root, Some(Path::new("bare.git")), no_env)?;
+        assert_eq!((repo.git_dir(), repo.work_tree()), (bare.as_path(), None));
+        let bare_env = |name: &str| (name == "GIT_DIR").then(|| "bare.git".into());
+        let repo = Repository::discover_with_env(&root, None, bare_env)?;
+        assert!(repo.require_work_tree().is_err());
+        let repo = Repository::discover_with_env(&root, Some(Path::new("work/.git")), no_env)?;
+        assert_eq!(repo.work_tree(), Some(root.as_path()));
+        git(&["config", "core.worktree", "../src"], &work)?;
+        let repo = Repository::discover_with_env(&root, Some(Path::new("work/.git")), no_env)?;
+        assert_eq!(repo.require_work_tree()?.canonicalize()?, work.join("src"));
+        git(&["config", "--unset", "core.worktree"], &work)?;
+        let env = |name: &str| match name {
+            "GIT_DIR" => Some("work/.git".into()),
+            "GIT_WORK_TREE" => Some("separate".into()),
+            _ => None,
+        };
+        let repo = Repository::discover_with_env(&root, None, env)?;
+        assert_eq!(repo.git_dir(), work.join(".git"));
+        assert_eq!(repo.work_tree(), Some(separate.as_path()));
+        assert!(Repository::discover_with_env(&root, Some(Path::new("separate")), env).is_err());
+
+        // Commands go through the repository
+        let mut buff = Cursor::new(Vec::new());
+        let repo = Repository::discover_with_env(&work.join("src"), None, no_env)?;
+        _git_rev_parse(
+            &["HEAD".to_string()],
+            false,
+            None,
+            &repo.objects()?,
+            &repo.refs(),
+            &mut buff,
+        )?;
+        assert_eq!(
+            String::from_utf8(buff.into_inner())?,
+            git(&["rev-parse", "HEAD"], &work)?
+        );
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_and_identity() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().canonicalize()?;
+        let home = root.join("home");
+        let work = root.join("work");
+        fs::create_dir_all(home.join(".config/git"))?;
+        fs::create_dir(&work)?;
+        create_empty_git_repo(&work)?;
+
+        fs::write(home.join(".config/git/config"), "[user]\n\tname = Xdg\n")?;
+        fs::write(
+            home.join(".gitconfig"),
+            "[user]\n\tname = Global\n\temail = global@example.com\n[include]\n\tpath = extra.inc\n",
+        )?;
+        fs::write(home.join("extra.inc"), "[core]\n\teditor = vim\n")?;
+        fs::write(
+            home.join("work.inc"),
+            "[user]\n\temail = work@example.com\n",
+        )?;
+        fs::write(work.join(".git/branch.inc"), "[user]\n\tname = Feature\n")?;
+        let mut config = fs::OpenOptions::new()
+            .append(true)
+            .open(work.join(".git/config"))?;
+        config.write_all(
+            concat!(
+                "[includeIf \"gitdir:work/\"]\n\tpath = ~/work.inc\n",
+                "[includeIf \"gitdir:/elsewhere/\"]\n\tpath = ~/extra.inc\n",
+                "[includeIf \"onbranch:feature/\"]\n\tpath = branch.inc\n",
+                "[committer]\n\temail = committer@example.com\n",
+            )
+            .as_bytes(),
+        )?;
+
+        let env = |name: &str| match name {
+            "HOME" => Some(home.clone().into()),
+            "GIT_CONFIG_NOSYSTEM" => Some("1".into()),
+            "GIT_AUTHOR_NAME" => Some("Env".into()),
+            _ => None,
+        };
+        let real_git = |args: &[&str]| -> Result<String> {
+            let output = Command::new("git")
+                .args(args)
+                .current_dir(&work)
+                .env_clear()
+                .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+                .env("HOME", &home)
+                .env("GIT_CONFIG_NOSYSTEM", "1")
+                .env("GIT_AUTHOR_NAME", "Env")
+                .output()?;
+            Ok(String::from_utf8(output.stdout)?.trim_end().to_string())
+        };
+        let ident = |ident: &str| {
+            real_git(&["var", ident])
+                .map(|ident| ident[..ident.find('>').unwrap_or_default() + 1].to_string())
+        };
+
+        for branch in ["main", "feature/config"] {
+            let target = format!("refs/heads/{}", branch);
+            git(&["symbolic-ref", "HEAD", &target], &work)?;
+            let config = Config::load(Some(&work.join(".git")), env)?;
+            for key in ["user.name", "user.email", "core.editor", "core.bare"] {
+                assert_eq!(
+                    config.get(key).unwrap_or_default(),
+                    real_git(&["config", "--get", key])?
+                );
+            }
+            assert_eq!(config.get_all("core.editor").len(), 1);
+
+            let author = ident::signature(&config, Role::Author, env)?;
+            let committer = ident::signature(&config, Role::Committer, env)?;
+            assert_eq!(
+                format!(
+                    "{} <{}>",
+                    String::from_utf8_lossy(&author.name),
+                    String::from_utf8_lossy(&author.email)
+                ),
+                ident("GIT_AUTHOR_IDENT")?
+            );
+            assert_eq!(
+                format!(
+                    "{} <{}>",
+                    String::from_utf8_lossy(&committer.name),
+                    String::from_utf8_lossy(&committer.email)
+                ),
+                ident("GIT_COMMITTER_IDENT")?
+            );
+        }
+        let config = Config::load(Some(&work.join(".git")), env)?;
+        assert_eq!(config.get("user.name"), Some("Feature"));
+        assert_eq!(config.get("user.email"), Some("work@example.com"));
+        assert_eq!(config.get("core.editor"), Some("vim"));
+
+        // Without any name nor email there is no identity
+        let config = Config::load(None, |_| None)?;
+        assert!(ident::signature(&config, Role::Committer, |_| None).is_err());
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn config() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        let home = root.join("home");
+        fs::create_dir(&home)?;
+        let env = |name: &str| match name {
+            "HOME" => Some(home.clone().into()),
+            "GIT_CONFIG_NOSYSTEM" => Some("1".into()),
+            _ => None,
+        };
+
+        // Edits keep the formatting like git's do
+        let original = "# top comment\n[core]\n\tbare = false ; keep\n[User]\n  Name = Old\n[alias] lg = log\n";
+        let (ours, theirs) = (root.join("ours"), root.join("theirs"));
+        fs::write(&ours, original)?;
+        fs::write(&theirs, original)?;
+        let file = ConfigLocation::File(ours.clone());
+        let set = |key: &str, value: &str| ConfigAction::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+            value_pattern: None,
+        };
+        let edits = [
+            (set("User.Email", "a b#c"), vec!["User.Email", "a b#c"]),
+            (set("core.editor", "vim"), vec!["core.editor", "vim"]),
+            (
+                set("Remote.Ori\"gin.URL", "x\\y\"z\tw"),
+                vec!["Remote.Ori\"gin.URL", "x\\y\"z\tw"],
+            ),
+            (
+                ConfigAction::Add {
+                    key: "alias.lg".to_string(),
+                    value: "other".to_string(),
+                },
+                vec!["--add", "alias.lg", "other"],
+            ),
+            (set("user.name", " New "), vec!["user.name", " New "]),
+            (
+                ConfigAction::Unset {
+                    key: "core.bare".to_string(),
+                    value_pattern: None,
+                },
+                vec!["--unset", "core.bare"],
+            ),
+            (
+                ConfigAction::Unset {
+                    key: "alias.lg".to_string(),
+                    value_pattern: Some("^l".to_string()),
+                },
+                vec!["--unset", "alias.lg", "^l"],
+            ),
+            (set("flag.x", ""), vec!["flag.x", ""]),
+            (
+                ConfigAction::Add {
+                    key: "flag.x".to_string(),
+                    value: "y".to_string(),
+                },
+                vec!["--add", "flag.x", "y"],
+            ),
+        ];
+        for (action, args) in edits {
+            _git_config(&action, Some(&file), None, env, &mut std::io::sink())?;
+            let mut git_args = vec!["config", "--file", "theirs"];
+            git_args.extend(args);
+            git(&git_args, root)?;
+            assert_eq!(fs::read_to_string(&ours)?, fs::read_to_string(&theirs)?);
+        }
+        // Values to replace or remove must be unambiguous, and failures exit with git's codes
+        for (invalid, code) in [
+            (set("flag.x", "z"), 5),
+            (set("nosection", "x"), 2),
+            (set("a.1x", "x"), 1),
+            (
+                ConfigAction::Unset {
+                    key: "flag.x".to_string(),
+                    value_pattern: None,
+                },
+                5,
+            ),
+            (
+                ConfigAction::Unset {
+                    key: "core.missing".to_string(),
+                    value_pattern: None,
+                },
+                5,
+            ),
+        ] {
+            let error = _git_config(&invalid, Some(&file), None, env, &mut std::io::sink())
+                .expect_err("config change should fail");
+            let error = error
+                .downcast_ref::<ConfigError>()
+                .expect("config error with an exit code");
+            assert_eq!(error.exit_code(), code);
+        }
+        // Reading a malformed key fails before the lookup
+        let get_bad = ConfigAction::Get {
+            key: "bad".to_string(),
+            value_pattern: None,
+        };
+        let error = _git_config(&get_bad, Some(&file), None, env, &mut std::io::sink())
+            .expect_err("malformed key should fail");
+        assert_eq!(error.to_string(), "key does not contain a section: bad");
+
+        let read = |action: &ConfigAction, location: Option<&ConfigLocation>| -> Result<String> {
+            let mut buff = Cursor::new(Vec::new());
+            _git_config(
+                action,
+                location,
+                Some(&root.join("repo/.git")),
+                env,
+                &mut buff,
+            )?;
+            Ok(String::from_utf8(buff.into_inner())?)
+        };
+        let get_regexp = ConfigAction::GetRegexp {
+            name_pattern: "user|remote".to_string(),
+            value_pattern: Some("!Old".to_string()),
+        };
+        assert_eq!(
+            read(&get_regexp, Some(&file))?,
+            git(
+                &[
+                    "config",
+                    "-f",
+                    "theirs",
+                    "--get-regexp",
+                    "user|remote",
+                    "!Old"
+                ],
+                root
+            )?
+        );
+        assert_eq!(
+            read(&ConfigAction::List, Some(&file))?,
+            git(&["config", "-f", "theirs", "--list"], root)?
+        );
+
+        // Global settings go to the home directory, and local ones to the repository
+        fs::create_dir(root.join("repo"))?;
+        _git_init(
+            &root.join("repo"),
+            &InitOptions::default(),
+            env,
+            &mut std::io::sink(),
+        )?;
+        assert_eq!(
+            read(&ConfigAction::List, Some(&ConfigLocation::Local))?,
+            "core.repositoryformatversion=0\ncore.filemode=true\ncore.bare=false\ncore.logallrefupdates=true\n"
+        );
+        _git_config(
+            &set("user.name", "Global"),
+            Some(&ConfigLocation::Global),
+            None,
+            env,
+            &mut std::io::sink(),
+        )?;
+        _git_config(
+            &set("user.name", "Local"),
+            None,
+            Some(&root.join("repo/.git")),
+            env,
+            &mut std::io::sink(),
+        )?;
+        assert_eq!(
+            fs::read_to_string(home.join(".gitconfig"))?,
+            "[user]\n\tname = Global\n"
+        );
+        let get_all = ConfigAction::GetAll {
+            key: "user.name".to_string(),
+            value_pattern: None,
+        };
+        assert_eq!(read(&get_all, None)?, "Global\nLocal\n");
+        let get = ConfigAction::Get {
+            key: "USER.NAME".to_string(),
+            value_pattern: None,
+        };
+        assert_eq!(read(&get, None)?, "Local\n");
+        assert_eq!(read(&get, Some(&ConfigLocation::Global))?, "Global\n");
+        let missing = ConfigAction::Get {
+            key: "user.email".to_string(),
+            value_pattern: None,
+        };
+        assert!(read(&missing, None).is_err());
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn index() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_empty_git_repo(root)?;
+        let index_path = root.join(".git/index");
+
+        // Indexes written by git are written back as is
+        let round_trip = || -> Result<Index> {
+            let bytes = fs::read(&index_path)?;
+            let index = Index::from_bytes(&bytes)?;
+            assert_eq!(index.to_bytes(), bytes);
+            Ok(index)
+        };
+        let ls_files = |index: &Index| -> String {
+            index
+                .entries()
+                .iter()
+                .map(|entry| {
+                    format!(
+                        "{} {} {}\t{}\n",
+                        entry.mode, entry.id, entry.stage, entry.path
+                    )
+                })
+                .collect()
+        };
+
+        fs::create_dir_all(root.join("src/bin"))?;
+        fs::write(root.join("README"), "readme\n")?;
+        fs::write(root.join("src/lib.rs"), "lib\n")?;
+        fs::write(root.join("src/bin/main.rs"), "main\n")?;
+        fs::write(root.join("run.sh"), "#!/bin/sh\n")?;
+        fs::set_permissions(root.join("run.sh"), fs::Permissions::from_mode(0o755))?;
+        std::os::unix::fs::symlink("README", root.join("link"))?;
+        git(&["add", "."], root)?;
+        let tree = git(&["write-tree"], root)?;
+
+        let index = round_trip()?;
+        assert_eq!(index.version, 2);
+        assert_eq!(ls_files(&index), git(&["ls-files", "--stage"], root)?);
+        let cache_tree = index.cache_tree.as_ref().context("missing cache tree")?;
+        assert_eq!(cache_tree.id, Some(tree.trim().parse()?));
+        assert_eq!(cache_tree.entry_count, 5);
+        assert_eq!(cache_tree.subtrees[0].name, "src");
+        let entry = index.entry("README", 0).context("missing README")?;
+        let metadata = fs::metadata(root.join("README"))?;
+        assert_eq!(entry.size, 7);
+        assert_eq!(entry.mtime.seconds as i64, metadata.mtime());
+        assert_eq!(entry.ino as u64, metadata.ino());
+
+        // Extended flags need version 3, and version 4 compresses paths
+        git(&["update-index", "--skip-worktree", "src/lib.rs"], root)?;
+        assert_eq!(round_trip()?.version, 3);
+        git(&["update-index", "--index-version", "4"], root)?;
+        let index = round_trip()?;
+        assert_eq!(index.version, 4);
+        assert!(
+            index
+                .entry("src/lib.rs", 0)
+                .context("missing src/lib.rs")?
+                .skip_worktree
+        );
+        assert_eq!(ls_files(&index), git(&["ls-files", "--stage"], root)?);
+        git(&["update-index", "--no-skip-worktree", "src/lib.rs"], root)?;
+        git(&["update-index", "--index-version", "2"], root)?;
+        git(&["commit", "-q", "-m", "Files"], root)?;
+
+        // Conflicts, one of which is resolved
+        git(&["checkout", "-q", "-b", "other"], root)?;
+        fs::write(root.join("README"), "theirs\n")?;
+        fs::write(root.join("src/lib.rs"), "their lib\n")?;
+        git(&["commit", "-q", "-am", "Theirs"], root)?;
+        git(&["checkout", "-q", "-"], root)?;
+        fs::write(root.join("README"), "ours\n")?;
+        fs::write(root.join("src/lib.rs"), "our lib\n")?;
+        git(&["commit", "-q", "-am", "Ours"], root)?;
+        assert!(git(&["merge", "other"], root).is_err());
+        fs::write(root.join("README"), "resolved\n")?;
+        git(&["add", "README"], root)?;
+
+        let mut index = round_trip()?;
+        assert_eq!(ls_files(&index), git(&["ls-files", "--stage"], root)?);
+        assert!((1..=3).all(|stage| index.entry("src/lib.rs", stage).is_some()));
+        assert_eq!(index.resolve_undo.len(), 1);
+        assert_eq!(index.resolve_undo[0].path, "README");
+        assert_eq!(
+            index.resolve_undo[0].stages[1],
+            Some((FileMode::Regular, get_sha("HEAD:README", root)?.parse()?))
+        );
+
+        // Git reads what we write
+        let mut resolved = index
+            .entry("src/lib.rs", 2)
+            .context("missing ours")?
+            .clone();
+        resolved.stage = 0;
+        resolved.intent_to_add = true;
+        assert!(index.remove("src/lib.rs"));
+        index.add(resolved);
+        index.version = 4;
+        index.write(&index_path)?;
+        assert_eq!(Index::open(&index_path)?, index);
+        assert_eq!(ls_files(&index), git(&["ls-files", "--stage"], root)?);
+        // Without intent-to-add, `src/lib.rs` would be the same as in HEAD
+        assert_eq!(
+            git(&["diff", "--cached", "--name-only", "HEAD"], root)?,
+            "README\nsrc/lib.rs\n"
+        );
+
+        // A file replaces the directory where it goes, and a directory the file
+        let mut file = index.entry("README", 0).context("missing README")?.clone();
+        let mut expected: Vec<String> = index
+            .entries()
+            .iter()
+            .map(|entry| entry.path.clone())
+            .filter(|path| path != "README" && !path.starts_with("src/"))
+            .collect();
+        file.path = "src".to_string();
+        index.add(file.clone());
+        file.path = "README/nested".to_string();
+        index.add(file);
+        expected.extend(["README/nested".to_string(), "src".to_string()]);
+        expected.sort();
+        let paths: Vec<String> = index
+            .entries()
+            .iter()
+            .map(|entry| entry.path.clone())
+            .collect();
+        assert_eq!(paths, expected);
+
+        // A corrupt entry count fails on the missing entries instead of reserving room for them
+        let mut bytes = [&b"DIRC"[..], &2u32.to_be_bytes(), &u32::MAX.to_be_bytes()].concat();
+        bytes.extend(ObjectId::hash(&bytes).as_bytes());
+        assert!(Index::from_bytes(&bytes).is_err());
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_packed_objects() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo_with_history(root)?;
+
+        let check_all_objects = || -> Result<()> {
+            let odb = open_object_database(root)?;
+            let objects = git(&["cat-file", "--batch-all-objects", "--batch-check"], root)?;
+            for line in objects.lines() {
+                let mut fields = line.split(' ');
+                let (Some(hash), Some(kind)) = (fields.next(), fields.next()) else {
+                    return Err(anyhow!("Unexpected batch-check output: {}", line));
+                };
+                let mut buff = Cursor::new(Vec::new());
+                _git_cat_file(hash, &odb, &open_refs(root), &mut buff)?;
+                let expected = Command::new("git")
+                    .args(["cat-file", kind, hash])
+                    .current_dir(root)
+                    .output()?
+                    .stdout;
+                assert_eq!(buff.into_inner(), expected, "{} {}", kind, hash);
+            }
+            Ok(())
+        };
+
+        git(&["gc", "--quiet"], root)?;
+        assert_eq!(
+            LooseObjectDatabase::new(root.join(".git/objects"))
+                .iter()?
+                .count(),
+            0
+        );
+        let verify = |root: &Path| -> Result<String> {
+            let packs = PackedObjectDatabase::open(root.join(".git/objects/pack"))?;
+            let pack = packs.packs().first().ok_or(anyhow!("No pack"))?;
+            git(&["verify-pack", "-v", &pack.path().to_string_lossy()], root)
+        };
+        assert!(verify(root)?.contains("chain length = "));
+        check_all_objects()?;
+
+        // Same thing with REF_DELTA entries instead of OFS_DELTA ones
+        git(
+            &[
+                "-c",
+                "repack.useDeltaBaseOffset=false",
+                "repack",
+                "-a",
+                "-d",
+                "-f",
+                "-q",
+            ],
+            root,
+        )?;
+        assert!(verify(root)?.contains("chain length = "));
+        check_all_objects()?;
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn repack() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo_with_history(root)?;
+        let loose = LooseObjectDatabase::new(root.join(".git/objects"));
+        let objects = git(&["cat-file", "--batch-all-objects", "--batch-check"], root)?;
+        let count = loose.iter()?.count();
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_repack(false, true, &Repository::open(root)?, &mut buff)?;
+        assert_eq!(loose.iter()?.count(), 0);
+        let packs = PackedObjectDatabase::open(root.join(".git/objects/pack"))?;
+        assert_eq!(packs.packs().len(), 1);
+        assert_eq!(packs.iter()?.count(), count);
+
+        // Real git can read the pack, which contains deltas
+        let pack = packs.packs()[0].path().to_string_lossy().to_string();
+        assert!(git(&["verify-pack", "-v", &pack], root)?.contains("chain length = 1:"));
+        assert_eq!(
+            git(&["cat-file", "--batch-all-objects", "--batch-check"], root)?,
+            objects
+        );
+        git(&["fsck", "--strict"], root)?;
+
+        // Repacking everything merges the new loose objects and the existing pack
+        fs::write(root.join("new.txt"), "new file")?;
+        git(&["add", "new.txt"], root)?;
+        git(&["commit", "-m", "New file"], root)?;
+        let mut buff = Cursor::new(Vec::new());
+        _git_repack(true, true, &Repository::open(root)?, &mut buff)?;
+        assert_eq!(loose.iter()?.count(), 0);
+        let packs = PackedObjectDatabase::open(root.join(".git/objects/pack"))?;
+        assert_eq!(packs.packs().len(), 1);
+        assert_eq!(packs.iter()?.count(), count + 3);
+        git(&["fsck", "--strict"], root)?;
+
+        // Objects already in a pack aren't written again as loose objects
+        let mut buff = Cursor::new(Vec::new());
+        _git_hash_object(
+            Path::new("new.txt"),
+            root,
+            &open_object_database(root)?,
+            &mut buff,
+        )?;
+        assert_eq!(
+            String::from_utf8(buff.into_inner())?,
+            get_sha("HEAD:new.txt", root)?
+        );
+        assert_eq!(loose.iter()?.count(), 0);
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_repack(false, true, &Repository::open(root)?, &mut buff)?;
+        assert_eq!(buff.into_inner(), b"Nothing new to pack.\n");
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn abbreviated_object_ids() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo_with_files(root).context("create git repo with files")?;
+        let head = get_sha("HEAD", root)?;
+        let tree = get_sha("HEAD^{tree}", root)?;
+
+        for _ in 0..2 {
+            let odb = open_object_database(root)?;
+            let mut buff = Cursor::new(Vec::new());
+            _git_cat_file(&head[..7], &odb, &open_refs(root), &mut buff)?;
+            assert!(buff.into_inner().starts_with(b"tree "));
+            let mut buff = Cursor::new(Vec::new());
+            _git_ls_tree(
+                &tree[..5].to_uppercase(),
+                true,
+                &odb,
+                &open_refs(root),
+                &mut buff,
+            )?;
+            assert_eq!(buff.into_inner(), b"Cargo.toml\nsrc\n");
+
+            assert!(_git_cat_file(&head[..3], &odb, &open_refs(root), &mut Vec::new()).is_err());
+            assert!(_git_cat_file("not-hex", &odb, &open_refs(root), &mut Vec::new()).is_err());
+
+            // Same thing with packed objects
+            git(&["gc", "--quiet"], root)?;
         }
 
-        String::from_utf8(output.stdout)
-            .map(|sha| sha.trim().to_string())
-            .map_err(From::from)
+        // Find two blobs whose ids share the same first 4 characters
+        let odb = MemoryObjectDatabase::new();
+        let mut seen = std::collections::HashMap::new();
+        let (first, second) = (0..)
+            .find_map(|i| {
+                let id = odb.write(&Object::Blob(i.to_string().into_bytes())).ok()?;
+                seen.insert(id.abbrev(4), id).map(|other| (other, id))
+            })
+            .ok_or(anyhow!("No ambiguous prefix"))?;
+        let error = resolve_object_id(&odb, &first.abbrev(4))
+            .expect_err("prefix should be ambiguous")
+            .to_string();
+        assert!(error.starts_with(&format!("short object ID {} is ambiguous", first.abbrev(4))));
+        assert!(error.contains(&format!("hint:   {} blob", first.abbrev(7))));
+        assert!(error.contains(&format!("hint:   {} blob", second.abbrev(7))));
+
+        dir.close()?;
+
+        Ok(())
     }
 
     #[test]
-    fn initialize_repo() -> Result<()> {
+    fn latin1_commit() -> Result<()> {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
         let dir = tempfile::tempdir()?;
         let root = dir.path();
+        create_git_repo_with_files(root)?;
+        // Git writes the identity and message as given, in the encoding of the commit
+        let status = Command::new("git")
+            .args(["-c", "i18n.commitEncoding=ISO-8859-1", "commit", "-q"])
+            .args(["--allow-empty", "-m"])
+            .arg(OsStr::from_bytes(b"Caf\xe9"))
+            .env("GIT_AUTHOR_NAME", OsStr::from_bytes(b"Ren\xe9"))
+            .current_dir(root)
+            .status()?;
+        assert!(status.success());
+        let odb = open_object_database(root)?;
+        let refs = open_refs(root);
 
-        _git_init(root)?;
-
-        let dot_git = root.join(".git");
-        assert!(dot_git.is_dir());
-        assert!(dot_git.join("objects").is_dir());
-        assert!(dot_git.join("refs").is_dir());
-        assert!(dot_git.join("HEAD").is_file());
+        let Object::Commit(commit) = odb.read(&get_sha("HEAD", root)?.parse()?)? else {
+            return Err(anyhow!("Expected a commit"));
+        };
+        assert_eq!(commit.author.name, b"Ren\xe9");
+        let mut buff = Cursor::new(Vec::new());
+        _git_cat_file("HEAD", &odb, &refs, &mut buff)?;
+        let expected = Command::new("git")
+            .args(["cat-file", "-p", "HEAD"])
+            .current_dir(root)
+            .output()?
+            .stdout;
+        assert_eq!(buff.into_inner(), expected);
+        assert_eq!(
+            resolve_revision(&odb, &refs, "HEAD^{tree}")?.to_string(),
+            get_sha("HEAD^{tree}", root)?
+        );
+        let mut buff = Cursor::new(Vec::new());
+        _git_fsck(false, false, &Repository::open(root)?, &mut buff)?;
+        assert!(buff.into_inner().is_empty());
 
         dir.close()?;
 
@@ -276,32 +3390,134 @@ mod tests {
     }
 
     #[test]
-    fn cat_file() -> Result<()> {
+    fn fsck() -> Result<()> {
         let dir = tempfile::tempdir()?;
         let root = dir.path();
-        create_git_repo(root)?;
-        let hash = get_sha("HEAD", root)?;
+        create_git_repo_with_history(root)?;
+        let loose = LooseObjectDatabase::new(root.join(".git/objects"));
 
         let mut buff = Cursor::new(Vec::new());
-        _git_cat_file(&hash, root, &mut buff)?;
+        _git_fsck(false, false, &Repository::open(root)?, &mut buff)?;
+        assert!(buff.into_inner().is_empty());
 
-        buff.set_position(0);
-        let mut lines = buff.lines();
-        assert!(lines
-            .next()
-            .is_some_and(|line| line.is_ok_and(|line| line.starts_with("tree"))));
-        assert!(lines
-            .next()
-            .is_some_and(|line| line.is_ok_and(|line| line.starts_with("author"))));
-        assert!(lines
-            .next()
-            .is_some_and(|line| line.is_ok_and(|line| line.starts_with("committer"))));
-        assert!(lines
-            .next()
-            .is_some_and(|line| line.is_ok_and(|line| line.is_empty())));
-        assert!(lines
-            .next()
-            .is_some_and(|line| line.is_ok_and(|line| !line.is_empty())));
+        // Objects that nothing points to are dangling
+        fs::write(root.join("dangling.txt"), "dangling")?;
+        let blob = git(&["hash-object", "-w", "dangling.txt"], root)?;
+        let mut buff = Cursor::new(Vec::new());
+        _git_fsck(false, false, &Repository::open(root)?, &mut buff)?;
+        assert_eq!(
+            String::from_utf8(buff.into_inner())?,
+            format!("dangling blob {}", blob)
+        );
+
+        // Trees must be sorted the way git sorts them
+        let unsorted = Object::Tree(vec![
+            TreeEntry {
+                mode: FileMode::Regular,
+                name: b"b".to_vec(),
+                id: blob.trim().parse()?,
+                raw_mode: None,
+            },
+            TreeEntry {
+                mode: FileMode::Regular,
+                name: b"a".to_vec(),
+                id: blob.trim().parse()?,
+                raw_mode: None,
+            },
+        ]);
+        let unsorted = loose.write(&unsorted)?;
+        let mut buff = Cursor::new(Vec::new());
+        assert!(_git_fsck(false, false, &Repository::open(root)?, &mut buff).is_err());
+        let output = String::from_utf8(buff.into_inner())?;
+        assert!(output.contains(&format!("error in tree {}: not properly sorted", unsorted)));
+        fs::remove_file(loose.object_path(&unsorted))?;
+
+        // The contents of an object must match its id and its declared size
+        let file = get_sha("HEAD:file.txt", root)?.parse()?;
+        let object_path = loose.object_path(&file);
+        let original = fs::read(&object_path)?;
+        fs::set_permissions(&object_path, fs::Permissions::from_mode(0o644))?;
+        let mut e = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        e.write_all(b"blob 5\0other")?;
+        fs::write(&object_path, e.finish()?)?;
+        let mut buff = Cursor::new(Vec::new());
+        assert!(_git_fsck(false, false, &Repository::open(root)?, &mut buff).is_err());
+        let output = String::from_utf8(buff.into_inner())?;
+        assert!(output.contains(&format!("loose object {} is corrupt: hash mismatch", file)));
+
+        let mut e = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        e.write_all(b"blob 10\0other")?;
+        fs::write(&object_path, e.finish()?)?;
+        let mut buff = Cursor::new(Vec::new());
+        assert!(_git_fsck(false, false, &Repository::open(root)?, &mut buff).is_err());
+        let output = String::from_utf8(buff.into_inner())?;
+        assert!(output.contains("Object size mismatch"));
+
+        // Missing objects are reported with their type
+        fs::remove_file(&object_path)?;
+        let mut buff = Cursor::new(Vec::new());
+        assert!(_git_fsck(false, false, &Repository::open(root)?, &mut buff).is_err());
+        let output = String::from_utf8(buff.into_inner())?;
+        assert!(output.contains(&format!("missing blob {}", file)));
+
+        // Packed objects are checked too
+        fs::write(&object_path, original)?;
+        git(&["gc", "--quiet"], root)?;
+        let mut buff = Cursor::new(Vec::new());
+        _git_fsck(false, false, &Repository::open(root)?, &mut buff)?;
+        git(&["reset", "--quiet", "--hard", "HEAD~1"], root)?;
+        git(&["tag", "--delete", "v1.0"], root)?;
+        git(&["reflog", "expire", "--expire=now", "--all"], root)?;
+        let mut buff = Cursor::new(Vec::new());
+        _git_fsck(true, false, &Repository::open(root)?, &mut buff)?;
+        let output = String::from_utf8(buff.into_inner())?;
+        assert!(output.contains("unreachable commit "));
+        assert!(output.contains("unreachable tag "));
+
+        // Names don't have to be valid UTF-8, and odd modes are read as is but reported like git
+        fs::write(root.join("empty"), "")?;
+        let blob: ObjectId = git(&["hash-object", "-w", "empty"], root)?.trim().parse()?;
+        let empty: ObjectId = git(&["hash-object", "-t", "tree", "-w", "empty"], root)?
+            .trim()
+            .parse()?;
+        let mut odd = Vec::new();
+        for (mode, name, id) in [
+            (&b"100644"[..], &b"caf\xe9"[..], blob),
+            (b"100664", b"legacy", blob),
+            (b"040000", b"sub", empty),
+        ] {
+            odd.extend([mode, b" ", name, b"\0", id.as_bytes()].concat());
+        }
+        fs::write(root.join("odd"), &odd)?;
+        let tree = git(
+            &["hash-object", "-t", "tree", "--literally", "-w", "odd"],
+            root,
+        )?;
+        let commit = git(&["commit-tree", tree.trim(), "-m", "Odd"], root)?;
+        git(&["update-ref", "refs/heads/odd", commit.trim()], root)?;
+        let repo = Repository::open(root)?;
+        assert_eq!(
+            repo.objects()?.read(&tree.trim().parse()?)?.content_bytes(),
+            odd
+        );
+        let zero_padded = format!(
+            "in tree {}: zeroPaddedFilemode: contains zero-padded file modes",
+            tree.trim()
+        );
+        let mut buff = Cursor::new(Vec::new());
+        _git_fsck(false, false, &repo, &mut buff)?;
+        let output = String::from_utf8(buff.into_inner())?;
+        assert!(output.contains(&format!("warning {}", zero_padded)));
+        assert!(!output.contains("error") && !output.contains("missing"));
+        assert!(!output.contains("badFilemode"));
+        let mut buff = Cursor::new(Vec::new());
+        assert!(_git_fsck(false, true, &repo, &mut buff).is_err());
+        let output = String::from_utf8(buff.into_inner())?;
+        assert!(output.contains(&format!("error {}", zero_padded)));
+        assert!(output.contains(&format!(
+            "warning in tree {}: badFilemode: contains bad file modes",
+            tree.trim()
+        )));
 
         dir.close()?;
 
@@ -324,7 +3540,12 @@ mod tests {
         }
 
         let mut buff = Cursor::new(Vec::new());
-        _git_hash_object(&PathBuf::from("main.rs"), root, &mut buff)?;
+        _git_hash_object(
+            &PathBuf::from("main.rs"),
+            root,
+            &open_object_database(root)?,
+            &mut buff,
+        )?;
 
         buff.set_position(0);
         let mut lines = buff.lines();
@@ -344,6 +3565,101 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn atomic_idempotent_writes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_empty_git_repo(root)?;
+        fs::write(root.join("main.rs"), "fn main() {}\n")?;
+
+        // Writing an object that already exists (and is read-only) is a no-op
+        let odb = open_object_database(root)?;
+        for _ in 0..2 {
+            _git_hash_object(&PathBuf::from("main.rs"), root, &odb, &mut Vec::new())?;
+        }
+        let loose = LooseObjectDatabase::new(root.join(".git/objects"));
+        let id = loose.iter()?.next().ok_or(anyhow!("No object written"))?;
+        let mode = fs::metadata(loose.object_path(&id))?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o444);
+
+        let blobs: Vec<_> = (0..50)
+            .map(|i| Object::Blob(format!("blob {}", i).into_bytes()))
+            .collect();
+        std::thread::scope(|scope| {
+            let writers: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| -> Result<()> {
+                        let loose = LooseObjectDatabase::new(root.join(".git/objects"));
+                        for blob in &blobs {
+                            loose.write(blob)?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            writers.into_iter().try_for_each(|writer| {
+                writer
+                    .join()
+                    .map_err(|_| anyhow!("Writer thread panicked"))?
+            })
+        })?;
+
+        assert_eq!(loose.iter()?.count(), 51);
+        for blob in &blobs {
+            assert_eq!(&loose.read(&blob.hash())?, blob);
+        }
+        for dir in fs::read_dir(root.join(".git/objects"))? {
+            for file in fs::read_dir(dir?.path())? {
+                let name = file?.file_name();
+                assert!(!name.to_string_lossy().starts_with("tmp_obj_"));
+            }
+        }
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn hash_object_binary_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_empty_git_repo(root)?;
+
+        // Not valid UTF-8, with null bytes and a trailing CR to make sure nothing gets mangled
+        let contents: Vec<u8> = (0..=255u8).chain([0xff, 0xfe, 0x00, b'\r']).collect();
+        fs::write(root.join("image.bin"), &contents)?;
+
+        let output = Command::new("git")
+            .args(["hash-object", "image.bin"])
+            .current_dir(root)
+            .output()
+            .context("Hash binary file with git")?;
+        let expected = String::from_utf8(output.stdout)?.trim().to_string();
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_hash_object(
+            &PathBuf::from("image.bin"),
+            root,
+            &open_object_database(root)?,
+            &mut buff,
+        )?;
+        assert_eq!(String::from_utf8(buff.into_inner())?, expected);
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_cat_file(
+            &expected,
+            &open_object_database(root)?,
+            &open_refs(root),
+            &mut buff,
+        )?;
+        assert_eq!(buff.into_inner(), contents);
+
+        dir.close()?;
+
+        Ok(())
+    }
+
     #[test]
     fn ls_tree() -> Result<()> {
         let dir = tempfile::tempdir()?;
@@ -353,7 +3669,14 @@ mod tests {
         let hash = get_sha("HEAD:./", root)?;
 
         let mut buff = Cursor::new(Vec::new());
-        _git_ls_tree(&hash, root, &mut buff).context("call ls-tree command with hash of root")?;
+        _git_ls_tree(
+            &hash,
+            true,
+            &open_object_database(root)?,
+            &open_refs(root),
+            &mut buff,
+        )
+        .context("call ls-tree command with hash of root")?;
 
         buff.set_position(0);
         let mut lines = buff.lines();
@@ -370,15 +3693,128 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn ls_tree_with_modes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo_with_files(root).context("create git repo with files")?;
+        fs::write(root.join("run.sh"), "#!/bin/sh\n")?;
+        fs::set_permissions(root.join("run.sh"), fs::Permissions::from_mode(0o755))?;
+        std::os::unix::fs::symlink("src/main.rs", root.join("link"))?;
+        git(&["add", "."], root)?;
+        git(&["commit", "-m", "More files"], root)?;
+        let hash = get_sha("HEAD^{tree}", root)?;
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_ls_tree(
+            &hash,
+            false,
+            &open_object_database(root)?,
+            &open_refs(root),
+            &mut buff,
+        )?;
+
+        assert_eq!(
+            String::from_utf8(buff.into_inner())?,
+            git(&["ls-tree", &hash], root)?
+        );
+
+        // Old versions of git wrote other modes, which read as the closest canonical one
+        let blob: ObjectId = get_sha("HEAD:run.sh", root)?.parse()?;
+        let mut legacy = Vec::new();
+        for (mode, name) in [("100664", "a"), ("100775", "b"), ("040000", "c")] {
+            legacy.extend(format!("{} {}\0", mode, name).as_bytes());
+            legacy.extend(blob.as_bytes());
+        }
+        fs::write(root.join("legacy"), legacy)?;
+        let legacy = git(
+            &["hash-object", "-t", "tree", "--literally", "-w", "legacy"],
+            root,
+        )?;
+        let mut buff = Cursor::new(Vec::new());
+        _git_ls_tree(
+            legacy.trim(),
+            false,
+            &open_object_database(root)?,
+            &open_refs(root),
+            &mut buff,
+        )?;
+        assert_eq!(
+            String::from_utf8(buff.into_inner())?,
+            git(&["ls-tree", legacy.trim()], root)?
+        );
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_tree_round_trip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo_with_files(root).context("create git repo with files")?;
+        let hash = get_sha("HEAD^{tree}", root)?.parse()?;
+
+        let bytes = LooseObjectDatabase::new(root.join(".git/objects")).read_loose(&hash)?;
+        let tree = Object::from_bytes(&bytes)?;
+        let Object::Tree(entries) = &tree else {
+            return Err(anyhow!("Expected a tree"));
+        };
+        assert_eq!(entries[1].id, get_sha("HEAD:src", root)?.parse()?);
+        assert_eq!(tree.to_bytes(), bytes);
+        assert_eq!(tree.hash(), hash);
+
+        // Names are bytes, which don't have to be valid UTF-8
+        let blob: ObjectId = get_sha("HEAD:src/main.rs", root)?.parse()?;
+        let mut latin1 = b"100644 caf\xe9\0".to_vec();
+        latin1.extend(blob.as_bytes());
+        fs::write(root.join("latin1"), &latin1)?;
+        let hash = git(&["hash-object", "-t", "tree", "-w", "latin1"], root)?;
+        let odb = open_object_database(root)?;
+        let tree = odb.read(&hash.trim().parse()?)?;
+        assert_eq!(tree.content_bytes(), latin1);
+        let mut buff = Cursor::new(Vec::new());
+        _git_ls_tree(hash.trim(), false, &odb, &open_refs(root), &mut buff)?;
+        let output = Command::new("git")
+            .args(["-c", "core.quotePath=false", "ls-tree", hash.trim()])
+            .current_dir(root)
+            .output()?;
+        assert_eq!(buff.into_inner(), output.stdout);
+
+        // Status goes through the tree of HEAD
+        let commit = git(&["commit-tree", hash.trim(), "-m", "Latin-1"], root)?;
+        git(&["reset", "-q", "--soft", commit.trim()], root)?;
+        let mut output = Vec::new();
+        _git_status(
+            "",
+            &StatusOptions::default(),
+            &Repository::open(root)?,
+            &mut output,
+        )?;
+
+        dir.close()?;
+
+        Ok(())
+    }
+
     #[test]
     fn write_tree() -> Result<()> {
         let dir = tempfile::tempdir()?;
         let root = dir.path();
         create_git_repo_with_uncommited_files(root)
             .context("create git repo with uncommited files")?;
+        git(&["add", "."], root)?;
 
         let mut buff = Cursor::new(Vec::new());
-        _git_write_tree(root, &mut buff).context("call write-tree command")?;
+        _git_write_tree(
+            &root.join(".git/index"),
+            None,
+            false,
+            &open_object_database(root)?,
+            &mut buff,
+        )
+        .context("call write-tree command")?;
 
         buff.set_position(0);
         let mut lines = buff.lines();
@@ -391,6 +3827,220 @@ mod tests {
         assert!(objects.join(&hash[..2]).is_dir());
         assert!(objects.join(&hash[..2]).join(&hash[2..]).exists());
 
+        write_tree_from_index(root)?;
+
+        Ok(())
+    }
+
+    // What's on disk doesn't matter, only what's staged
+    fn write_tree_from_index(root: &Path) -> Result<()> {
+        let index_path = root.join(".git/index");
+        let odb = open_object_database(root)?;
+        let write_tree = |prefix: Option<&str>, missing_ok: bool| -> Result<String> {
+            let mut buff = Vec::new();
+            _git_write_tree(&index_path, prefix, missing_ok, &odb, &mut buff)?;
+            Ok(String::from_utf8(buff)?)
+        };
+
+        fs::create_dir_all(root.join("src/bin"))?;
+        fs::write(root.join("src/bin/tool.rs"), "tool\n")?;
+        fs::write(root.join("unstaged"), "unstaged\n")?;
+        git(&["add", "src/bin/tool.rs"], root)?;
+        fs::write(root.join("src/bin/tool.rs"), "changed\n")?;
+        git(
+            &["update-index", "--add", "--chmod=+x", "src/bin/tool.rs"],
+            root,
+        )?;
+        let tree = write_tree(None, false)?;
+        assert_eq!(tree, git(&["write-tree"], root)?.trim());
+        assert_eq!(
+            write_tree(Some("src/bin"), false)?,
+            git(&["write-tree", "--prefix=src/bin/"], root)?.trim()
+        );
+        assert!(write_tree(Some("missing"), false).is_err());
+
+        // The trees are saved in the index for git and the next run
+        let mut index = Index::open(&index_path)?;
+        let cache_tree = index.cache_tree.clone().context("missing cache tree")?;
+        assert_eq!(cache_tree.id, Some(tree.parse()?));
+        assert_eq!(cache_tree.entry_count, 3);
+        assert_eq!(
+            git(&["ls-files"], root)?,
+            "Cargo.toml\nsrc/bin/tool.rs\nsrc/main.rs\n"
+        );
+
+        // Valid trees aren't computed again: pretend `src` is the root tree
+        let cache_tree = index.cache_tree.as_mut().context("missing cache tree")?;
+        cache_tree.subtrees[0].id = Some(tree.parse()?);
+        index.write(&index_path)?;
+        assert_eq!(write_tree(Some("src"), false)?, tree);
+        fs::write(root.join("src/main.rs"), "fn main() {}\n")?;
+        git(&["add", "src/main.rs"], root)?;
+        assert_eq!(
+            write_tree(Some("src"), false)?,
+            git(&["write-tree", "--prefix=src"], root)?.trim()
+        );
+
+        // Missing objects and conflicts
+        let missing = "0123456789012345678901234567890123456789";
+        git(
+            &[
+                "update-index",
+                "--add",
+                "--cacheinfo",
+                &format!("100644,{},missing", missing),
+            ],
+            root,
+        )?;
+        assert!(write_tree(None, false).is_err());
+        assert_eq!(
+            write_tree(None, true)?,
+            git(&["write-tree", "--missing-ok"], root)?.trim()
+        );
+        git(&["update-index", "--force-remove", "missing"], root)?;
+        let mut index = Index::open(&index_path)?;
+        let mut conflict = index
+            .entry("Cargo.toml", 0)
+            .context("missing entry")?
+            .clone();
+        conflict.stage = 2;
+        index.add(conflict);
+        index.write(&index_path)?;
+        let error = format!("{:#}", write_tree(None, false).unwrap_err());
+        assert!(error.contains("path 'Cargo.toml' is unmerged"), "{}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_tree_with_symlinks_and_submodules() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo_with_uncommited_files(root)
+            .context("create git repo with uncommited files")?;
+        fs::write(root.join("run.sh"), "#!/bin/sh\n")?;
+        fs::set_permissions(root.join("run.sh"), fs::Permissions::from_mode(0o755))?;
+        std::os::unix::fs::symlink("src", root.join("link"))?;
+        let nested = root.join("nested");
+        fs::create_dir(&nested)?;
+        create_git_repo(&nested)?;
+        git(&["add", "."], root)?;
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_write_tree(
+            &root.join(".git/index"),
+            None,
+            false,
+            &open_object_database(root)?,
+            &mut buff,
+        )
+        .context("call write-tree command")?;
+
+        let hash = String::from_utf8(buff.into_inner())?;
+        assert_eq!(hash, git(&["write-tree"], root)?.trim());
+        let ls_tree = git(&["ls-tree", &hash], root)?;
+        assert!(ls_tree.contains("120000 blob "));
+        assert!(ls_tree.contains(&format!(
+            "160000 commit {}\tnested",
+            get_sha("HEAD", &nested)?
+        )));
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn commands_with_memory_object_database() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo_with_uncommited_files(root)
+            .context("create git repo with uncommited files")?;
+        fs::write(root.join("Cargo.toml"), "[package]\n")?;
+        let odb = MemoryObjectDatabase::new();
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_hash_object(&PathBuf::from("Cargo.toml"), root, &odb, &mut buff)?;
+        let blob = String::from_utf8(buff.into_inner())?;
+        let mut buff = Cursor::new(Vec::new());
+        _git_cat_file(&blob, &odb, &open_refs(root), &mut buff)?;
+        assert_eq!(buff.into_inner(), b"[package]\n");
+
+        // Stage the files without writing their blobs to the repository
+        let mut index = Index::default();
+        for path in ["Cargo.toml", "src/main.rs"] {
+            let mut buff = Cursor::new(Vec::new());
+            _git_hash_object(&PathBuf::from(path), root, &odb, &mut buff)?;
+            let id = String::from_utf8(buff.into_inner())?.parse()?;
+            let metadata = fs::metadata(root.join(path))?;
+            index.add(IndexEntry::from_metadata(
+                path,
+                &metadata,
+                FileMode::Regular,
+                id,
+            ));
+        }
+        let index_path = root.join(".git/index");
+        index.write(&index_path)?;
+
+        let mut buff = Cursor::new(Vec::new());
+        _git_write_tree(&index_path, None, false, &odb, &mut buff)?;
+        let tree = String::from_utf8(buff.into_inner())?;
+        let mut buff = Cursor::new(Vec::new());
+        _git_ls_tree(&tree, true, &odb, &open_refs(root), &mut buff)?;
+        assert_eq!(buff.into_inner(), b"Cargo.toml\nsrc\n");
+
+        // Nothing was written to the repository
+        assert_eq!(open_object_database(root)?.iter()?.count(), 0);
+        assert!(odb.exists(&tree.parse()?)?);
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn layered_object_database() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_git_repo_with_files(root).context("create git repo with files")?;
+        let head = get_sha("HEAD", root)?;
+        let tree = get_sha("HEAD^{tree}", root)?;
+
+        let odb = LayeredObjectDatabase::new(vec![
+            Box::new(MemoryObjectDatabase::new()),
+            Box::new(open_object_database(root)?),
+        ]);
+        let mut buff = Cursor::new(Vec::new());
+        let options = CommitTreeOptions {
+            parents: vec![head],
+            messages: vec!["In memory".to_string()],
+            ..Default::default()
+        };
+        _git_commit_tree(
+            &tree,
+            &options,
+            &odb,
+            &open_refs(root),
+            &mut std::io::empty(),
+            &mut buff,
+        )?;
+        let commit = String::from_utf8(buff.into_inner())?.parse()?;
+
+        // The commit was written to the first layer only, but its tree is read from the second
+        assert!(odb.layers()[0].exists(&commit)?);
+        assert!(!odb.layers()[1].exists(&commit)?);
+        let Object::Commit(commit) = odb.read(&commit)? else {
+            return Err(anyhow!("Expected a commit"));
+        };
+        assert!(matches!(odb.read(&commit.tree)?, Object::Tree(_)));
+        assert_eq!(
+            odb.iter()?.count(),
+            open_object_database(root)?.iter()?.count() + 1
+        );
+
+        dir.close()?;
+
         Ok(())
     }
 
@@ -404,9 +4054,20 @@ mod tests {
         let commit_sha = get_sha("HEAD", root)?;
 
         let mut buff = Cursor::new(Vec::new());
-        let msg = "A new commit";
-        _git_commit_tree(&tree_sha, &commit_sha, msg, root, &mut buff)
-            .context("call commit-tree command with hash of root")?;
+        let options = CommitTreeOptions {
+            parents: vec![commit_sha.clone()],
+            messages: vec!["A new commit".to_string()],
+            ..Default::default()
+        };
+        _git_commit_tree(
+            &tree_sha,
+            &options,
+            &open_object_database(root)?,
+            &open_refs(root),
+            &mut std::io::empty(),
+            &mut buff,
+        )
+        .context("call commit-tree command with hash of root")?;
 
         buff.set_position(0);
         let mut hash = String::new();
@@ -419,6 +4080,630 @@ mod tests {
             .join(&hash[2..])
             .exists());
 
+        let commit_tree = |options: &CommitTreeOptions, input: &str| -> Result<String> {
+            let mut output = Vec::new();
+            _git_commit_tree(
+                &tree_sha,
+                options,
+                &open_object_database(root)?,
+                &open_refs(root),
+                &mut Cursor::new(input.as_bytes()),
+                &mut output,
+            )?;
+            let commit = String::from_utf8(output)?;
+            git(&["cat-file", "commit", &commit], root)
+        };
+        let body = |commit: &str| commit.split_once("\n\n").map(|(_, body)| body.to_string());
+        let parents = |commit: &str| {
+            commit
+                .lines()
+                .filter(|line| line.starts_with("parent "))
+                .count()
+        };
+
+        // A root commit, with the message read from the standard input as it is
+        let commit = commit_tree(&CommitTreeOptions::default(), "  From stdin\n\n")?;
+        assert_eq!(parents(&commit), 0);
+        assert_eq!(body(&commit).as_deref(), Some("  From stdin\n\n"));
+
+        // A merge, where a parent given twice is only kept once
+        fs::write(root.join("message"), "From a file\n")?;
+        let commit = commit_tree(
+            &CommitTreeOptions {
+                parents: vec![commit_sha.clone(), hash.clone(), commit_sha.clone()],
+                messages: vec!["Subject".to_string(), "Body\n".to_string()],
+                file: Some(root.join("message")),
+            },
+            "",
+        )?;
+        assert_eq!(parents(&commit), 2);
+        assert_eq!(
+            body(&commit).as_deref(),
+            Some("Subject\n\nBody\n\nFrom a file\n")
+        );
+        assert!(commit_tree(
+            &CommitTreeOptions {
+                parents: vec![tree_sha.clone()],
+                ..Default::default()
+            },
+            "message"
+        )
+        .is_err());
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn commit() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_empty_git_repo(root)?;
+        let repo = Repository::open(root)?;
+        let commit = |options: &CommitOptions| -> Result<String> {
+            let mut output = Vec::new();
+            _git_commit(
+                options,
+                "",
+                &repo,
+                &mut Cursor::new(b"from stdin\n"),
+                &mut output,
+            )?;
+            Ok(String::from_utf8(output)?)
+        };
+        let message = |messages: &[&str]| CommitOptions {
+            messages: messages.iter().map(|message| message.to_string()).collect(),
+            ..Default::default()
+        };
+        let abbrev = || -> Result<String> {
+            Ok(git(&["rev-parse", "--short", "HEAD"], root)?
+                .trim_end()
+                .to_string())
+        };
+
+        assert!(commit(&message(&["empty"])).is_err());
+        fs::write(root.join("README"), "readme\n")?;
+        git(&["add", "README"], root)?;
+        assert!(commit(&CommitOptions::default()).is_err());
+        assert!(commit(&message(&["  ", ""])).is_err());
+        assert!(commit(&CommitOptions {
+            amend: true,
+            ..message(&["nothing to amend"])
+        })
+        .is_err());
+
+        let output = commit(&message(&["Initial  ", "", "Some\n\n\nbody  \n"]))?;
+        assert_eq!(
+            output,
+            format!("[master (root-commit) {}] Initial\n", abbrev()?)
+        );
+        assert_eq!(
+            git(&["log", "--format=%B"], root)?,
+            "Initial\n\nSome\n\nbody\n\n"
+        );
+        assert_eq!(git(&["rev-list", "--parents", "HEAD"], root)?.len(), 41);
+
+        // Nothing changed since HEAD
+        assert!(commit(&message(&["same"])).is_err());
+        let output = commit(&CommitOptions {
+            allow_empty: true,
+            ..message(&["Empty"])
+        })?;
+        assert_eq!(output, format!("[master {}] Empty\n", abbrev()?));
+
+        fs::create_dir(root.join("src"))?;
+        fs::write(root.join("src/lib.rs"), "lib\n")?;
+        fs::write(root.join("message"), "\n\nFrom a file\n\n")?;
+        git(&["add", "src"], root)?;
+        commit(&CommitOptions {
+            file: Some(root.join("message")),
+            ..Default::default()
+        })?;
+        assert_eq!(
+            git(&["log", "--format=%s"], root)?,
+            "From a file\nEmpty\nInitial\n"
+        );
+        // The tree is the one git would write from the index
+        let tree = git(&["write-tree"], root)?;
+        assert_eq!(git(&["rev-parse", "HEAD^{tree}"], root)?, tree);
+
+        // Amending keeps the parents and, without a new message, the old one
+        let parent = git(&["rev-parse", "HEAD^"], root)?;
+        commit(&CommitOptions {
+            amend: true,
+            author: Some("A U Thor <author@example.com>".to_string()),
+            date: Some("2005-04-07T22:13:13+0200".to_string()),
+            ..Default::default()
+        })?;
+        assert_eq!(git(&["rev-parse", "HEAD^"], root)?, parent);
+        assert_eq!(
+            git(
+                &["log", "-1", "--format=%an <%ae> %ad%n%B", "--date=raw"],
+                root
+            )?,
+            "A U Thor <author@example.com> 1112904793 +0200\nFrom a file\n\n"
+        );
+        assert!(commit(&CommitOptions {
+            author: Some("nobody".to_string()),
+            allow_empty: true,
+            ..message(&["bad author"])
+        })
+        .is_err());
+
+        assert_eq!(
+            git(&["reflog", "--format=%gs", "master"], root)?,
+            "commit (amend): From a file\ncommit: From a file\ncommit: Empty\n\
+            commit (initial): Initial\n"
+        );
+        assert_eq!(
+            git(&["reflog", "--format=%gs", "HEAD"], root)?,
+            git(&["reflog", "--format=%gs", "master"], root)?
+        );
+
+        git(&["checkout", "-q", "--detach"], root)?;
+        let output = commit(&CommitOptions {
+            allow_empty: true,
+            file: Some(PathBuf::from("-")),
+            ..Default::default()
+        })?;
+        assert_eq!(
+            output,
+            format!("[detached HEAD {}] from stdin\n", abbrev()?)
+        );
+        assert_eq!(
+            git(&["log", "-1", "--format=%s", "master"], root)?,
+            "From a file\n"
+        );
+
+        // Messages are stored as given, even when they aren't UTF-8
+        fs::write(root.join("message"), b"Caf\xe9  \n")?;
+        commit(&CommitOptions {
+            allow_empty: true,
+            file: Some(root.join("message")),
+            ..Default::default()
+        })?;
+        let raw = Command::new("git")
+            .args(["cat-file", "commit", "HEAD"])
+            .current_dir(root)
+            .output()?
+            .stdout;
+        assert!(raw.ends_with(b"\n\nCaf\xe9\n"));
+
+        git(&["fsck"], root)?;
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_and_rm() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_empty_git_repo(root)?;
+        let repo = Repository::open(root)?;
+        let add = |pathspecs: &[&str], prefix: &str, options: &AddOptions| -> Result<String> {
+            let pathspecs: Vec<String> = pathspecs.iter().map(|spec| spec.to_string()).collect();
+            let mut output = Vec::new();
+            _git_add(&pathspecs, prefix, options, &repo, &mut output)?;
+            Ok(String::from_utf8(output)?)
+        };
+        let rm = |pathspecs: &[&str], options: &RmOptions| -> Result<String> {
+            let pathspecs: Vec<String> = pathspecs.iter().map(|spec| spec.to_string()).collect();
+            let mut output = Vec::new();
+            _git_rm(&pathspecs, "", options, &repo, &mut output)?;
+            Ok(String::from_utf8(output)?)
+        };
+        let status = || git(&["status", "--porcelain"], root);
+
+        fs::create_dir_all(root.join("src/bin"))?;
+        fs::write(root.join("README"), "readme\n")?;
+        fs::write(root.join("src/lib.rs"), "lib\n")?;
+        fs::write(root.join("src/bin/main.rs"), "main\n")?;
+        fs::write(root.join("run.sh"), "#!/bin/sh\n")?;
+        fs::set_permissions(root.join("run.sh"), fs::Permissions::from_mode(0o755))?;
+        std::os::unix::fs::symlink("README", root.join("link"))?;
+
+        assert!(add(&[], "", &AddOptions::default()).is_err());
+        assert!(add(&["missing"], "", &AddOptions::default()).is_err());
+        assert!(add(&["../outside"], "", &AddOptions::default()).is_err());
+
+        // Pathspecs are relative to the current directory, and directories add what's in them
+        assert_eq!(add(&["bin"], "src/", &AddOptions::default())?, "");
+        assert_eq!(
+            status()?,
+            "A  src/bin/main.rs\n?? README\n?? link\n?? run.sh\n?? src/lib.rs\n"
+        );
+        let dry_run = AddOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        assert_eq!(add(&["*.rs"], "", &dry_run)?, "add 'src/lib.rs'\n");
+        assert_eq!(add(&["."], "", &AddOptions::default())?, "");
+        assert_eq!(
+            status()?,
+            "A  README\nA  link\nA  run.sh\nA  src/bin/main.rs\nA  src/lib.rs\n"
+        );
+        let staged = git(&["ls-files", "--stage"], root)?;
+        git(&["add", "."], root)?;
+        assert_eq!(git(&["ls-files", "--stage"], root)?, staged);
+        git(&["commit", "-m", "Initial commit"], root)?;
+
+        // -u only stages tracked files, -A new ones and removals too
+        fs::write(root.join("README"), "changed\n")?;
+        fs::remove_file(root.join("src/lib.rs"))?;
+        fs::write(root.join("new"), "new\n")?;
+        let update = AddOptions {
+            update: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            add(
+                &[],
+                "",
+                &AddOptions {
+                    dry_run: true,
+                    ..update.clone()
+                }
+            )?,
+            "add 'README'\nremove 'src/lib.rs'\n"
+        );
+        // Like git, tracked paths come in index order, then new files
+        assert_eq!(
+            add(&["."], "", &dry_run)?,
+            git(&["add", "--dry-run", "."], root)?
+        );
+        assert_eq!(status()?, " M README\n D src/lib.rs\n?? new\n");
+        add(&[], "", &update)?;
+        assert_eq!(status()?, "M  README\nD  src/lib.rs\n?? new\n");
+        let all = AddOptions {
+            all: true,
+            ..Default::default()
+        };
+        add(&[], "", &all)?;
+        assert_eq!(status()?, "M  README\nA  new\nD  src/lib.rs\n");
+        git(&["commit", "-m", "Second commit"], root)?;
+
+        // --chmod only changes the mode in the index
+        let chmod = AddOptions {
+            chmod: Some(true),
+            ..Default::default()
+        };
+        add(&["new"], "", &chmod)?;
+        assert_eq!(status()?, "MM new\n");
+        assert!(git(&["ls-files", "--stage", "new"], root)?.starts_with("100755"));
+        assert!(add(&["link"], "", &chmod).is_err());
+        add(
+            &["new"],
+            "",
+            &AddOptions {
+                chmod: Some(false),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(status()?, "");
+
+        // Staging a conflicted path resolves it
+        let id = git(&["hash-object", "-w", "README"], root)?;
+        git(&["rm", "--cached", "-q", "README"], root)?;
+        let mut update_index = Command::new("git")
+            .args(["update-index", "--index-info"])
+            .current_dir(root)
+            .stdin(std::process::Stdio::piped())
+            .spawn()?;
+        update_index.stdin.take().context("no stdin")?.write_all(
+            format!(
+                "100644 {} 2\tREADME\n100644 {} 3\tREADME\n",
+                id.trim(),
+                id.trim()
+            )
+            .as_bytes(),
+        )?;
+        update_index.wait()?;
+        assert_eq!(status()?, "AA README\n");
+        add(&["README"], "", &AddOptions::default())?;
+        assert_eq!(status()?, "");
+        assert!(Index::open(&repo.index_path())?.resolve_undo[0].path == "README");
+
+        // rm refuses to lose changes unless forced
+        let no_options = RmOptions::default();
+        let cached = RmOptions {
+            cached: true,
+            ..Default::default()
+        };
+        assert!(rm(&[], &no_options).is_err());
+        assert!(rm(&["missing"], &no_options).is_err());
+        assert!(rm(&["src"], &no_options).is_err());
+        fs::write(root.join("new"), "modified\n")?;
+        let error = rm(&["new"], &no_options).unwrap_err().to_string();
+        assert!(error.contains("has local modifications"), "{}", error);
+        git(&["add", "new"], root)?;
+        let error = rm(&["new"], &no_options).unwrap_err().to_string();
+        assert!(
+            error.contains("has changes staged in the index"),
+            "{}",
+            error
+        );
+        fs::write(root.join("new"), "modified again\n")?;
+        let error = rm(&["new"], &cached).unwrap_err().to_string();
+        assert!(
+            error.contains("different from both the\nfile and the HEAD"),
+            "{}",
+            error
+        );
+        assert_eq!(
+            rm(
+                &["new"],
+                &RmOptions {
+                    force: true,
+                    ..cached
+                }
+            )?,
+            "rm 'new'\n"
+        );
+        assert_eq!(status()?, "D  new\n?? new\n");
+        fs::remove_file(root.join("new"))?;
+
+        assert_eq!(rm(&["link"], &cached)?, "rm 'link'\n");
+        assert!(root.join("link").exists());
+        let recursive = RmOptions {
+            recursive: true,
+            ..Default::default()
+        };
+        assert_eq!(rm(&["src"], &recursive)?, "rm 'src/bin/main.rs'\n");
+        assert!(!root.join("src").exists());
+        assert_eq!(status()?, "D  link\nD  new\nD  src/bin/main.rs\n?? link\n");
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn status() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_empty_git_repo(root)?;
+        let repo = Repository::open(root)?;
+        let formats = [
+            (&["status"][..], StatusFormat::Long, false),
+            (
+                &["status", "--short", "--branch"],
+                StatusFormat::Short,
+                true,
+            ),
+            (&["status", "--porcelain"], StatusFormat::Porcelain, false),
+            (
+                &["status", "--porcelain=v2", "--branch"],
+                StatusFormat::PorcelainV2,
+                true,
+            ),
+        ];
+        // Every format matches git, from the root and from a subdirectory
+        let compare = |prefix: &str| -> Result<()> {
+            for (args, format, branch) in formats {
+                let mut output = Vec::new();
+                _git_status(
+                    prefix,
+                    &StatusOptions {
+                        format,
+                        branch,
+                        ..Default::default()
+                    },
+                    &repo,
+                    &mut output,
+                )?;
+                assert_eq!(
+                    String::from_utf8(output)?,
+                    git(args, &root.join(prefix))?,
+                    "{:?} in '{}'",
+                    format,
+                    prefix
+                );
+            }
+            Ok(())
+        };
+
+        compare("")?;
+        fs::create_dir_all(root.join("src/bin"))?;
+        fs::create_dir_all(root.join("untracked/deep"))?;
+        fs::write(root.join("README"), "readme\n")?;
+        fs::write(root.join("src/lib.rs"), "lib\n")?;
+        fs::write(root.join("src/bin/main.rs"), "main\n")?;
+        fs::write(root.join("untracked/deep/file"), "file\n")?;
+        compare("")?;
+        git(&["add", "README", "src/lib.rs"], root)?;
+        compare("")?;
+        git(&["add", "."], root)?;
+        git(&["commit", "-m", "Initial commit"], root)?;
+        compare("")?;
+
+        fs::write(root.join("README"), "changed\n")?;
+        fs::remove_file(root.join("src/lib.rs"))?;
+        fs::set_permissions(
+            root.join("src/bin/main.rs"),
+            fs::Permissions::from_mode(0o755),
+        )?;
+        std::os::unix::fs::symlink("README", root.join("link"))?;
+        fs::write(root.join("with space"), "")?;
+        fs::write(root.join("ümlaut"), "")?;
+        git(&["add", "link"], root)?;
+        git(&["add", "--intent-to-add", "with space"], root)?;
+        git(&["rm", "--cached", "-q", "untracked/deep/file"], root)?;
+        compare("")?;
+        compare("src/")?;
+        git(&["checkout", "-q", "--detach"], root)?;
+        compare("")?;
+        git(&["checkout", "-q", "master"], root)?;
+
+        // Files are only hashed again when their stat data changed
+        git(&["add", "--all"], root)?;
+        git(&["commit", "-m", "Second commit"], root)?;
+        let index_path = repo.index_path();
+        let mut index = Index::open(&index_path)?;
+        let mut entry = index.entry("README", 0).context("missing README")?.clone();
+        entry.mtime.seconds -= 1;
+        index.add(entry);
+        index.write(&index_path)?;
+        let mut output = Vec::new();
+        _git_status("", &StatusOptions::default(), &repo, &mut output)?;
+        assert!(String::from_utf8(output)?.ends_with("working tree clean\n"));
+        let index = Index::open(&index_path)?;
+        let entry = index.entry("README", 0).context("missing README")?;
+        assert_eq!(
+            entry.mtime.seconds as i64,
+            fs::metadata(root.join("README"))?.mtime()
+        );
+
+        // Conflicts in the middle of a merge
+        git(&["checkout", "-q", "-b", "other"], root)?;
+        fs::write(root.join("README"), "ours\n")?;
+        git(&["commit", "-q", "-am", "Ours"], root)?;
+        git(&["checkout", "-q", "master"], root)?;
+        fs::write(root.join("README"), "theirs\n")?;
+        git(&["commit", "-q", "-am", "Theirs"], root)?;
+        assert!(git(&["merge", "other"], root).is_err());
+        compare("")?;
+        git(&["add", "README"], root)?;
+        compare("")?;
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignore() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        create_empty_git_repo(root)?;
+        let repo = Repository::open(root)?;
+
+        for (path, contents) in [
+            ("target/debug/main.o", ""),
+            ("node_modules/dep/index.js", ""),
+            (".github/ci.yml", ""),
+            ("src/main.rs", ""),
+            ("src/gen/out.rs", ""),
+            ("src/gen/keep.rs", ""),
+            ("objects/only.o", ""),
+            ("mixed/file", ""),
+            ("mixed/file.o", ""),
+            ("docs/notes.tmp", ""),
+            ("debug.log", ""),
+            ("important.log", ""),
+            (
+                ".gitignore",
+                "target/\nnode_modules\n*.o\n*.log\n!important.log\n# comment\n/docs/*.tmp\n",
+            ),
+            ("src/gen/.gitignore", "out.rs\n"),
+            (".git/info/exclude", "*.tmp\n"),
+        ] {
+            fs::create_dir_all(root.join(path).parent().context("no parent")?)?;
+            fs::write(root.join(path), contents)?;
+        }
+        let excludes = root.join(".git/global-ignore");
+        fs::write(&excludes, "keep.rs\n")?;
+        git(
+            &["config", "core.excludesFile", &excludes.to_string_lossy()],
+            root,
+        )?;
+
+        // Untracked and ignored files like git, dotfiles included
+        for (args, format) in [
+            (
+                &["status", "--porcelain", "--ignored"][..],
+                StatusFormat::Porcelain,
+            ),
+            (&["status", "--ignored"], StatusFormat::Long),
+        ] {
+            let mut output = Vec::new();
+            let options = StatusOptions {
+                format,
+                ignored: true,
+                ..Default::default()
+            };
+            _git_status("", &options, &repo, &mut output)?;
+            assert_eq!(String::from_utf8(output)?, git(args, root)?);
+        }
+
+        let check_ignore = |paths: &[&str],
+                            prefix: &str,
+                            options: &CheckIgnoreOptions|
+         -> Result<(String, bool)> {
+            let paths: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+            let mut output = Vec::new();
+            let ignored = _git_check_ignore(&paths, prefix, options, &repo, &mut output)?;
+            Ok((String::from_utf8(output)?, ignored))
+        };
+        let paths = [
+            "debug.log",
+            "important.log",
+            "src/main.rs",
+            "target",
+            "target/debug/main.o",
+            "src/gen/out.rs",
+            "src/gen/keep.rs",
+            "docs/notes.tmp",
+        ];
+        let verbose = CheckIgnoreOptions {
+            verbose: true,
+            ..Default::default()
+        };
+        let non_matching = CheckIgnoreOptions {
+            non_matching: true,
+            ..verbose.clone()
+        };
+        for (args, options) in [
+            (&[][..], &CheckIgnoreOptions::default()),
+            (&["-v"], &verbose),
+            (&["-v", "-n"], &non_matching),
+        ] {
+            let args = [&["check-ignore"], args, &paths].concat();
+            assert_eq!(check_ignore(&paths, "", options)?.0, git(&args, root)?);
+        }
+        assert_eq!(
+            check_ignore(&["gen/out.rs", "../debug.log"], "src/", &verbose)?.0,
+            git(
+                &["check-ignore", "-v", "gen/out.rs", "../debug.log"],
+                &root.join("src")
+            )?
+        );
+        assert_eq!(
+            check_ignore(&["src/main.rs"], "", &verbose)?,
+            (String::new(), false)
+        );
+
+        // Ignored files have to be added explicitly with -f, and are tracked from then on
+        let add = |pathspecs: &[&str], force: bool| {
+            let pathspecs: Vec<String> = pathspecs.iter().map(|spec| spec.to_string()).collect();
+            let options = AddOptions {
+                force,
+                ..Default::default()
+            };
+            _git_add(&pathspecs, "", &options, &repo, &mut Vec::new())
+        };
+        let error = add(&["debug.log", "important.log"], false).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("ignored by one of your .gitignore files:\ndebug.log\n"));
+        assert!(add(&["target"], false).is_err());
+        assert!(add(&["target/debug/main.o"], false).is_err());
+        add(&["."], false)?;
+        add(&["debug.log"], true)?;
+        assert!(!check_ignore(&["debug.log"], "", &verbose)?.1);
+        let no_index = CheckIgnoreOptions {
+            no_index: true,
+            ..verbose
+        };
+        assert!(check_ignore(&["debug.log"], "", &no_index)?.1);
+        assert_eq!(
+            git(&["ls-files"], root)?,
+            ".github/ci.yml\n.gitignore\ndebug.log\nimportant.log\nmixed/file\nsrc/gen/.gitignore\n\
+            src/main.rs\n"
+        );
+
         dir.close()?;
 
         Ok(())
@@ -0,0 +1,79 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Git's way of updating a file atomically: the new contents are written to `<file>.lock`, which
+// is then renamed over the file. Creating the lock fails if it already exists, so there is only
+// one writer at a time. The lock is removed if it is dropped without being committed.
+pub(crate) struct LockFile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    file: File,
+    committed: bool,
+}
+
+impl LockFile {
+    pub(crate) fn acquire(path: &Path) -> Result<Self> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Create directory {}", parent.display()))?;
+        }
+        let file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(anyhow!(
+                    "Unable to create '{}': File exists. Another git process seems to be running.",
+                    lock_path.display()
+                ))
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Unable to create '{}'", lock_path.display()))
+            }
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            lock_path,
+            file,
+            committed: false,
+        })
+    }
+
+    // Replaces the locked file with what was written to the lock
+    pub(crate) fn commit(mut self) -> Result<()> {
+        self.file.sync_all().context("flush lock file")?;
+        fs::rename(&self.lock_path, &self.path)
+            .with_context(|| format!("rename {}", self.lock_path.display()))?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for LockFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // Not committed: release the lock and leave the file untouched
+        if !self.committed {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
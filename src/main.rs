@@ -1,22 +1,46 @@
-use std::path::PathBuf;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use clap::{Parser, Subcommand};
 
 use git_starter_rust::{
-    git_cat_file, git_commit_tree, git_hash_object, git_init, git_ls_tree, git_write_tree,
+    git_add, git_cat_file, git_check_ignore, git_commit, git_commit_tree, git_config, git_fsck,
+    git_gc, git_hash_object, git_init, git_ls_tree, git_mktag, git_reflog_expire, git_reflog_show,
+    git_repack, git_rev_parse, git_rm, git_show_ref, git_status, git_symbolic_ref, git_tag,
+    git_update_ref, git_write_tree, AddOptions, CheckIgnoreOptions, CommitOptions,
+    CommitTreeOptions, ConfigAction, ConfigError, ConfigLocation, InitOptions, Repository,
+    RmOptions, ShowRefOptions, StatusFormat, StatusOptions,
 };
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    // Run as if started in this directory, relative to the previous one when repeated
+    #[arg(short = 'C')]
+    directories: Vec<PathBuf>,
+    #[arg(long)]
+    git_dir: Option<PathBuf>,
     #[command(subcommand)]
     command: Command,
 }
 
 #[derive(Subcommand)]
 enum Command {
-    Init,
+    Init {
+        #[arg(long)]
+        bare: bool,
+        #[arg(short = 'b', long)]
+        initial_branch: Option<String>,
+        #[arg(long)]
+        template: Option<PathBuf>,
+        #[arg(long)]
+        object_format: Option<String>,
+        #[arg(short = 'q', long)]
+        quiet: bool,
+        directory: Option<PathBuf>,
+    },
     CatFile {
         #[arg(short = 'p')]
         blob_sha: Option<String>,
@@ -30,41 +54,491 @@ enum Command {
         name_only: bool,
         tree_sha: Option<String>,
     },
-    WriteTree,
+    Add {
+        #[arg(short = 'u', long)]
+        update: bool,
+        #[arg(short = 'A', long)]
+        all: bool,
+        #[arg(long, allow_hyphen_values = true)]
+        chmod: Option<String>,
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        #[arg(short = 'f', long)]
+        force: bool,
+        pathspecs: Vec<String>,
+    },
+    Rm {
+        #[arg(long)]
+        cached: bool,
+        #[arg(short = 'r')]
+        recursive: bool,
+        #[arg(short = 'f', long)]
+        force: bool,
+        pathspecs: Vec<String>,
+    },
+    Status {
+        #[arg(short = 's', long)]
+        short: bool,
+        // `--porcelain` alone is version 1
+        #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "v1")]
+        porcelain: Option<String>,
+        #[arg(short = 'b', long)]
+        branch: bool,
+        #[arg(long)]
+        ignored: bool,
+    },
+    CheckIgnore {
+        #[arg(short = 'v', long)]
+        verbose: bool,
+        #[arg(short = 'n', long)]
+        non_matching: bool,
+        #[arg(long)]
+        no_index: bool,
+        paths: Vec<String>,
+    },
+    WriteTree {
+        #[arg(long)]
+        prefix: Option<String>,
+        #[arg(long)]
+        missing_ok: bool,
+    },
+    Commit {
+        #[arg(short = 'm', long = "message")]
+        messages: Vec<String>,
+        #[arg(short = 'F', long)]
+        file: Option<PathBuf>,
+        #[arg(long)]
+        amend: bool,
+        #[arg(long)]
+        allow_empty: bool,
+        #[arg(long)]
+        author: Option<String>,
+        #[arg(long)]
+        date: Option<String>,
+    },
     CommitTree {
         tree_sha: String,
         #[arg(short = 'p')]
-        commit_sha: String,
+        parents: Vec<String>,
+        #[arg(short = 'm')]
+        messages: Vec<String>,
+        #[arg(short = 'F')]
+        file: Option<PathBuf>,
+    },
+    Mktag,
+    Tag {
+        #[arg(short = 'a')]
+        annotate: bool,
         #[arg(short = 'm')]
-        message: String,
+        message: Option<String>,
+        #[arg(short = 'f')]
+        force: bool,
+        name: String,
+        object: Option<String>,
+    },
+    Repack {
+        #[arg(short = 'a')]
+        all: bool,
+        #[arg(short = 'd')]
+        delete: bool,
+    },
+    Gc,
+    Fsck {
+        #[arg(long)]
+        unreachable: bool,
+        #[arg(long)]
+        strict: bool,
+    },
+    UpdateRef {
+        #[arg(short = 'd')]
+        delete: bool,
+        name: String,
+        #[arg(short = 'm')]
+        message: Option<String>,
+        // The new value, or the old value when deleting
+        value: Option<String>,
+        old_value: Option<String>,
+    },
+    Reflog {
+        #[command(subcommand)]
+        command: Option<ReflogCommand>,
+    },
+    SymbolicRef {
+        #[arg(long)]
+        short: bool,
+        name: String,
+        target: Option<String>,
+    },
+    RevParse {
+        #[arg(long)]
+        verify: bool,
+        #[arg(long, num_args = 0..=1, default_missing_value = "7", require_equals = true)]
+        short: Option<usize>,
+        revisions: Vec<String>,
+    },
+    ShowRef {
+        #[arg(long)]
+        head: bool,
+        #[arg(long)]
+        heads: bool,
+        #[arg(long)]
+        tags: bool,
+        #[arg(short = 'd', long)]
+        dereference: bool,
+        #[arg(long)]
+        verify: bool,
+        patterns: Vec<String>,
+    },
+    Config {
+        #[arg(long, group = "action")]
+        get: bool,
+        #[arg(long, group = "action")]
+        get_all: bool,
+        #[arg(long, group = "action")]
+        get_regexp: bool,
+        #[arg(short = 'l', long, group = "action")]
+        list: bool,
+        #[arg(long, group = "action")]
+        add: bool,
+        #[arg(long, group = "action")]
+        unset: bool,
+        #[arg(long, group = "location")]
+        global: bool,
+        #[arg(long, group = "location")]
+        local: bool,
+        #[arg(short = 'f', long, group = "location")]
+        file: Option<PathBuf>,
+        args: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ReflogCommand {
+    Show {
+        name: Option<String>,
+    },
+    Expire {
+        #[arg(long)]
+        expire: Option<String>,
+        #[arg(long)]
+        all: bool,
+        names: Vec<String>,
     },
 }
 
 fn main() -> Result<()> {
     let cli = Cli::try_parse()?;
 
+    for directory in &cli.directories {
+        env::set_current_dir(directory)
+            .with_context(|| format!("cannot change to '{}'", directory.display()))?;
+    }
+    if let Command::Init {
+        bare,
+        initial_branch,
+        template,
+        object_format,
+        quiet,
+        directory,
+    } = &cli.command
+    {
+        let options = InitOptions {
+            bare: *bare,
+            initial_branch: initial_branch.clone(),
+            template: template.clone(),
+            object_format: object_format.clone(),
+            quiet: *quiet,
+        };
+        return git_init(directory.as_deref(), &options);
+    }
+    if let Command::Config {
+        get,
+        get_all,
+        get_regexp,
+        list,
+        add,
+        unset,
+        global,
+        local,
+        file,
+        args,
+    } = &cli.command
+    {
+        let location = match (global, local, file) {
+            (true, _, _) => Some(ConfigLocation::Global),
+            (_, true, _) => Some(ConfigLocation::Local),
+            (_, _, Some(file)) => Some(ConfigLocation::File(file.clone())),
+            _ => None,
+        };
+        let mut args = args.iter().cloned();
+        let (first, second, third) = (args.next(), args.next(), args.next());
+        if args.next().is_some() {
+            return Err(anyhow!("Too many arguments for config"));
+        }
+        let missing = || anyhow!("Missing arguments for config");
+        let action = match (get, get_all, get_regexp, list, add, unset) {
+            (true, ..) | (_, true, ..) if third.is_none() => {
+                let key = first.ok_or_else(missing)?;
+                match get {
+                    true => ConfigAction::Get {
+                        key,
+                        value_pattern: second,
+                    },
+                    false => ConfigAction::GetAll {
+                        key,
+                        value_pattern: second,
+                    },
+                }
+            }
+            (_, _, true, ..) if third.is_none() => ConfigAction::GetRegexp {
+                name_pattern: first.ok_or_else(missing)?,
+                value_pattern: second,
+            },
+            (_, _, _, true, ..) if first.is_none() => ConfigAction::List,
+            (_, _, _, _, true, _) if third.is_none() => ConfigAction::Add {
+                key: first.ok_or_else(missing)?,
+                value: second.ok_or_else(missing)?,
+            },
+            (_, _, _, _, _, true) if third.is_none() => ConfigAction::Unset {
+                key: first.ok_or_else(missing)?,
+                value_pattern: second,
+            },
+            (false, false, false, false, false, false) => match second {
+                // `git config <key>` reads, `git config <key> <value>` writes
+                None => ConfigAction::Get {
+                    key: first.ok_or_else(missing)?,
+                    value_pattern: None,
+                },
+                Some(value) => ConfigAction::Set {
+                    key: first.ok_or_else(missing)?,
+                    value,
+                    value_pattern: third,
+                },
+            },
+            _ => return Err(anyhow!("Too many arguments for config")),
+        };
+        // Global settings don't need a repository
+        let repo = Repository::discover(Path::new("."), cli.git_dir.as_deref()).ok();
+        let result = git_config(repo.as_ref(), location.as_ref(), &action);
+        // Like git, scripts can tell from the exit code why the config couldn't be changed
+        if let Some(error) = result
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref::<ConfigError>())
+        {
+            eprintln!("Error: {}", error);
+            process::exit(error.exit_code());
+        }
+        return result;
+    }
+    let repo = Repository::discover(Path::new("."), cli.git_dir.as_deref())?;
+
     match &cli.command {
-        Command::Init => git_init(),
+        Command::Init { .. } | Command::Config { .. } => {
+            unreachable!("handled before looking for a repository")
+        }
         Command::CatFile { blob_sha } => match blob_sha {
-            Some(blob_sha) => git_cat_file(blob_sha),
+            Some(blob_sha) => git_cat_file(&repo, blob_sha),
             None => Ok(()),
         },
         Command::HashObject { file } => match file {
-            Some(file) => git_hash_object(file),
+            Some(file) => git_hash_object(&repo, file),
             None => Ok(()),
         },
         Command::LsTree {
-            name_only: _,
+            name_only,
             tree_sha,
         } => match tree_sha {
-            Some(tree_sha) => git_ls_tree(tree_sha),
+            Some(tree_sha) => git_ls_tree(&repo, tree_sha, *name_only),
             None => Ok(()),
         },
-        Command::WriteTree => git_write_tree(),
+        Command::Add {
+            update,
+            all,
+            chmod,
+            dry_run,
+            force,
+            pathspecs,
+        } => {
+            let chmod = match chmod.as_deref() {
+                None => None,
+                Some("+x") => Some(true),
+                Some("-x") => Some(false),
+                Some(chmod) => {
+                    return Err(anyhow!("--chmod param '{}' must be either -x or +x", chmod))
+                }
+            };
+            let options = AddOptions {
+                update: *update,
+                all: *all,
+                chmod,
+                dry_run: *dry_run,
+                force: *force,
+            };
+            git_add(&repo, pathspecs, &options)
+        }
+        Command::Rm {
+            cached,
+            recursive,
+            force,
+            pathspecs,
+        } => {
+            let options = RmOptions {
+                cached: *cached,
+                recursive: *recursive,
+                force: *force,
+            };
+            git_rm(&repo, pathspecs, &options)
+        }
+        Command::Status {
+            short,
+            porcelain,
+            branch,
+            ignored,
+        } => {
+            let format = match (porcelain.as_deref(), short) {
+                (Some("v1" | "1"), _) => StatusFormat::Porcelain,
+                (Some("v2" | "2"), _) => StatusFormat::PorcelainV2,
+                (Some(version), _) => {
+                    return Err(anyhow!("unsupported porcelain version '{}'", version))
+                }
+                (None, true) => StatusFormat::Short,
+                (None, false) => StatusFormat::Long,
+            };
+            let options = StatusOptions {
+                format,
+                branch: *branch,
+                ignored: *ignored,
+            };
+            git_status(&repo, &options)
+        }
+        Command::CheckIgnore {
+            verbose,
+            non_matching,
+            no_index,
+            paths,
+        } => {
+            let options = CheckIgnoreOptions {
+                verbose: *verbose,
+                non_matching: *non_matching,
+                no_index: *no_index,
+            };
+            // Like git, exit with 1 when nothing is ignored
+            if !git_check_ignore(&repo, paths, &options)? {
+                process::exit(1);
+            }
+            Ok(())
+        }
+        Command::WriteTree { prefix, missing_ok } => {
+            git_write_tree(&repo, prefix.as_deref(), *missing_ok)
+        }
+        Command::Commit {
+            messages,
+            file,
+            amend,
+            allow_empty,
+            author,
+            date,
+        } => git_commit(
+            &repo,
+            &CommitOptions {
+                messages: messages.clone(),
+                file: file.clone(),
+                amend: *amend,
+                allow_empty: *allow_empty,
+                author: author.clone(),
+                date: date.clone(),
+            },
+        ),
         Command::CommitTree {
             tree_sha,
-            commit_sha,
+            parents,
+            messages,
+            file,
+        } => git_commit_tree(
+            &repo,
+            tree_sha,
+            &CommitTreeOptions {
+                parents: parents.clone(),
+                messages: messages.clone(),
+                file: file.clone(),
+            },
+        ),
+        Command::Mktag => git_mktag(&repo),
+        Command::Tag {
+            annotate,
+            message,
+            force,
+            name,
+            object,
+        } => {
+            if *annotate && message.is_none() {
+                return Err(anyhow!("Annotated tags need a message, pass it with -m"));
+            }
+            git_tag(&repo, name, object.as_deref(), message.as_deref(), *force)
+        }
+        Command::Repack { all, delete } => git_repack(&repo, *all, *delete),
+        Command::Gc => git_gc(&repo),
+        Command::Fsck {
+            unreachable,
+            strict,
+        } => git_fsck(&repo, *unreachable, *strict),
+        Command::UpdateRef {
+            delete,
+            name,
             message,
-        } => git_commit_tree(tree_sha, commit_sha, message),
+            value,
+            old_value,
+        } => {
+            if *delete {
+                if old_value.is_some() {
+                    return Err(anyhow!("Too many arguments for update-ref -d"));
+                }
+                git_update_ref(&repo, name, None, value.as_deref(), message.as_deref())
+            } else {
+                let new = value.as_deref().ok_or(anyhow!("Missing new value"))?;
+                git_update_ref(
+                    &repo,
+                    name,
+                    Some(new),
+                    old_value.as_deref(),
+                    message.as_deref(),
+                )
+            }
+        }
+        Command::Reflog { command } => match command {
+            None => git_reflog_show(&repo, None),
+            Some(ReflogCommand::Show { name }) => git_reflog_show(&repo, name.as_deref()),
+            Some(ReflogCommand::Expire { expire, all, names }) => {
+                git_reflog_expire(&repo, expire.as_deref(), *all, names)
+            }
+        },
+        Command::SymbolicRef {
+            short,
+            name,
+            target,
+        } => git_symbolic_ref(&repo, name, target.as_deref(), *short),
+        Command::RevParse {
+            verify,
+            short,
+            revisions,
+        } => git_rev_parse(&repo, revisions, *verify, *short),
+        Command::ShowRef {
+            head,
+            heads,
+            tags,
+            dereference,
+            verify,
+            patterns,
+        } => {
+            let options = ShowRefOptions {
+                head: *head,
+                heads: *heads,
+                tags: *tags,
+                dereference: *dereference,
+                verify: *verify,
+            };
+            git_show_ref(&repo, patterns, &options)
+        }
     }
 }
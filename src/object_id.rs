@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Context, Result};
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::str;
+
+// The SHA-1 of a git object, stored as raw bytes and displayed as 40 hex characters
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ObjectId([u8; ObjectId::LEN]);
+
+impl ObjectId {
+    // Number of bytes in a raw id
+    pub const LEN: usize = 20;
+    // Number of characters in a hex-encoded id
+    pub const HEX_LEN: usize = 2 * Self::LEN;
+
+    // The all-zero id, used by git to denote the absence of an object, e.g. in reflogs
+    pub fn null() -> Self {
+        Self([0; Self::LEN])
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.0 == [0; Self::LEN]
+    }
+
+    pub fn hash(bytes: &[u8]) -> Self {
+        let mut hasher = Sha1::new();
+        hasher.update(bytes);
+        Self(hasher.finalize().into())
+    }
+
+    // Builds an id from its raw representation, e.g. as found in tree entries
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes = bytes.try_into().map_err(|_| {
+            anyhow!(
+                "Object id should be {} bytes long, got {}",
+                Self::LEN,
+                bytes.len()
+            )
+        })?;
+        Ok(Self(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; Self::LEN] {
+        &self.0
+    }
+
+    pub fn to_hex(self) -> String {
+        hex::encode(self.0)
+    }
+
+    // The first `len` hex characters of the id, like git's short ids
+    pub fn abbrev(&self, len: usize) -> String {
+        let mut hex = self.to_hex();
+        hex.truncate(len);
+        hex
+    }
+}
+
+impl From<[u8; ObjectId::LEN]> for ObjectId {
+    fn from(bytes: [u8; ObjectId::LEN]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl str::FromStr for ObjectId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != Self::HEX_LEN {
+            return Err(anyhow!(
+                "Object id should be {} hex characters long, got: {}",
+                Self::HEX_LEN,
+                s
+            ));
+        }
+        let mut bytes = [0; Self::LEN];
+        hex::decode_to_slice(s, &mut bytes).with_context(|| format!("Invalid object id: {}", s))?;
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ObjectId({})", self)
+    }
+}
@@ -0,0 +1,419 @@
+use anyhow::{anyhow, Context, Result};
+use flate2::{bufread::ZlibDecoder, write::ZlibEncoder, Compression};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{prelude::*, BufReader};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use crate::git_object::{Object, ObjectKind, Time};
+use crate::object_id::ObjectId;
+
+// A store of git objects, addressed by their id
+pub trait ObjectDatabase {
+    // Returns the kind and the contents (without the header) of an object
+    fn read_raw(&self, id: &ObjectId) -> Result<(ObjectKind, Vec<u8>)>;
+
+    // Stores an object given its kind and contents, and returns its id
+    fn write_raw(&self, kind: ObjectKind, content: &[u8]) -> Result<ObjectId>;
+
+    fn exists(&self, id: &ObjectId) -> Result<bool>;
+
+    // Iterates over the ids of all the objects in the store
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>>;
+
+    // Returns the ids of the objects whose hex representation starts with `prefix`, which must be
+    // lowercase hex. Stores can override this to avoid going through all the objects.
+    fn find_by_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        Ok(self
+            .iter()?
+            .filter(|id| id.to_hex().starts_with(prefix))
+            .collect())
+    }
+
+    fn read(&self, id: &ObjectId) -> Result<Object> {
+        let (kind, content) = self.read_raw(id)?;
+        Object::parse(kind, &content).with_context(|| format!("parse object {}", id))
+    }
+
+    fn write(&self, object: &Object) -> Result<ObjectId> {
+        self.write_raw(object.kind(), &object.content_bytes())
+    }
+}
+
+// The `.git/objects` directory, where each object is zlib-compressed in its own file
+pub struct LooseObjectDatabase {
+    path: PathBuf,
+}
+
+impl LooseObjectDatabase {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Objects are in a folder named after the first two characters of the hash, and the
+    // remaining characters are used for the file name
+    pub fn object_path(&self, id: &ObjectId) -> PathBuf {
+        let hash = id.to_hex();
+        let (dir_name, file_name) = hash.split_at(2);
+        self.path.join(dir_name).join(file_name)
+    }
+
+    // Returns the decompressed object, header included
+    pub fn read_loose(&self, id: &ObjectId) -> Result<Vec<u8>> {
+        let f = File::open(self.object_path(id)).with_context(|| format!("open object {}", id))?;
+        let reader = BufReader::new(f);
+
+        let mut z = ZlibDecoder::new(reader);
+        let mut buffer = Vec::new();
+        z.read_to_end(&mut buffer)
+            .with_context(|| format!("decompress object {}", id))?;
+
+        Ok(buffer)
+    }
+
+    // Deletes a loose object, e.g. once it has been packed
+    pub fn remove(&self, id: &ObjectId) -> Result<()> {
+        let path = self.object_path(id);
+        fs::remove_file(&path).with_context(|| format!("remove object {}", id))?;
+        // Clean up the fan-out directory if it is now empty, like `git prune-packed`
+        if let Some(dir) = path.parent() {
+            let _ = fs::remove_dir(dir);
+        }
+        Ok(())
+    }
+}
+
+impl ObjectDatabase for LooseObjectDatabase {
+    fn read_raw(&self, id: &ObjectId) -> Result<(ObjectKind, Vec<u8>)> {
+        let bytes = self.read_loose(id)?;
+        let (kind, content) =
+            Object::split_header(&bytes).with_context(|| format!("parse object {}", id))?;
+
+        Ok((kind, content.to_owned()))
+    }
+
+    // Objects are immutable, so an existing object is never rewritten. Otherwise the object is
+    // written to a temporary file which is then renamed, so that readers (and crashes) never see
+    // a partially written object.
+    fn write_raw(&self, kind: ObjectKind, content: &[u8]) -> Result<ObjectId> {
+        let bytes = Object::with_header(kind, content);
+        let id = ObjectId::hash(&bytes);
+
+        let file_path = self.object_path(&id);
+        if file_path.exists() {
+            return Ok(id);
+        }
+        // Create dir if necessary
+        let dir_path = file_path
+            .parent()
+            .ok_or(anyhow!("Invalid object path: {}", file_path.display()))?;
+        fs::create_dir_all(dir_path).context("Create directory in .git/objects")?;
+
+        // Create encoder and compress object
+        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+        e.write_all(&bytes)?;
+        let compressed = e.finish()?;
+
+        let (mut file, tmp_path) = create_temp_file(dir_path)?;
+        let written = file
+            .write_all(&compressed)
+            .and_then(|()| file.sync_all())
+            // Like git, objects are read-only
+            .and_then(|()| file.set_permissions(fs::Permissions::from_mode(0o444)))
+            .and_then(|()| fs::rename(&tmp_path, &file_path));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e).with_context(|| format!("write object {}", id));
+        }
+
+        Ok(id)
+    }
+
+    fn exists(&self, id: &ObjectId) -> Result<bool> {
+        Ok(self.object_path(id).is_file())
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let mut ids = Vec::new();
+        let dirs = match fs::read_dir(&self.path) {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Box::new(ids.into_iter()))
+            }
+            Err(e) => return Err(e).context("list objects directory"),
+        };
+        for dir in dirs {
+            let dir = dir?;
+            let dir_name = dir.file_name();
+            let Some(prefix) = dir_name.to_str() else {
+                continue;
+            };
+            if prefix.len() != 2 || !prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let file_name = file?.file_name();
+                // Skip files that are not objects, e.g. temporary files
+                if let Some(Ok(id)) = file_name
+                    .to_str()
+                    .map(|suffix| format!("{}{}", prefix, suffix).parse())
+                {
+                    ids.push(id);
+                }
+            }
+        }
+
+        Ok(Box::new(ids.into_iter()))
+    }
+
+    // Only the directory named after the first two characters needs to be scanned
+    fn find_by_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        if prefix.len() < 2 {
+            return Ok(self
+                .iter()?
+                .filter(|id| id.to_hex().starts_with(prefix))
+                .collect());
+        }
+        let (dir_name, file_prefix) = prefix.split_at(2);
+        let files = match fs::read_dir(self.path.join(dir_name)) {
+            Ok(files) => files,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context("list objects directory"),
+        };
+
+        let mut ids = Vec::new();
+        for file in files {
+            let file_name = file?.file_name();
+            let Some(suffix) = file_name.to_str() else {
+                continue;
+            };
+            if let (true, Ok(id)) = (
+                suffix.starts_with(file_prefix),
+                format!("{}{}", dir_name, suffix).parse(),
+            ) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+}
+
+// Creates a new file with a unique name in `dir`, which object listings ignore since it is not a
+// valid object name
+fn create_temp_file(dir: &Path) -> Result<(File, PathBuf)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let path = dir.join(format!(
+            "tmp_obj_{}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).context("Create temporary object file"),
+        }
+    }
+}
+
+// Keeps objects in memory, e.g. for tests or to build objects without touching the disk
+#[derive(Default)]
+pub struct MemoryObjectDatabase {
+    objects: RwLock<HashMap<ObjectId, (ObjectKind, Vec<u8>)>>,
+}
+
+impl MemoryObjectDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ObjectDatabase for MemoryObjectDatabase {
+    fn read_raw(&self, id: &ObjectId) -> Result<(ObjectKind, Vec<u8>)> {
+        let objects = self.objects.read().map_err(|_| anyhow!("Poisoned lock"))?;
+        objects
+            .get(id)
+            .cloned()
+            .ok_or(anyhow!("Object {} not found", id))
+    }
+
+    fn write_raw(&self, kind: ObjectKind, content: &[u8]) -> Result<ObjectId> {
+        let id = ObjectId::hash(&Object::with_header(kind, content));
+        let mut objects = self.objects.write().map_err(|_| anyhow!("Poisoned lock"))?;
+        objects
+            .entry(id)
+            .or_insert_with(|| (kind, content.to_owned()));
+        Ok(id)
+    }
+
+    fn exists(&self, id: &ObjectId) -> Result<bool> {
+        let objects = self.objects.read().map_err(|_| anyhow!("Poisoned lock"))?;
+        Ok(objects.contains_key(id))
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let objects = self.objects.read().map_err(|_| anyhow!("Poisoned lock"))?;
+        let ids: Vec<_> = objects.keys().copied().collect();
+        Ok(Box::new(ids.into_iter()))
+    }
+}
+
+// Reads from several stores in order, e.g. a repository and its alternates. Objects are
+// always written to the first store.
+pub struct LayeredObjectDatabase {
+    layers: Vec<Box<dyn ObjectDatabase>>,
+}
+
+impl LayeredObjectDatabase {
+    pub fn new(layers: Vec<Box<dyn ObjectDatabase>>) -> Self {
+        Self { layers }
+    }
+
+    pub fn layers(&self) -> &[Box<dyn ObjectDatabase>] {
+        &self.layers
+    }
+
+    fn find(&self, id: &ObjectId) -> Result<&dyn ObjectDatabase> {
+        for layer in &self.layers {
+            if layer.exists(id)? {
+                return Ok(layer.as_ref());
+            }
+        }
+        Err(anyhow!("Object {} not found", id))
+    }
+}
+
+impl ObjectDatabase for LayeredObjectDatabase {
+    fn read_raw(&self, id: &ObjectId) -> Result<(ObjectKind, Vec<u8>)> {
+        self.find(id)?.read_raw(id)
+    }
+
+    // Like git, an object any layer already has (in a pack for instance) isn't written again
+    fn write_raw(&self, kind: ObjectKind, content: &[u8]) -> Result<ObjectId> {
+        let id = ObjectId::hash(&Object::with_header(kind, content));
+        if self.exists(&id)? {
+            return Ok(id);
+        }
+        self.layers
+            .first()
+            .ok_or(anyhow!("No object database to write to"))?
+            .write_raw(kind, content)
+    }
+
+    fn exists(&self, id: &ObjectId) -> Result<bool> {
+        for layer in &self.layers {
+            if layer.exists(id)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let mut ids = BTreeSet::new();
+        for layer in &self.layers {
+            ids.extend(layer.iter()?);
+        }
+        Ok(Box::new(ids.into_iter()))
+    }
+
+    fn find_by_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        let mut ids = BTreeSet::new();
+        for layer in &self.layers {
+            ids.extend(layer.find_by_prefix(prefix)?);
+        }
+        Ok(ids.into_iter().collect())
+    }
+}
+
+// Like git, abbreviated ids must be at least 4 characters long
+const MIN_ABBREV_LEN: usize = 4;
+
+// Resolves a full or abbreviated hex object id. Abbreviated ids must match a single object.
+pub fn resolve_object_id(odb: &dyn ObjectDatabase, name: &str) -> Result<ObjectId> {
+    if name.len() < MIN_ABBREV_LEN
+        || name.len() > ObjectId::HEX_LEN
+        || !name.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return Err(anyhow!("Not a valid object name {}", name));
+    }
+    if name.len() == ObjectId::HEX_LEN {
+        return name.parse();
+    }
+
+    let prefix = name.to_ascii_lowercase();
+    let mut candidates = odb.find_by_prefix(&prefix)?;
+    match candidates.len() {
+        0 => Err(anyhow!("Not a valid object name {}", name)),
+        1 => Ok(candidates[0]),
+        _ => {
+            candidates.sort_unstable();
+            // Show the candidates with just enough characters to tell them apart
+            let len = (7.max(prefix.len())..ObjectId::HEX_LEN)
+                .find(|&len| {
+                    candidates
+                        .windows(2)
+                        .all(|w| w[0].abbrev(len) != w[1].abbrev(len))
+                })
+                .unwrap_or(ObjectId::HEX_LEN);
+
+            let mut message = format!(
+                "short object ID {} is ambiguous\nhint: The candidates are:",
+                name
+            );
+            for candidate in &candidates {
+                message.push_str(&format!(
+                    "\nhint:   {} {}",
+                    candidate.abbrev(len),
+                    describe_candidate(odb, candidate)
+                ));
+            }
+            Err(anyhow!(message))
+        }
+    }
+}
+
+// The shortest abbreviation of `id`, at least `min_len` characters long, that no other object
+// shares
+pub fn abbreviate_object_id(
+    odb: &dyn ObjectDatabase,
+    id: &ObjectId,
+    min_len: usize,
+) -> Result<String> {
+    for len in min_len.max(MIN_ABBREV_LEN)..ObjectId::HEX_LEN {
+        let abbrev = id.abbrev(len);
+        if odb.find_by_prefix(&abbrev)?.len() <= 1 {
+            return Ok(abbrev);
+        }
+    }
+    Ok(id.to_hex())
+}
+
+// Like git, commits and tags are shown with their date and subject or name
+fn describe_candidate(odb: &dyn ObjectDatabase, id: &ObjectId) -> String {
+    let date = |time: &Time| {
+        chrono::DateTime::from_timestamp(time.seconds, 0)
+            .map(|date| date.format("%Y-%m-%d").to_string())
+            .unwrap_or_default()
+    };
+    match odb.read(id) {
+        Ok(Object::Commit(commit)) => {
+            let message = String::from_utf8_lossy(&commit.message);
+            let subject = message.lines().next().unwrap_or_default();
+            format!("commit {} - {}", date(&commit.committer.time), subject)
+        }
+        Ok(Object::Tag(tag)) => match &tag.tagger {
+            Some(tagger) => format!("tag {} - {}", date(&tagger.time), tag.name),
+            None => format!("tag {}", tag.name),
+        },
+        Ok(object) => object.kind().to_string(),
+        Err(_) => "unknown".to_string(),
+    }
+}
@@ -0,0 +1,937 @@
+use anyhow::{anyhow, Context, Result};
+use flate2::read::ZlibDecoder;
+use sha1::{Digest, Sha1};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use crate::git_object::ObjectKind;
+use crate::object_id::ObjectId;
+use crate::odb::ObjectDatabase;
+
+// Pack entry types, as stored in the header of each entry
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+// Delta chains can't be infinitely long, this protects against cycles in corrupt packs
+const MAX_DELTA_DEPTH: usize = 10_000;
+
+// Sizes in headers aren't trusted until the data has been read, so they only decide how much to
+// reserve up to this limit
+const MAX_RESERVE: usize = 1 << 24;
+
+// A version 2 pack index (`.idx` file), mapping object ids to offsets in the pack
+//
+// Its layout is:
+// - the magic number `\377tOc` and the version number (2)
+// - a fanout table of 256 entries, entry `n` being the number of objects whose id starts with a
+//   byte lower or equal to `n`
+// - the sorted object ids
+// - the CRC32 of the packed data of each object
+// - the offset of each object, or an index in the following table if the MSB is set
+// - the 64-bit offsets, for packs larger than 2GiB
+// - the checksum of the pack and the checksum of the index
+pub struct PackIndex {
+    ids: Vec<ObjectId>,
+    crcs: Vec<u32>,
+    offsets: Vec<u64>,
+    pack_checksum: ObjectId,
+}
+
+impl PackIndex {
+    const MAGIC: &'static [u8] = b"\xfftOc";
+    const VERSION: u32 = 2;
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        Self::from_bytes(&bytes).with_context(|| format!("parse {}", path.display()))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = bytes;
+        let magic = take(&mut reader, 4)?;
+        if magic != Self::MAGIC {
+            return Err(anyhow!("Only version 2 pack indexes are supported"));
+        }
+        let version = read_u32(&mut reader)?;
+        if version != Self::VERSION {
+            return Err(anyhow!("Unsupported pack index version: {}", version));
+        }
+
+        let mut fanout = [0; 256];
+        for entry in fanout.iter_mut() {
+            *entry = read_u32(&mut reader)?;
+        }
+        if fanout.windows(2).any(|w| w[0] > w[1]) {
+            return Err(anyhow!("Fanout table is not monotonic"));
+        }
+        let count = fanout[255] as usize;
+
+        let ids = take(&mut reader, count * ObjectId::LEN)?
+            .chunks_exact(ObjectId::LEN)
+            .map(ObjectId::from_bytes)
+            .collect::<Result<Vec<_>>>()?;
+        let crcs = (0..count)
+            .map(|_| read_u32(&mut reader))
+            .collect::<Result<Vec<_>>>()?;
+        let small_offsets = (0..count)
+            .map(|_| read_u32(&mut reader))
+            .collect::<Result<Vec<_>>>()?;
+
+        let large_offset_count = small_offsets
+            .iter()
+            .filter(|&&offset| offset & 0x8000_0000 != 0)
+            .count();
+        let large_offsets = (0..large_offset_count)
+            .map(|_| read_u64(&mut reader))
+            .collect::<Result<Vec<_>>>()?;
+        let offsets = small_offsets
+            .into_iter()
+            .map(|offset| {
+                if offset & 0x8000_0000 == 0 {
+                    return Ok(offset as u64);
+                }
+                large_offsets
+                    .get((offset & 0x7fff_ffff) as usize)
+                    .copied()
+                    .ok_or(anyhow!("Invalid large offset index"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let pack_checksum = ObjectId::from_bytes(take(&mut reader, ObjectId::LEN)?)?;
+        let index_checksum = ObjectId::from_bytes(take(&mut reader, ObjectId::LEN)?)?;
+        if !reader.is_empty() {
+            return Err(anyhow!("Unexpected data at the end of the pack index"));
+        }
+        if ObjectId::hash(&bytes[..bytes.len() - ObjectId::LEN]) != index_checksum {
+            return Err(anyhow!("Pack index checksum mismatch"));
+        }
+
+        Ok(Self {
+            ids,
+            crcs,
+            offsets,
+            pack_checksum,
+        })
+    }
+
+    // The sorted ids of the objects in the pack
+    pub fn ids(&self) -> &[ObjectId] {
+        &self.ids
+    }
+
+    // Since ids are sorted, the ones starting with `prefix` are contiguous
+    pub fn find_by_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        let lowest: ObjectId = format!("{:0<width$}", prefix, width = ObjectId::HEX_LEN).parse()?;
+        let start = self.ids.partition_point(|id| id < &lowest);
+        Ok(self.ids[start..]
+            .iter()
+            .take_while(|id| id.to_hex().starts_with(prefix))
+            .copied()
+            .collect())
+    }
+
+    pub fn offset(&self, id: &ObjectId) -> Option<u64> {
+        let idx = self.ids.binary_search(id).ok()?;
+        Some(self.offsets[idx])
+    }
+
+    pub fn crc(&self, id: &ObjectId) -> Option<u32> {
+        let idx = self.ids.binary_search(id).ok()?;
+        Some(self.crcs[idx])
+    }
+
+    pub fn pack_checksum(&self) -> ObjectId {
+        self.pack_checksum
+    }
+}
+
+// A `.pack` file along with its index
+//
+// A pack starts with the `PACK` signature, a version number and the number of objects, followed
+// by the objects and the checksum of everything that precedes it. Each object is made up of:
+// - a header with the type and the size of the (uncompressed) data, as a variable length integer
+// - for deltas, the base object given as a negative offset (OFS_DELTA) or an id (REF_DELTA)
+// - the zlib-compressed data
+pub struct Pack {
+    path: PathBuf,
+    file: File,
+    index: PackIndex,
+}
+
+impl Pack {
+    // Opens a pack given the path to its `.idx` file
+    pub fn open(index_path: &Path) -> Result<Self> {
+        let index = PackIndex::open(index_path)?;
+        let path = index_path.with_extension("pack");
+        let file = File::open(&path).with_context(|| format!("open {}", path.display()))?;
+
+        let mut header = [0; 12];
+        file.read_exact_at(&mut header, 0)
+            .with_context(|| format!("read header of {}", path.display()))?;
+        if &header[..4] != b"PACK" {
+            return Err(anyhow!("{} is not a pack", path.display()));
+        }
+        let version = u32::from_be_bytes(header[4..8].try_into()?);
+        if version != 2 && version != 3 {
+            return Err(anyhow!("Unsupported pack version: {}", version));
+        }
+        let count = u32::from_be_bytes(header[8..12].try_into()?);
+        if count as usize != index.ids().len() {
+            return Err(anyhow!(
+                "{} has {} objects but its index has {}",
+                path.display(),
+                count,
+                index.ids().len()
+            ));
+        }
+
+        Ok(Self { path, file, index })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn index(&self) -> &PackIndex {
+        &self.index
+    }
+
+    // Checks that the trailing checksum matches both the contents of the pack and its index
+    pub fn verify_checksum(&self) -> Result<()> {
+        let len = self.file.metadata()?.len();
+        let data_len = len
+            .checked_sub(ObjectId::LEN as u64)
+            .ok_or(anyhow!("{} is truncated", self.path.display()))?;
+
+        let mut hasher = Sha1::new();
+        let mut reader = PackReader {
+            file: &self.file,
+            offset: 0,
+        }
+        .take(data_len);
+        io::copy(&mut reader, &mut hasher)?;
+        let computed = ObjectId::from_bytes(&hasher.finalize())?;
+
+        let mut trailer = [0; ObjectId::LEN];
+        self.file.read_exact_at(&mut trailer, data_len)?;
+        if computed != ObjectId::from(trailer) {
+            return Err(anyhow!("{} checksum mismatch", self.path.display()));
+        }
+        if computed != self.index.pack_checksum() {
+            return Err(anyhow!("{} does not match its index", self.path.display()));
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, id: &ObjectId) -> bool {
+        self.index.offset(id).is_some()
+    }
+
+    // Reads an object, resolving deltas. Bases of REF_DELTA entries that are not in this pack are
+    // looked up in the other packs of `external`, if any.
+    pub fn read(
+        &self,
+        id: &ObjectId,
+        external: Option<&PackedObjectDatabase>,
+    ) -> Result<Option<(ObjectKind, Vec<u8>)>> {
+        let Some(offset) = self.index.offset(id) else {
+            return Ok(None);
+        };
+        self.read_at(offset, external)
+            .with_context(|| format!("read object {} from {}", id, self.path.display()))
+            .map(Some)
+    }
+
+    // Deltas are collected down to the base object, then applied from there, so that long chains
+    // (possibly going through other packs) don't need deep recursion
+    fn read_at<'a>(
+        &'a self,
+        offset: u64,
+        external: Option<&'a PackedObjectDatabase>,
+    ) -> Result<(ObjectKind, Vec<u8>)> {
+        let mut deltas = Vec::new();
+        let (mut pack, mut offset) = (self, offset);
+        let (kind, mut content) = loop {
+            if deltas.len() > MAX_DELTA_DEPTH {
+                return Err(anyhow!("Delta chain is too long"));
+            }
+
+            let mut reader = PackReader {
+                file: &pack.file,
+                offset,
+            };
+            let (entry_type, size) = read_entry_header(&mut reader)?;
+
+            let kind = match entry_type {
+                OBJ_COMMIT => ObjectKind::Commit,
+                OBJ_TREE => ObjectKind::Tree,
+                OBJ_BLOB => ObjectKind::Blob,
+                OBJ_TAG => ObjectKind::Tag,
+                OBJ_OFS_DELTA => {
+                    offset = offset
+                        .checked_sub(read_offset_encoding(&mut reader)?)
+                        .ok_or(anyhow!("Delta base offset is out of bounds"))?;
+                    deltas.push(decompress(&mut reader, size)?);
+                    continue;
+                }
+                OBJ_REF_DELTA => {
+                    let mut base_id = [0; ObjectId::LEN];
+                    reader.read_exact(&mut base_id)?;
+                    let base_id = ObjectId::from(base_id);
+                    deltas.push(decompress(&mut reader, size)?);
+                    (pack, offset) = match pack.index.offset(&base_id) {
+                        Some(base_offset) => (pack, base_offset),
+                        None => external
+                            .ok_or(anyhow!("Delta base {} is not in the pack", base_id))?
+                            .packs
+                            .iter()
+                            .find_map(|other| Some((other, other.index.offset(&base_id)?)))
+                            .ok_or(anyhow!("Delta base {} not found", base_id))?,
+                    };
+                    continue;
+                }
+                t => return Err(anyhow!("Invalid pack entry type: {}", t)),
+            };
+            break (kind, decompress(&mut reader, size)?);
+        };
+
+        for delta in deltas.iter().rev() {
+            content = apply_delta(&content, delta)?;
+        }
+        Ok((kind, content))
+    }
+}
+
+// Reads the file sequentially from an offset, without needing a mutable file handle
+struct PackReader<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for PackReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+// The type is in bits 4 to 6 of the first byte, and the size is a variable length integer made of
+// the 4 low bits of the first byte followed by 7 bits per byte as long as the MSB is set
+fn read_entry_header<R: Read>(reader: &mut R) -> Result<(u8, usize)> {
+    let mut byte = read_byte(reader)?;
+    let entry_type = (byte >> 4) & 0b111;
+    let mut size = (byte & 0b1111) as usize;
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = read_byte(reader)?;
+        size |= ((byte & 0x7f) as usize)
+            .checked_shl(shift)
+            .ok_or(anyhow!("Entry size overflow"))?;
+        shift += 7;
+    }
+    Ok((entry_type, size))
+}
+
+// The offset of OFS_DELTA bases uses a slightly different encoding than sizes: big-endian, and
+// adding one for each continuation byte so that there is a single encoding per value
+pub(crate) fn read_offset_encoding<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut byte = read_byte(reader)?;
+    let mut offset = (byte & 0x7f) as u64;
+    while byte & 0x80 != 0 {
+        byte = read_byte(reader)?;
+        offset = offset
+            .checked_add(1)
+            .and_then(|offset| offset.checked_mul(128))
+            .ok_or(anyhow!("Delta base offset overflow"))?
+            | (byte & 0x7f) as u64;
+    }
+    Ok(offset)
+}
+
+fn decompress<R: Read>(reader: &mut R, size: usize) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size.min(MAX_RESERVE));
+    ZlibDecoder::new(reader)
+        .take(size as u64 + 1)
+        .read_to_end(&mut data)
+        .context("decompress pack entry")?;
+    if data.len() != size {
+        return Err(anyhow!(
+            "Pack entry should be {} bytes long, got {}",
+            size,
+            data.len()
+        ));
+    }
+    Ok(data)
+}
+
+// A delta starts with the sizes of the base and of the result, followed by instructions to either
+// copy a range of the base (MSB set) or insert the bytes that follow the instruction
+pub(crate) fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut delta = delta;
+    let base_size = read_size(&mut delta)?;
+    if base_size != base.len() {
+        return Err(anyhow!(
+            "Delta base should be {} bytes long, got {}",
+            base_size,
+            base.len()
+        ));
+    }
+    let result_size = read_size(&mut delta)?;
+
+    let mut result = Vec::with_capacity(result_size.min(MAX_RESERVE));
+    while let Some((&instruction, rest)) = delta.split_first() {
+        delta = rest;
+        if instruction & 0x80 != 0 {
+            // The low 4 bits tell which bytes of the offset are present, the next 3 bits which
+            // bytes of the size are present
+            let mut offset = 0;
+            for i in 0..4 {
+                if instruction & (1 << i) != 0 {
+                    offset |= (read_byte(&mut delta)? as usize) << (8 * i);
+                }
+            }
+            let mut size = 0;
+            for i in 0..3 {
+                if instruction & (1 << (4 + i)) != 0 {
+                    size |= (read_byte(&mut delta)? as usize) << (8 * i);
+                }
+            }
+            if size == 0 {
+                size = 0x10000;
+            }
+            let chunk = offset
+                .checked_add(size)
+                .and_then(|end| base.get(offset..end))
+                .ok_or(anyhow!("Delta copies data out of the base"))?;
+            result.extend(chunk);
+        } else if instruction != 0 {
+            let chunk = take(&mut delta, instruction as usize)?;
+            result.extend(chunk);
+        } else {
+            return Err(anyhow!("Invalid delta instruction"));
+        }
+        if result.len() > result_size {
+            return Err(anyhow!("Delta result is longer than {} bytes", result_size));
+        }
+    }
+
+    if result.len() != result_size {
+        return Err(anyhow!(
+            "Delta result should be {} bytes long, got {}",
+            result_size,
+            result.len()
+        ));
+    }
+    Ok(result)
+}
+
+// Sizes in deltas are little-endian variable length integers, 7 bits per byte
+fn read_size(bytes: &mut &[u8]) -> Result<usize> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = read_byte(bytes)?;
+        size |= ((byte & 0x7f) as usize)
+            .checked_shl(shift)
+            .ok_or(anyhow!("Delta size overflow"))?;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte).context("read byte")?;
+    Ok(byte[0])
+}
+
+pub(crate) fn read_u32(bytes: &mut &[u8]) -> Result<u32> {
+    Ok(u32::from_be_bytes(take(bytes, 4)?.try_into()?))
+}
+
+fn read_u64(bytes: &mut &[u8]) -> Result<u64> {
+    Ok(u64::from_be_bytes(take(bytes, 8)?.try_into()?))
+}
+
+pub(crate) fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if bytes.len() < n {
+        return Err(anyhow!("Unexpected end of data"));
+    }
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Ok(head)
+}
+
+// All the packs in a `.git/objects/pack` directory
+pub struct PackedObjectDatabase {
+    path: PathBuf,
+    packs: Vec<Pack>,
+}
+
+impl PackedObjectDatabase {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let mut packs = Vec::new();
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self { path, packs }),
+            Err(e) => return Err(e).context("list pack directory"),
+        };
+        for entry in entries {
+            let index_path = entry?.path();
+            // Packs are only usable once their index has been written
+            if index_path.extension().is_some_and(|ext| ext == "idx")
+                && index_path.with_extension("pack").is_file()
+            {
+                packs.push(Pack::open(&index_path)?);
+            }
+        }
+        Ok(Self { path, packs })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn packs(&self) -> &[Pack] {
+        &self.packs
+    }
+}
+
+impl ObjectDatabase for PackedObjectDatabase {
+    fn read_raw(&self, id: &ObjectId) -> Result<(ObjectKind, Vec<u8>)> {
+        for pack in &self.packs {
+            // Bases of REF_DELTA entries may live in another pack of the same directory
+            if let Some(object) = pack.read(id, Some(self))? {
+                return Ok(object);
+            }
+        }
+        Err(anyhow!("Object {} not found", id))
+    }
+
+    fn write_raw(&self, _kind: ObjectKind, _content: &[u8]) -> Result<ObjectId> {
+        Err(anyhow!("Objects can't be written directly to a pack"))
+    }
+
+    fn exists(&self, id: &ObjectId) -> Result<bool> {
+        Ok(self.packs.iter().any(|pack| pack.contains(id)))
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        Ok(Box::new(
+            self.packs
+                .iter()
+                .flat_map(|pack| pack.index().ids().iter().copied()),
+        ))
+    }
+
+    fn find_by_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        let mut ids = Vec::new();
+        for pack in &self.packs {
+            ids.extend(pack.index().find_by_prefix(prefix)?);
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        Ok(ids)
+    }
+}
+
+// Number of previous objects tried as delta bases for each object, like `git pack-objects`
+const DELTA_WINDOW: usize = 10;
+// Maximum length of delta chains, to keep reads fast
+const MAX_WRITE_DELTA_DEPTH: usize = 50;
+// Deltas are computed by looking up blocks of this size of the target in the base
+const DELTA_BLOCK_SIZE: usize = 16;
+// Copy instructions can encode up to 3 bytes of size, but git only uses 2 for compatibility
+const MAX_COPY_SIZE: usize = 0x10000;
+// Insert instructions store their size in the 7 low bits of the instruction
+const MAX_INSERT_SIZE: usize = 0x7f;
+
+struct PackEntry {
+    id: ObjectId,
+    kind: ObjectKind,
+    content: Vec<u8>,
+    // Index of the base in the list of entries along with the delta against it
+    delta: Option<(usize, Vec<u8>)>,
+    depth: usize,
+}
+
+// Writes the given objects to a new pack and its index in `dir`, like `git pack-objects`, and
+// returns the checksum of the pack, which is also its name: `pack-<checksum>.pack`
+pub fn write_pack(odb: &dyn ObjectDatabase, ids: &[ObjectId], dir: &Path) -> Result<ObjectId> {
+    let mut ids = ids.to_vec();
+    ids.sort_unstable();
+    ids.dedup();
+
+    let mut entries = ids
+        .into_iter()
+        .map(|id| {
+            let (kind, content) = odb.read_raw(&id)?;
+            Ok(PackEntry {
+                id,
+                kind,
+                content,
+                delta: None,
+                depth: 0,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Similar objects should be close to each other for the delta search. Without the paths of
+    // the objects, grouping them by kind and size is a reasonable heuristic. Larger objects come
+    // first since removing data produces smaller deltas than adding it.
+    entries.sort_by(|a, b| {
+        (a.kind as u8, b.content.len(), a.id).cmp(&(b.kind as u8, a.content.len(), b.id))
+    });
+    find_deltas(&mut entries);
+
+    // Bases always come before the objects that depend on them, so OFS_DELTA can be used
+    let mut pack = b"PACK".to_vec();
+    pack.extend(2u32.to_be_bytes());
+    pack.extend(u32::try_from(entries.len())?.to_be_bytes());
+
+    let mut offsets = Vec::with_capacity(entries.len());
+    let mut crcs = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        let offset = pack.len() as u64;
+        let data = match &entry.delta {
+            Some((base, delta)) => {
+                let mut data = encode_entry_header(OBJ_OFS_DELTA, delta.len());
+                data.extend(encode_offset(offset - offsets[*base]));
+                data.extend(compress(delta)?);
+                data
+            }
+            None => {
+                let entry_type = match entry.kind {
+                    ObjectKind::Commit => OBJ_COMMIT,
+                    ObjectKind::Tree => OBJ_TREE,
+                    ObjectKind::Blob => OBJ_BLOB,
+                    ObjectKind::Tag => OBJ_TAG,
+                };
+                let mut data = encode_entry_header(entry_type, entry.content.len());
+                data.extend(compress(&entry.content)?);
+                data
+            }
+        };
+
+        let mut crc = flate2::Crc::new();
+        crc.update(&data);
+        crcs.push(crc.sum());
+        offsets.push(offset);
+        pack.extend(data);
+    }
+    let checksum = ObjectId::hash(&pack);
+    pack.extend(checksum.as_bytes());
+
+    let mut index: Vec<_> = entries
+        .iter()
+        .zip(offsets.into_iter().zip(crcs))
+        .map(|(entry, (offset, crc))| (entry.id, crc, offset))
+        .collect();
+    index.sort_unstable_by_key(|(id, _, _)| *id);
+    let index = encode_index(&index, checksum);
+
+    // The index is written last: a pack is only picked up by readers once its index exists
+    fs::create_dir_all(dir).context("Create pack directory")?;
+    let name = format!("pack-{}", checksum);
+    write_atomically(&dir.join(format!("{}.pack", name)), &pack)?;
+    write_atomically(&dir.join(format!("{}.idx", name)), &index)?;
+
+    Ok(checksum)
+}
+
+fn find_deltas(entries: &mut [PackEntry]) {
+    for i in 0..entries.len() {
+        let mut best: Option<(usize, Vec<u8>)> = None;
+        for j in i.saturating_sub(DELTA_WINDOW)..i {
+            let (base, target) = (&entries[j], &entries[i]);
+            if base.kind != target.kind || base.depth >= MAX_WRITE_DELTA_DEPTH {
+                continue;
+            }
+            // A delta is only worth it if it is much smaller than the object itself
+            let max_size = best
+                .as_ref()
+                .map_or(target.content.len() / 2, |(_, delta)| delta.len());
+            if let Some(delta) = create_delta(&base.content, &target.content, max_size) {
+                best = Some((j, delta));
+            }
+        }
+        if let Some((base, delta)) = best {
+            entries[i].depth = entries[base].depth + 1;
+            entries[i].delta = Some((base, delta));
+        }
+    }
+}
+
+// Computes a delta that turns `base` into `target`, giving up if it gets larger than `max_size`
+pub(crate) fn create_delta(base: &[u8], target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    if base.len() < DELTA_BLOCK_SIZE || target.len() < DELTA_BLOCK_SIZE {
+        return None;
+    }
+
+    // Index the blocks of the base, keeping the first occurrence of each block
+    let mut blocks = std::collections::HashMap::new();
+    for offset in (0..base.len() - DELTA_BLOCK_SIZE + 1)
+        .step_by(DELTA_BLOCK_SIZE)
+        .rev()
+    {
+        blocks.insert(&base[offset..offset + DELTA_BLOCK_SIZE], offset);
+    }
+
+    let mut delta = encode_size(base.len());
+    delta.extend(encode_size(target.len()));
+    let mut insert: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    while pos < target.len() {
+        let matched = target
+            .get(pos..pos + DELTA_BLOCK_SIZE)
+            .and_then(|block| blocks.get(block));
+        let Some(&base_offset) = matched else {
+            insert.push(target[pos]);
+            pos += 1;
+            continue;
+        };
+
+        // Extend the match backwards over the pending inserted bytes, then forwards
+        let mut start = base_offset;
+        while start > 0 && insert.last() == Some(&base[start - 1]) {
+            insert.pop();
+            start -= 1;
+        }
+        let mut len = base_offset - start + DELTA_BLOCK_SIZE;
+        pos += DELTA_BLOCK_SIZE;
+        while pos < target.len() && start + len < base.len() && base[start + len] == target[pos] {
+            len += 1;
+            pos += 1;
+        }
+
+        push_inserts(&mut delta, &insert);
+        insert.clear();
+        for chunk_start in (start..start + len).step_by(MAX_COPY_SIZE) {
+            let chunk_len = MAX_COPY_SIZE.min(start + len - chunk_start);
+            push_copy(&mut delta, chunk_start, chunk_len);
+        }
+        if delta.len() > max_size {
+            return None;
+        }
+    }
+    push_inserts(&mut delta, &insert);
+
+    (delta.len() <= max_size).then_some(delta)
+}
+
+fn push_inserts(delta: &mut Vec<u8>, bytes: &[u8]) {
+    for chunk in bytes.chunks(MAX_INSERT_SIZE) {
+        delta.push(chunk.len() as u8);
+        delta.extend(chunk);
+    }
+}
+
+// Only the non-zero bytes of the offset and size are written, flagged in the instruction
+fn push_copy(delta: &mut Vec<u8>, offset: usize, size: usize) {
+    let mut instruction = 0x80;
+    let mut args = Vec::new();
+    for i in 0..4 {
+        let byte = (offset >> (8 * i)) as u8;
+        if byte != 0 {
+            instruction |= 1 << i;
+            args.push(byte);
+        }
+    }
+    // A size of 0x10000 is encoded as no size at all
+    let size = if size == MAX_COPY_SIZE { 0 } else { size };
+    for i in 0..3 {
+        let byte = (size >> (8 * i)) as u8;
+        if byte != 0 {
+            instruction |= 1 << (4 + i);
+            args.push(byte);
+        }
+    }
+    delta.push(instruction);
+    delta.extend(args);
+}
+
+fn encode_size(mut size: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (size & 0x7f) as u8;
+        size >>= 7;
+        if size == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn encode_entry_header(entry_type: u8, size: usize) -> Vec<u8> {
+    let mut byte = (entry_type << 4) | (size & 0b1111) as u8;
+    let mut size = size >> 4;
+    let mut bytes = Vec::new();
+    while size != 0 {
+        bytes.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    bytes.push(byte);
+    bytes
+}
+
+pub(crate) fn encode_offset(mut offset: u64) -> Vec<u8> {
+    let mut bytes = vec![(offset & 0x7f) as u8];
+    offset >>= 7;
+    while offset != 0 {
+        offset -= 1;
+        bytes.push(0x80 | (offset & 0x7f) as u8);
+        offset >>= 7;
+    }
+    bytes.reverse();
+    bytes
+}
+
+// `entries` must be sorted by id
+fn encode_index(entries: &[(ObjectId, u32, u64)], pack_checksum: ObjectId) -> Vec<u8> {
+    let mut index = PackIndex::MAGIC.to_vec();
+    index.extend(PackIndex::VERSION.to_be_bytes());
+
+    let mut fanout = [0u32; 256];
+    for (id, _, _) in entries {
+        fanout[id.as_bytes()[0] as usize] += 1;
+    }
+    let mut total = 0;
+    for count in fanout {
+        total += count;
+        index.extend(total.to_be_bytes());
+    }
+
+    for (id, _, _) in entries {
+        index.extend(id.as_bytes());
+    }
+    for (_, crc, _) in entries {
+        index.extend(crc.to_be_bytes());
+    }
+    let mut large_offsets = Vec::new();
+    for &(_, _, offset) in entries {
+        if offset < 0x8000_0000 {
+            index.extend((offset as u32).to_be_bytes());
+        } else {
+            index.extend((0x8000_0000 | large_offsets.len() as u32).to_be_bytes());
+            large_offsets.push(offset);
+        }
+    }
+    for offset in large_offsets {
+        index.extend(offset.to_be_bytes());
+    }
+
+    index.extend(pack_checksum.as_bytes());
+    let checksum = ObjectId::hash(&index);
+    index.extend(checksum.as_bytes());
+    index
+}
+
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut e = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    io::Write::write_all(&mut e, data)?;
+    Ok(e.finish()?)
+}
+
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data).with_context(|| format!("write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("rename to {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trip() -> Result<()> {
+        let base: Vec<u8> = (0..100_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect();
+        let mut target = base.clone();
+        target.splice(1000..1010, b"inserted".iter().copied());
+        target.drain(50_000..50_100);
+        target.extend(b"and appended at the end");
+
+        let delta = create_delta(&base, &target, target.len()).ok_or(anyhow!("No delta"))?;
+        assert!(delta.len() < 200);
+        assert_eq!(apply_delta(&base, &delta)?, target);
+
+        // Unrelated data doesn't produce a worthwhile delta
+        let unrelated: Vec<u8> = (0..1000u32)
+            .flat_map(|i| (i * 7919).to_be_bytes())
+            .collect();
+        assert!(create_delta(&base, &unrelated, unrelated.len() / 2).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_sizes() -> Result<()> {
+        // Sizes from the headers are checked against the data instead of being allocated upfront
+        let huge = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        let delta = [&[0x00][..], &huge, &[0x01, b'a']].concat();
+        assert!(apply_delta(b"", &delta).is_err());
+        let compressed = compress(b"data")?;
+        assert!(decompress(&mut compressed.as_slice(), usize::MAX >> 1).is_err());
+        assert_eq!(decompress(&mut compressed.as_slice(), 4)?, b"data");
+
+        // Copies stop as soon as they go past the result size
+        let delta = [&[0x03, 0x01][..], &[0x90, 0x03].repeat(1 << 20)].concat();
+        assert!(apply_delta(b"abc", &delta).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn ref_delta_cycle_across_packs() -> Result<()> {
+        // Each pack has a single REF_DELTA whose base is the object of the other pack
+        let dir = tempfile::tempdir()?;
+        let (a, b) = (ObjectId::hash(b"a"), ObjectId::hash(b"b"));
+        for (id, base) in [(a, b), (b, a)] {
+            let delta = compress(&[0, 0])?;
+            let mut pack = b"PACK".to_vec();
+            pack.extend(2u32.to_be_bytes());
+            pack.extend(1u32.to_be_bytes());
+            pack.extend(encode_entry_header(OBJ_REF_DELTA, 2));
+            pack.extend(base.as_bytes());
+            pack.extend(delta);
+            let checksum = ObjectId::hash(&pack);
+            pack.extend(checksum.as_bytes());
+            let name = format!("pack-{}", checksum);
+            fs::write(dir.path().join(format!("{}.pack", name)), &pack)?;
+            let index = encode_index(&[(id, 0, 12)], checksum);
+            fs::write(dir.path().join(format!("{}.idx", name)), index)?;
+        }
+
+        let packs = PackedObjectDatabase::open(dir.path())?;
+        let error = packs.read_raw(&a).unwrap_err();
+        assert!(format!("{:#}", error).contains("Delta chain is too long"));
+
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn offset_encoding_round_trip() -> Result<()> {
+        for offset in [0, 1, 127, 128, 16511, 16512, 1 << 40] {
+            let encoded = encode_offset(offset);
+            assert_eq!(read_offset_encoding(&mut encoded.as_slice())?, offset);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::{anyhow, Result};
+
+use crate::wildmatch::wildmatch;
+
+// The paths given to commands like `add` or `rm`, relative to the root of the working tree. A
+// pathspec matches the path itself, everything below it if it is a directory, and glob patterns
+// match like `fnmatch` without special treatment for slashes.
+#[derive(Debug, Clone)]
+pub(crate) struct Pathspec {
+    patterns: Vec<String>,
+}
+
+impl Pathspec {
+    // `prefix` is the current directory relative to the root of the working tree
+    pub(crate) fn new(specs: &[String], prefix: &str) -> Result<Self> {
+        let patterns = specs
+            .iter()
+            .map(|spec| {
+                normalize(&format!("{}{}", prefix, spec))
+                    .ok_or(anyhow!("'{}' is outside repository", spec))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { patterns })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    // An empty pathspec matches everything
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        self.is_empty()
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| pattern_matches(pattern, path))
+    }
+
+    // The first pattern that matches none of `paths`, for "did not match any files" errors
+    pub(crate) fn unmatched<'a>(
+        &self,
+        paths: impl Iterator<Item = &'a str> + Clone,
+    ) -> Option<&str> {
+        self.patterns
+            .iter()
+            .find(|pattern| !paths.clone().any(|path| pattern_matches(pattern, path)))
+            .map(String::as_str)
+    }
+
+    pub(crate) fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+}
+
+pub(crate) fn pattern_matches(pattern: &str, path: &str) -> bool {
+    if pattern.is_empty() || path == pattern {
+        return true;
+    }
+    if path.starts_with(pattern) && path.as_bytes()[pattern.len()] == b'/' {
+        return true;
+    }
+    pattern.contains(['*', '?', '[']) && wildmatch(pattern, path, 0)
+}
+
+// Resolves `.` and `..` components, `None` if the path goes above the root. The root itself is
+// the empty string.
+fn normalize(path: &str) -> Option<String> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop()?;
+            }
+            component => components.push(component),
+        }
+    }
+    Some(components.join("/"))
+}
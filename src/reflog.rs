@@ -0,0 +1,47 @@
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::str;
+
+use crate::git_object::Signature;
+use crate::object_id::ObjectId;
+
+// A line of a reflog, i.e. `.git/logs/<ref>`, recording an update of the ref:
+// `<old id> <new id> Name <email> 1700000000 +0100\t<message>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    // The null id when the ref was created
+    pub old: ObjectId,
+    pub new: ObjectId,
+    pub committer: Signature,
+    pub message: String,
+}
+
+impl str::FromStr for ReflogEntry {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let (ids_and_signature, message) = line.split_once('\t').unwrap_or((line, ""));
+        let mut fields = ids_and_signature.splitn(3, ' ');
+        let (Some(old), Some(new), Some(committer)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(anyhow!("Invalid reflog entry: {}", line));
+        };
+
+        Ok(Self {
+            old: old.parse().context("parse old id")?,
+            new: new.parse().context("parse new id")?,
+            committer: Signature::from_bytes(committer.as_bytes())?,
+            message: message.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ReflogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}\t{}",
+            self.old, self.new, self.committer, self.message
+        )
+    }
+}
@@ -0,0 +1,483 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::git_object::Signature;
+use crate::lockfile::LockFile;
+use crate::object_id::ObjectId;
+use crate::reflog::ReflogEntry;
+
+// Where git looks for a ref given a short name, in order
+const REF_RULES: [&str; 6] = [
+    "{}",
+    "refs/{}",
+    "refs/tags/{}",
+    "refs/heads/{}",
+    "refs/remotes/{}",
+    "refs/remotes/{}/HEAD",
+];
+
+// Symbolic refs pointing to symbolic refs are allowed, but not indefinitely
+const MAX_SYMREF_DEPTH: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ref {
+    // Points directly to an object
+    Direct(ObjectId),
+    // Points to another ref, like HEAD usually does: `ref: refs/heads/master`
+    Symbolic(String),
+}
+
+// A ref from the `packed-refs` file, along with the object it peels to for annotated tags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackedRef {
+    pub id: ObjectId,
+    pub peeled: Option<ObjectId>,
+}
+
+// The refs of a repository: loose refs are files under the git directory (`HEAD`,
+// `refs/heads/master`...), and refs can also be packed together in the `packed-refs` file. Loose
+// refs take precedence over packed ones.
+pub struct Refs {
+    git_dir: PathBuf,
+}
+
+impl Refs {
+    pub fn new(git_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            git_dir: git_dir.into(),
+        }
+    }
+
+    pub fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    pub fn read(&self, name: &str) -> Result<Option<Ref>> {
+        match self.read_loose(name)? {
+            Some(reference) => Ok(Some(reference)),
+            None => Ok(self
+                .read_packed()?
+                .get(name)
+                .map(|packed| Ref::Direct(packed.id))),
+        }
+    }
+
+    fn read_loose(&self, name: &str) -> Result<Option<Ref>> {
+        let path = self.git_dir.join(name);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            // A directory is not a ref, e.g. `refs/heads` when looking for `refs/heads/a/b`
+            Err(e) if e.kind() == io::ErrorKind::NotFound || path.is_dir() => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("read ref {}", name)),
+        };
+
+        let contents = contents.trim_end();
+        match contents.strip_prefix("ref:") {
+            Some(target) => Ok(Some(Ref::Symbolic(target.trim().to_string()))),
+            None => Ok(Some(Ref::Direct(
+                contents
+                    .parse()
+                    .with_context(|| format!("parse ref {}", name))?,
+            ))),
+        }
+    }
+
+    pub fn read_packed(&self) -> Result<BTreeMap<String, PackedRef>> {
+        let contents = match fs::read_to_string(self.git_dir.join("packed-refs")) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e).context("read packed-refs"),
+        };
+
+        // After an optional `# pack-refs with: <traits>` header, each line is `<id> <name>`,
+        // possibly followed by a `^<id>` line with the peeled object for annotated tags
+        let mut refs: BTreeMap<String, PackedRef> = BTreeMap::new();
+        let mut last: Option<String> = None;
+        for line in contents.lines() {
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            if let Some(peeled) = line.strip_prefix('^') {
+                let name = last.as_ref().ok_or(anyhow!("Peeled line without a ref"))?;
+                if let Some(packed) = refs.get_mut(name) {
+                    packed.peeled = Some(peeled.parse().context("parse peeled ref")?);
+                }
+                continue;
+            }
+            let (id, name) = line
+                .split_once(' ')
+                .ok_or(anyhow!("Invalid packed-refs line: {}", line))?;
+            let packed = PackedRef {
+                id: id.parse().context("parse packed ref")?,
+                peeled: None,
+            };
+            refs.insert(name.to_string(), packed);
+            last = Some(name.to_string());
+        }
+        Ok(refs)
+    }
+
+    // The full name of the ref a short name refers to
+    pub fn dwim(&self, name: &str) -> Result<Option<String>> {
+        for rule in REF_RULES {
+            let full_name = rule.replace("{}", name);
+            // Only refs under `refs/` and the likes of HEAD or FETCH_HEAD are looked up as is, not
+            // `config` or other files of the git directory
+            let is_pseudo_ref = name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_');
+            if rule == "{}" && !is_pseudo_ref && !name.starts_with("refs/") {
+                continue;
+            }
+            if check_ref_format(&full_name).is_err() {
+                continue;
+            }
+            if self.resolve(&full_name)?.is_some() {
+                return Ok(Some(full_name));
+            }
+        }
+        Ok(None)
+    }
+
+    // Follows symbolic refs until the ref that holds an object id (or doesn't exist yet, like the
+    // branch of a freshly initialized repository), and returns its name
+    pub fn resolve_name(&self, name: &str) -> Result<String> {
+        let mut name = name.to_string();
+        for _ in 0..=MAX_SYMREF_DEPTH {
+            match self.read(&name)? {
+                Some(Ref::Symbolic(target)) => name = target,
+                _ => return Ok(name),
+            }
+        }
+        Err(anyhow!("Too many levels of symbolic refs for {}", name))
+    }
+
+    // The object a ref ultimately points to, if it exists
+    pub fn resolve(&self, name: &str) -> Result<Option<ObjectId>> {
+        match self.read(&self.resolve_name(name)?)? {
+            Some(Ref::Direct(id)) => Ok(Some(id)),
+            _ => Ok(None),
+        }
+    }
+
+    // All the refs whose name starts with `prefix`, e.g. `refs/heads/`, resolved to the objects
+    // they point to and sorted by name. HEAD is not included.
+    pub fn list(&self, prefix: &str) -> Result<Vec<(String, ObjectId)>> {
+        let mut refs: BTreeMap<String, ObjectId> = self
+            .read_packed()?
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, packed)| (name, packed.id))
+            .collect();
+
+        let mut dirs = vec![self.git_dir.join("refs")];
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).context("list refs"),
+            };
+            for entry in entries {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let Some(name) = path
+                    .strip_prefix(&self.git_dir)?
+                    .to_str()
+                    .map(str::to_string)
+                else {
+                    continue;
+                };
+                // Skip leftover lock files
+                if !name.starts_with(prefix) || check_ref_format(&name).is_err() {
+                    continue;
+                }
+                // Symbolic refs pointing to refs that don't exist are skipped
+                if let Some(id) = self.resolve(&name)? {
+                    refs.insert(name, id);
+                }
+            }
+        }
+
+        Ok(refs.into_iter().collect())
+    }
+
+    // Points `name` (or the ref it points to if it is symbolic) to `new`. If `expected` is given,
+    // the update only happens if the ref currently points to it, the null id meaning that the ref
+    // must not exist. The update is recorded in the reflogs of the ref, and of HEAD if it points
+    // to it.
+    pub fn update(
+        &self,
+        name: &str,
+        new: &ObjectId,
+        expected: Option<&ObjectId>,
+        committer: &Signature,
+        message: &str,
+    ) -> Result<()> {
+        check_ref_format(name)?;
+        let resolved = self.resolve_name(name)?;
+        check_ref_format(&resolved)?;
+
+        let mut lock = self.lock(&resolved)?;
+        self.check_expected(&resolved, expected)?;
+        let old = self.resolve(&resolved)?.unwrap_or(ObjectId::null());
+        writeln!(lock, "{}", new)?;
+        lock.commit()?;
+
+        let entry = ReflogEntry {
+            old,
+            new: *new,
+            committer: committer.clone(),
+            // Reflog entries are single lines
+            message: message.split_whitespace().collect::<Vec<_>>().join(" "),
+        };
+        self.append_reflog(&resolved, &entry)?;
+        if resolved != "HEAD" && (name == "HEAD" || self.resolve_name("HEAD")? == resolved) {
+            self.append_reflog("HEAD", &entry)?;
+        }
+        Ok(())
+    }
+
+    // Deletes a ref, both its loose and packed versions
+    pub fn delete(&self, name: &str, expected: Option<&ObjectId>) -> Result<()> {
+        let name = self.resolve_name(name)?;
+        check_ref_format(&name)?;
+
+        let lock = self.lock(&name)?;
+        self.check_expected(&name, expected)?;
+        if self.read(&name)?.is_none() {
+            return Err(anyhow!("Ref {} does not exist", name));
+        }
+
+        if self.read_packed()?.contains_key(&name) {
+            let packed_refs = self.git_dir.join("packed-refs");
+            let mut packed_lock = LockFile::acquire(&packed_refs)?;
+            let contents = fs::read_to_string(&packed_refs).context("read packed-refs")?;
+            let mut skip_peeled = false;
+            for line in contents.lines() {
+                if line.starts_with('^') && skip_peeled {
+                    continue;
+                }
+                skip_peeled = line
+                    .split_once(' ')
+                    .is_some_and(|(_, ref_name)| ref_name == name);
+                if !skip_peeled {
+                    writeln!(packed_lock, "{}", line)?;
+                }
+            }
+            packed_lock.commit()?;
+        }
+
+        remove_if_exists(&self.git_dir.join(&name))
+            .with_context(|| format!("delete ref {}", name))?;
+        drop(lock);
+        remove_empty_parents(&self.git_dir, &name);
+
+        // The history of a deleted ref goes with it
+        let logs = self.git_dir.join("logs");
+        remove_if_exists(&logs.join(&name))
+            .with_context(|| format!("delete reflog of {}", name))?;
+        remove_empty_parents(&logs, &name);
+
+        Ok(())
+    }
+
+    // Makes `name` a symbolic ref pointing to `target`
+    pub fn set_symbolic(&self, name: &str, target: &str) -> Result<()> {
+        check_ref_format(name)?;
+        if !target.starts_with("refs/") {
+            return Err(anyhow!(
+                "Refusing to point {} outside of refs/: {}",
+                name,
+                target
+            ));
+        }
+        check_ref_format(target)?;
+
+        let mut lock = self.lock(name)?;
+        writeln!(lock, "ref: {}", target)?;
+        lock.commit()
+    }
+
+    // The updates of a ref, oldest first, or nothing if it has no reflog
+    pub fn read_reflog(&self, name: &str) -> Result<Vec<ReflogEntry>> {
+        let contents = match fs::read_to_string(self.git_dir.join("logs").join(name)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("read reflog of {}", name)),
+        };
+        contents
+            .lines()
+            .map(|line| {
+                line.parse()
+                    .with_context(|| format!("parse reflog of {}", name))
+            })
+            .collect()
+    }
+
+    // Updates are logged for the refs that already have a reflog, and for the ones that
+    // `core.logAllRefUpdates` says should get one
+    fn append_reflog(&self, name: &str, entry: &ReflogEntry) -> Result<()> {
+        let path = self.git_dir.join("logs").join(name);
+        if !path.exists() && !self.creates_reflog(name)? {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("create reflog directory")?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("open reflog of {}", name))?;
+        writeln!(file, "{}", entry).with_context(|| format!("write reflog of {}", name))
+    }
+
+    // Like git, `always` creates a reflog for every ref, `true` for HEAD and branches only, and
+    // `false` for none. When unset, only bare repositories don't get new reflogs.
+    fn creates_reflog(&self, name: &str) -> Result<bool> {
+        let config = Config::load(Some(&self.git_dir), |name| env::var_os(name))?;
+        let setting = config.get("core.logAllRefUpdates");
+        if setting.is_some_and(|setting| setting.eq_ignore_ascii_case("always")) {
+            return Ok(true);
+        }
+        let enabled = match setting {
+            Some(_) => config.get_bool("core.logAllRefUpdates")?.unwrap_or(false),
+            None => !config.get_bool("core.bare")?.unwrap_or(false),
+        };
+        Ok(enabled
+            && (name == "HEAD"
+                || ["refs/heads/", "refs/remotes/", "refs/notes/"]
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))))
+    }
+
+    // The names of the refs that have a reflog, sorted
+    pub fn reflogs(&self) -> Result<Vec<String>> {
+        let logs = self.git_dir.join("logs");
+        let mut names = Vec::new();
+        let mut dirs = vec![logs.clone()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).context("list reflogs"),
+            };
+            for entry in entries {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if let Some(name) = path.strip_prefix(&logs)?.to_str() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    // Drops the entries of a reflog for which `keep` returns false, and returns how many there were
+    pub fn expire_reflog(&self, name: &str, keep: impl Fn(&ReflogEntry) -> bool) -> Result<usize> {
+        let entries = self.read_reflog(name)?;
+        let kept: Vec<_> = entries.iter().filter(|entry| keep(entry)).collect();
+        let expired = entries.len() - kept.len();
+        if expired == 0 {
+            return Ok(0);
+        }
+
+        // Lock the ref so that it isn't updated (and logged) in the meantime
+        let _ref_lock = self.lock(name)?;
+        let mut lock = LockFile::acquire(&self.git_dir.join("logs").join(name))?;
+        for entry in kept {
+            writeln!(lock, "{}", entry)?;
+        }
+        lock.commit()?;
+        Ok(expired)
+    }
+
+    fn lock(&self, name: &str) -> Result<LockFile> {
+        LockFile::acquire(&self.git_dir.join(name))
+            .with_context(|| format!("cannot lock ref '{}'", name))
+    }
+
+    fn check_expected(&self, name: &str, expected: Option<&ObjectId>) -> Result<()> {
+        let Some(expected) = expected else {
+            return Ok(());
+        };
+        let current = self.resolve(name)?;
+        match current {
+            Some(current) if current != *expected => Err(anyhow!(
+                "cannot lock ref '{}': is at {} but expected {}",
+                name,
+                current,
+                expected
+            )),
+            Some(_) if expected.is_null() => Err(anyhow!(
+                "cannot lock ref '{}': reference already exists",
+                name
+            )),
+            None if !expected.is_null() => Err(anyhow!(
+                "cannot lock ref '{}': unable to resolve reference",
+                name
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+// Removes the directories that only contained the ref `name` under `base`, e.g.
+// `refs/heads/feature/` once `refs/heads/feature/a` is deleted
+fn remove_empty_parents(base: &Path, name: &str) {
+    let refs_dir = base.join("refs");
+    let path = base.join(name);
+    let mut dir = path.parent();
+    while let Some(parent) = dir.filter(|dir| dir.starts_with(&refs_dir) && *dir != refs_dir) {
+        if fs::remove_dir(parent).is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+}
+
+// Validates a ref name like `git check-ref-format`
+pub fn check_ref_format(name: &str) -> Result<()> {
+    let invalid = |reason: &str| Err(anyhow!("'{}' is not a valid ref name: {}", name, reason));
+
+    if name.is_empty() || name == "@" {
+        return invalid("empty or @");
+    }
+    if name.starts_with('/') || name.ends_with('/') || name.contains("//") {
+        return invalid("empty component");
+    }
+    if name.ends_with('.') {
+        return invalid("ends with a dot");
+    }
+    if name.contains("..") || name.contains("@{") {
+        return invalid("contains .. or @{");
+    }
+    if name
+        .chars()
+        .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+    {
+        return invalid("contains a forbidden character");
+    }
+    for component in name.split('/') {
+        if component.starts_with('.') || component.ends_with(".lock") {
+            return invalid("component starts with a dot or ends with .lock");
+        }
+    }
+    Ok(())
+}
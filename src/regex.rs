@@ -0,0 +1,426 @@
+use anyhow::{anyhow, Result};
+
+// A small implementation of POSIX extended regular expressions, enough for the places git takes
+// one: `:/<regex>` revisions for instance. It supports literals, `.`, bracket expressions with
+// ranges, the `*`, `+`, `?` and `{m,n}` quantifiers, groups, alternation and the `^` and `$`
+// anchors. The regex is compiled to a Thompson NFA whose states are all followed at once, one
+// character at a time, so matching takes linear time and doesn't recurse over the text.
+#[derive(Debug, Clone)]
+pub(crate) struct Regex {
+    program: Vec<Instruction>,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    Any,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+    Start,
+    End,
+    Group(Vec<Vec<Node>>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Instruction {
+    // Consume a character
+    Char(char),
+    Any,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+    // Assertions on the position
+    Start,
+    End,
+    // Continue at both, or at the given instruction
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+// POSIX's RE_DUP_MAX, which also keeps the compiled program small
+const MAX_REPEAT: usize = 255;
+
+impl Regex {
+    pub(crate) fn new(pattern: &str) -> Result<Self> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut parser = Parser { chars, pos: 0 };
+        let alternatives = parser.alternatives()?;
+        if parser.pos < parser.chars.len() {
+            return Err(anyhow!("Unmatched ) in regex {}", pattern));
+        }
+
+        let mut program = Vec::new();
+        compile_alternatives(&alternatives, &mut program);
+        program.push(Instruction::Match);
+        Ok(Self { program })
+    }
+
+    // Whether the regex matches anywhere in `text`
+    pub(crate) fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        let mut current = States::new(self.program.len());
+        let mut next = States::new(self.program.len());
+        for pos in 0..=text.len() {
+            // A match can start at any position
+            if self.add_state(&mut current, 0, pos, &text) {
+                return true;
+            }
+            let Some(&c) = text.get(pos) else {
+                break;
+            };
+
+            next.clear();
+            for &pc in &current.list {
+                let consumed = match &self.program[pc] {
+                    Instruction::Char(expected) => *expected == c,
+                    Instruction::Any => true,
+                    Instruction::Class { negated, ranges } => {
+                        ranges.iter().any(|(low, high)| (low..=high).contains(&&c)) != *negated
+                    }
+                    _ => false,
+                };
+                if consumed && self.add_state(&mut next, pc + 1, pos + 1, &text) {
+                    return true;
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+        }
+        false
+    }
+
+    // Adds the state at `pc` and the ones reachable from it without consuming anything, returning
+    // whether that reaches the end of the regex
+    fn add_state(&self, states: &mut States, pc: usize, pos: usize, text: &[char]) -> bool {
+        let mut stack = vec![pc];
+        while let Some(pc) = stack.pop() {
+            if std::mem::replace(&mut states.seen[pc], true) {
+                continue;
+            }
+            match self.program[pc] {
+                Instruction::Start if pos == 0 => stack.push(pc + 1),
+                Instruction::End if pos == text.len() => stack.push(pc + 1),
+                Instruction::Start | Instruction::End => {}
+                Instruction::Split(first, second) => stack.extend([second, first]),
+                Instruction::Jump(target) => stack.push(target),
+                Instruction::Match => return true,
+                _ => states.list.push(pc),
+            }
+        }
+        false
+    }
+}
+
+// The states of the NFA the text can be in at some position, by instruction
+struct States {
+    seen: Vec<bool>,
+    list: Vec<usize>,
+}
+
+impl States {
+    fn new(len: usize) -> Self {
+        Self {
+            seen: vec![false; len],
+            list: Vec::new(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.seen.iter_mut().for_each(|seen| *seen = false);
+        self.list.clear();
+    }
+}
+
+fn compile_alternatives(alternatives: &[Vec<Node>], program: &mut Vec<Instruction>) {
+    let mut jumps = Vec::new();
+    for (i, nodes) in alternatives.iter().enumerate() {
+        let last = i + 1 == alternatives.len();
+        let split = program.len();
+        if !last {
+            program.push(Instruction::Split(split + 1, 0));
+        }
+        for node in nodes {
+            compile_node(node, program);
+        }
+        if !last {
+            jumps.push(program.len());
+            program.push(Instruction::Jump(0));
+            program[split] = Instruction::Split(split + 1, program.len());
+        }
+    }
+    let end = program.len();
+    for jump in jumps {
+        program[jump] = Instruction::Jump(end);
+    }
+}
+
+fn compile_node(node: &Node, program: &mut Vec<Instruction>) {
+    match node {
+        Node::Char(c) => program.push(Instruction::Char(*c)),
+        Node::Any => program.push(Instruction::Any),
+        Node::Class { negated, ranges } => program.push(Instruction::Class {
+            negated: *negated,
+            ranges: ranges.clone(),
+        }),
+        Node::Start => program.push(Instruction::Start),
+        Node::End => program.push(Instruction::End),
+        Node::Group(alternatives) => compile_alternatives(alternatives, program),
+        Node::Repeat { node, min, max } => {
+            for _ in 0..*min {
+                compile_node(node, program);
+            }
+            match max {
+                // A loop: either another repetition or the rest of the regex
+                None => {
+                    let split = program.len();
+                    program.push(Instruction::Split(split + 1, 0));
+                    compile_node(node, program);
+                    program.push(Instruction::Jump(split));
+                    program[split] = Instruction::Split(split + 1, program.len());
+                }
+                // Each optional repetition can skip to the end
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Instruction::Split(program.len() + 1, 0));
+                        compile_node(node, program);
+                    }
+                    let end = program.len();
+                    for split in splits {
+                        program[split] = Instruction::Split(split + 1, end);
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    // `a|b|c`, up to the end of the pattern or of the enclosing group
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        match self.next() {
+            Some('.') => Ok(Node::Any),
+            Some('^') => Ok(Node::Start),
+            Some('$') => Ok(Node::End),
+            Some('(') => {
+                let alternatives = self.alternatives()?;
+                if self.next() != Some(')') {
+                    return Err(anyhow!("Unmatched ( in regex"));
+                }
+                Ok(Node::Group(alternatives))
+            }
+            Some('[') => self.bracket(),
+            Some('\\') => self
+                .next()
+                .map(Node::Char)
+                .ok_or(anyhow!("Trailing backslash in regex")),
+            Some(c @ ('*' | '+' | '?')) => Err(anyhow!("Nothing to repeat before {}", c)),
+            Some(c) => Ok(Node::Char(c)),
+            None => Err(anyhow!("Unexpected end of regex")),
+        }
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node> {
+        let mut node = atom;
+        loop {
+            let quantifier = match self.peek() {
+                Some(c @ ('*' | '+' | '?' | '{')) => c,
+                _ => return Ok(node),
+            };
+            self.pos += 1;
+            let (min, max) = match quantifier {
+                '*' => (0, None),
+                '+' => (1, None),
+                '?' => (0, Some(1)),
+                _ => self.interval()?,
+            };
+            if min > MAX_REPEAT || max.is_some_and(|max| max > MAX_REPEAT || max < min) {
+                return Err(anyhow!("Invalid interval in regex"));
+            }
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+            };
+        }
+    }
+
+    // `{m}`, `{m,}` or `{m,n}`, the opening brace being already consumed
+    fn interval(&mut self) -> Result<(usize, Option<usize>)> {
+        let end = self.chars[self.pos..]
+            .iter()
+            .position(|&c| c == '}')
+            .ok_or(anyhow!("Unmatched {{ in regex"))?;
+        let interval: String = self.chars[self.pos..self.pos + end].iter().collect();
+        self.pos += end + 1;
+
+        let parse = |s: &str| {
+            s.parse::<usize>()
+                .map_err(|_| anyhow!("Invalid interval {{{}}} in regex", interval))
+        };
+        match interval.split_once(',') {
+            None => {
+                let count = parse(&interval)?;
+                Ok((count, Some(count)))
+            }
+            Some((min, "")) => Ok((parse(min)?, None)),
+            Some((min, max)) => Ok((parse(min)?, Some(parse(max)?))),
+        }
+    }
+
+    // `[abc]`, `[^a-z]`, `[]a]`..., the opening bracket being already consumed
+    fn bracket(&mut self) -> Result<Node> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.next().ok_or(anyhow!("Unmatched [ in regex"))?;
+            // A `]` right after the opening bracket is a literal
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+
+            if c == '[' && self.peek() == Some(':') {
+                ranges.extend(self.character_class()?);
+                continue;
+            }
+            let is_range = self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']');
+            if is_range {
+                self.pos += 1;
+                let high = self.next().ok_or(anyhow!("Unmatched [ in regex"))?;
+                if high < c {
+                    return Err(anyhow!("Invalid range {}-{} in regex", c, high));
+                }
+                ranges.push((c, high));
+            } else {
+                ranges.push((c, c));
+            }
+        }
+
+        Ok(Node::Class { negated, ranges })
+    }
+
+    // `[:alpha:]` and friends inside a bracket expression
+    fn character_class(&mut self) -> Result<Vec<(char, char)>> {
+        let end = self.chars[self.pos..]
+            .windows(2)
+            .position(|w| w == [':', ']'])
+            .ok_or(anyhow!("Unmatched [: in regex"))?;
+        let name: String = self.chars[self.pos + 1..self.pos + end].iter().collect();
+        self.pos += end + 2;
+
+        let ranges = match name.as_str() {
+            "alpha" => vec![('a', 'z'), ('A', 'Z')],
+            "digit" => vec![('0', '9')],
+            "alnum" => vec![('a', 'z'), ('A', 'Z'), ('0', '9')],
+            "upper" => vec![('A', 'Z')],
+            "lower" => vec![('a', 'z')],
+            "space" => vec![(' ', ' '), ('\t', '\r')],
+            "xdigit" => vec![('0', '9'), ('a', 'f'), ('A', 'F')],
+            "punct" => vec![('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+            _ => return Err(anyhow!("Unknown character class [:{}:] in regex", name)),
+        };
+        Ok(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches() -> Result<()> {
+        let cases = [
+            ("fix", "Fix the fix", true),
+            ("^fix", "Fix the fix", false),
+            ("^Fix.*fix$", "Fix the fix", true),
+            ("colou?r", "color", true),
+            ("colou?r", "colouur", false),
+            ("a{2,3}b", "aab", true),
+            ("^a{2,3}b", "ab", false),
+            ("(foo|bar)+baz", "xbarfoobaz", true),
+            ("[[:digit:]]+\\.[0-9]", "version 1.2", true),
+            ("[^a-z ]", "only lowercase", false),
+            ("[]x]", "]", true),
+            ("core\\..*", "core.bare", true),
+            ("core\\..*", "corexbare", false),
+            ("^(a|b*)*c$", "ababbc", true),
+            ("(a*)*$", "", true),
+            ("a{0}b", "b", true),
+            ("^(ab){2}$", "abab", true),
+            ("^(ab){2}$", "ababab", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(Regex::new(pattern)?.is_match(text), expected, "{}", pattern);
+        }
+
+        for invalid in [
+            "(a", "a)", "*a", "[a", "a{1,x}", "[z-a]", "a{3,2}", "a{256}",
+        ] {
+            assert!(Regex::new(invalid).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn long_text() -> Result<()> {
+        // Neither the stack nor the time grows with the number of ways to match
+        let text = "x".repeat(100_000);
+        assert!(!Regex::new("x.*y")?.is_match(&text));
+        assert!(!Regex::new("(a|x)+z")?.is_match(&text));
+        assert!(!Regex::new("(x*)*y")?.is_match(&text));
+        assert!(Regex::new("^(x|xx)+$")?.is_match(&text));
+        Ok(())
+    }
+}
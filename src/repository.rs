@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, ConfigFile};
+use crate::odb::{LayeredObjectDatabase, LooseObjectDatabase};
+use crate::pack::PackedObjectDatabase;
+use crate::refs::Refs;
+
+// Where a repository lives: its git directory (`.git`, or the repository itself when it is bare)
+// and its working tree, if any
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repository {
+    git_dir: PathBuf,
+    work_tree: Option<PathBuf>,
+}
+
+impl Repository {
+    pub fn new(git_dir: impl Into<PathBuf>, work_tree: Option<PathBuf>) -> Self {
+        Self {
+            git_dir: git_dir.into(),
+            work_tree,
+        }
+    }
+
+    // The repository at `path`, either a working tree with a `.git` directory or file, or a bare
+    // repository
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::find_at(path)?.ok_or(anyhow!("not a git repository: {}", path.display()))
+    }
+
+    // Finds the repository `cwd` belongs to like git does: `git_dir` (from `--git-dir`) or
+    // `$GIT_DIR` if set, otherwise the first of `cwd` and its parents that is a repository. The
+    // working tree can be set with `$GIT_WORK_TREE`.
+    pub fn discover(cwd: &Path, git_dir: Option<&Path>) -> Result<Self> {
+        Self::discover_with_env(cwd, git_dir, |name| env::var_os(name))
+    }
+
+    pub(crate) fn discover_with_env(
+        cwd: &Path,
+        git_dir: Option<&Path>,
+        env: impl Fn(&str) -> Option<OsString>,
+    ) -> Result<Self> {
+        let cwd = cwd.canonicalize().context("resolve current directory")?;
+        let work_tree = env("GIT_WORK_TREE").map(|work_tree| cwd.join(work_tree));
+
+        // An explicit git directory makes the current directory the working tree by default,
+        // unless the repository's config says otherwise
+        let git_dir = git_dir
+            .map(Path::to_path_buf)
+            .or(env("GIT_DIR").map(PathBuf::from));
+        if let Some(git_dir) = git_dir {
+            let git_dir = cwd.join(git_dir);
+            if !is_git_dir(&git_dir) {
+                return Err(anyhow!("not a git repository: '{}'", git_dir.display()));
+            }
+            let work_tree = match work_tree {
+                Some(work_tree) => Some(work_tree),
+                None => {
+                    let config = ConfigFile::open(&git_dir.join("config"))?.config()?;
+                    match config.get("core.worktree") {
+                        Some(work_tree) => Some(git_dir.join(work_tree)),
+                        None if config.get_bool("core.bare")? == Some(true) => None,
+                        None => Some(cwd),
+                    }
+                }
+            };
+            return Ok(Self::new(git_dir, work_tree));
+        }
+
+        for dir in cwd.ancestors() {
+            if let Some(mut repository) = Self::find_at(dir)? {
+                if work_tree.is_some() {
+                    repository.work_tree = work_tree;
+                }
+                return Ok(repository);
+            }
+        }
+        Err(anyhow!(
+            "not a git repository (or any of the parent directories): .git"
+        ))
+    }
+
+    fn find_at(dir: &Path) -> Result<Option<Self>> {
+        let dot_git = dir.join(".git");
+        if dot_git.is_file() {
+            // Used by submodules and worktrees: `gitdir: <path>`, relative to the file
+            let contents = fs::read_to_string(&dot_git).context("read .git file")?;
+            let git_dir = contents
+                .trim_end()
+                .strip_prefix("gitdir: ")
+                .ok_or(anyhow!("invalid gitfile format: {}", dot_git.display()))?;
+            let git_dir = dir.join(git_dir);
+            if !is_git_dir(&git_dir) {
+                return Err(anyhow!("not a git repository: {}", git_dir.display()));
+            }
+            return Ok(Some(Self::new(git_dir, Some(dir.to_path_buf()))));
+        }
+        if is_git_dir(&dot_git) {
+            return Ok(Some(Self::new(dot_git, Some(dir.to_path_buf()))));
+        }
+        if is_git_dir(dir) {
+            return Ok(Some(Self::new(dir, None)));
+        }
+        Ok(None)
+    }
+
+    pub fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    pub fn work_tree(&self) -> Option<&Path> {
+        self.work_tree.as_deref()
+    }
+
+    pub fn is_bare(&self) -> bool {
+        self.work_tree.is_none()
+    }
+
+    // For the commands that need files to work on
+    pub fn require_work_tree(&self) -> Result<&Path> {
+        self.work_tree()
+            .ok_or(anyhow!("this operation must be run in a work tree"))
+    }
+
+    // The current directory relative to the root of the working tree, with a trailing slash
+    // unless it is the root, which pathspecs are relative to
+    pub fn prefix(&self) -> Result<String> {
+        let root = self.require_work_tree()?.canonicalize()?;
+        let cwd = env::current_dir()?.canonicalize()?;
+        let prefix = cwd
+            .strip_prefix(&root)
+            .map_err(|_| anyhow!("{} is outside the working tree", cwd.display()))?;
+        Ok(match prefix.to_str() {
+            Some("") => String::new(),
+            Some(prefix) => format!("{}/", prefix),
+            None => return Err(anyhow!("{} is not valid UTF-8", prefix.display())),
+        })
+    }
+
+    // Loose objects first since new objects are written there, then packs
+    pub fn objects(&self) -> Result<LayeredObjectDatabase> {
+        let objects = self.git_dir.join("objects");
+        let packs = PackedObjectDatabase::open(objects.join("pack")).context("open packs")?;
+        Ok(LayeredObjectDatabase::new(vec![
+            Box::new(LooseObjectDatabase::new(&objects)),
+            Box::new(packs),
+        ]))
+    }
+
+    pub fn refs(&self) -> Refs {
+        Refs::new(&self.git_dir)
+    }
+
+    // The staging area
+    pub fn index_path(&self) -> PathBuf {
+        self.git_dir.join("index")
+    }
+
+    // The system and global settings along with the repository's own
+    pub fn config(&self) -> Result<Config> {
+        Config::load(Some(&self.git_dir), |name| env::var_os(name))
+    }
+}
+
+// Like git, a directory is a git directory if it has objects, refs and a HEAD
+fn is_git_dir(path: &Path) -> bool {
+    path.join("objects").is_dir() && path.join("refs").is_dir() && path.join("HEAD").is_file()
+}
@@ -0,0 +1,288 @@
+use anyhow::{anyhow, Context, Result};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+
+use crate::git_object::{Object, ObjectKind};
+use crate::object_id::ObjectId;
+use crate::odb::{resolve_object_id, ObjectDatabase};
+use crate::refs::Refs;
+use crate::regex::Regex;
+
+// Resolves a revision like git does (see `git help revisions`):
+// - `<sha1>`, abbreviated or not, and `<refname>` (`master`, `heads/master`, `v1.0`, `HEAD`...)
+// - `@` for HEAD and `<refname>@{<n>}` for the nth prior value of a ref according to its reflog
+// - `<rev>~<n>` and `<rev>^<n>` for ancestors and parents
+// - `<rev>^{<type>}`, `<rev>^{}` and `<rev>^{/<regex>}` to peel objects or search commits
+// - `<rev>:<path>` for an object in a tree, and `:/<regex>` to search commits from every ref
+pub fn resolve_revision(odb: &dyn ObjectDatabase, refs: &Refs, revision: &str) -> Result<ObjectId> {
+    let resolver = Resolver { odb, refs };
+    resolver
+        .resolve(revision)
+        .with_context(|| format!("Not a valid object name {}", revision))
+}
+
+struct Resolver<'a> {
+    odb: &'a dyn ObjectDatabase,
+    refs: &'a Refs,
+}
+
+impl Resolver<'_> {
+    fn resolve(&self, revision: &str) -> Result<ObjectId> {
+        if let Some(pattern) = revision.strip_prefix(":/") {
+            let starts = self.refs.list("refs/")?.into_iter().map(|(_, id)| id);
+            let starts = starts.chain(self.refs.resolve("HEAD")?);
+            return self.search_commits(starts, pattern);
+        }
+        if revision.starts_with(':') {
+            return Err(anyhow!("Looking up paths in the index is not supported"));
+        }
+
+        match split_tree_path(revision) {
+            Some((tree_ish, path)) => {
+                let tree = self.peel(self.resolve_suffixes(tree_ish)?, Some(ObjectKind::Tree))?;
+                self.lookup_path(tree, path)
+                    .with_context(|| format!("path '{}' does not exist in '{}'", path, tree_ish))
+            }
+            None => self.resolve_suffixes(revision),
+        }
+    }
+
+    // `<base>` followed by any number of `~<n>`, `^<n>` and `^{...}`
+    fn resolve_suffixes(&self, revision: &str) -> Result<ObjectId> {
+        let base_len = revision.find(['~', '^']).unwrap_or(revision.len());
+        let (base, mut suffixes) = revision.split_at(base_len);
+        let mut id = self.resolve_base(base)?;
+
+        while !suffixes.is_empty() {
+            let operator = suffixes.as_bytes()[0];
+            suffixes = &suffixes[1..];
+
+            if operator == b'^' && suffixes.starts_with('{') {
+                let end = suffixes
+                    .find('}')
+                    .ok_or(anyhow!("Missing }} in {}", revision))?;
+                id = self.peel_with(id, &suffixes[1..end])?;
+                suffixes = &suffixes[end + 1..];
+                continue;
+            }
+
+            let digits = suffixes
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(suffixes.len());
+            let n = match &suffixes[..digits] {
+                "" => 1,
+                n => n.parse().context("parse revision suffix")?,
+            };
+            suffixes = &suffixes[digits..];
+
+            id = match operator {
+                b'~' => (0..n).try_fold(id, |id, _| self.parent(id, 1))?,
+                _ if n == 0 => self.peel(id, Some(ObjectKind::Commit))?,
+                _ => self.parent(id, n)?,
+            };
+        }
+
+        Ok(id)
+    }
+
+    // A ref or object id, possibly followed by a `@{<n>}` reflog selector
+    fn resolve_base(&self, base: &str) -> Result<ObjectId> {
+        if let Some((name, selector)) = base
+            .strip_suffix('}')
+            .and_then(|base| base.rsplit_once("@{"))
+        {
+            let name = match name {
+                // `@{<n>}` alone refers to the current branch
+                "" => self.refs.resolve_name("HEAD")?,
+                name => self
+                    .refs
+                    .dwim(name)?
+                    .ok_or(anyhow!("Unknown ref {}", name))?,
+            };
+            return self.reflog_entry(&name, selector);
+        }
+
+        let base = if base == "@" { "HEAD" } else { base };
+        if base.len() == ObjectId::HEX_LEN {
+            if let Ok(id) = base.parse() {
+                return Ok(id);
+            }
+        }
+        match self.refs.dwim(base)? {
+            Some(name) => self
+                .refs
+                .resolve(&name)?
+                .ok_or(anyhow!("{} does not point to any object", name)),
+            None => resolve_object_id(self.odb, base),
+        }
+    }
+
+    fn reflog_entry(&self, name: &str, selector: &str) -> Result<ObjectId> {
+        let n: usize = selector
+            .parse()
+            .map_err(|_| anyhow!("Unsupported reflog selector @{{{}}}", selector))?;
+        if n == 0 {
+            // The current value, whether or not the ref has a reflog
+            return self
+                .refs
+                .resolve(name)?
+                .ok_or(anyhow!("{} does not point to any object", name));
+        }
+
+        let entries = self.refs.read_reflog(name)?;
+        entries
+            .iter()
+            .rev()
+            .nth(n)
+            .map(|entry| entry.new)
+            .ok_or(anyhow!(
+                "log for '{}' only has {} entries",
+                name,
+                entries.len()
+            ))
+    }
+
+    fn parent(&self, id: ObjectId, n: usize) -> Result<ObjectId> {
+        let commit = self.peel(id, Some(ObjectKind::Commit))?;
+        let Object::Commit(commit) = self.odb.read(&commit)? else {
+            unreachable!("peeled to a commit");
+        };
+        commit.parents.get(n - 1).copied().ok_or(anyhow!(
+            "Commit {} has no parent number {}",
+            id,
+            n
+        ))
+    }
+
+    // The contents of `^{...}`
+    fn peel_with(&self, id: ObjectId, contents: &str) -> Result<ObjectId> {
+        if let Some(pattern) = contents.strip_prefix('/') {
+            let commit = self.peel(id, Some(ObjectKind::Commit))?;
+            return self.search_commits([commit].into_iter(), pattern);
+        }
+        match contents {
+            // Just checks that the object exists
+            "object" => {
+                self.odb.read_raw(&id)?;
+                Ok(id)
+            }
+            "" => self.peel(id, None),
+            kind => self.peel(id, Some(kind.parse()?)),
+        }
+    }
+
+    // Dereferences tags, and commits to get to a tree, until reaching an object of the given kind
+    // (or anything that is not a tag without a kind)
+    fn peel(&self, mut id: ObjectId, kind: Option<ObjectKind>) -> Result<ObjectId> {
+        loop {
+            let object = self.odb.read(&id)?;
+            if Some(object.kind()) == kind {
+                return Ok(id);
+            }
+            id = match object {
+                Object::Tag(tag) => tag.object,
+                Object::Commit(commit) if kind == Some(ObjectKind::Tree) => commit.tree,
+                _ if kind.is_none() => return Ok(id),
+                object => {
+                    return Err(anyhow!(
+                        "{} {} cannot be peeled to a {}",
+                        object.kind(),
+                        id,
+                        kind.map(|kind| kind.to_string()).unwrap_or_default()
+                    ))
+                }
+            };
+        }
+    }
+
+    fn lookup_path(&self, tree: ObjectId, path: &str) -> Result<ObjectId> {
+        // Paths are relative to the root of the repository, `./` and `../` don't go anywhere else
+        let mut components: Vec<&str> = Vec::new();
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop();
+                }
+                component => components.push(component),
+            }
+        }
+
+        let mut id = tree;
+        for component in components {
+            let Object::Tree(entries) = self.odb.read(&id)? else {
+                return Err(anyhow!("{} is not a tree", id));
+            };
+            id = entries
+                .iter()
+                .find(|entry| entry.name == component.as_bytes())
+                .map(|entry| entry.id)
+                .ok_or(anyhow!("No entry {} in tree {}", component, id))?;
+        }
+        Ok(id)
+    }
+
+    // The most recent commit reachable from `starts` whose message matches the pattern. Like git,
+    // `!-` negates the pattern and `!!` escapes a leading `!`.
+    fn search_commits(
+        &self,
+        starts: impl Iterator<Item = ObjectId>,
+        pattern: &str,
+    ) -> Result<ObjectId> {
+        let (pattern, negated) = match pattern.strip_prefix('!') {
+            Some(pattern) if pattern.starts_with('!') => (pattern, false),
+            Some(pattern) => match pattern.strip_prefix('-') {
+                Some(pattern) => (pattern, true),
+                None => return Err(anyhow!("Invalid search pattern :/!{}", pattern)),
+            },
+            None => (pattern, false),
+        };
+        let regex = Regex::new(pattern)?;
+
+        // Walk the history from the most recent commit to the oldest one, commits with the same
+        // date being visited in the order they were found
+        let mut queue = BinaryHeap::new();
+        let mut seen = HashSet::new();
+        let mut enqueue = |queue: &mut BinaryHeap<_>, id: ObjectId| -> Result<()> {
+            if seen.insert(id) {
+                let Object::Commit(commit) = self.odb.read(&id)? else {
+                    return Err(anyhow!("{} is not a commit", id));
+                };
+                queue.push((commit.committer.time.seconds, Reverse(seen.len()), id));
+            }
+            Ok(())
+        };
+        for start in starts {
+            if let Ok(commit) = self.peel(start, Some(ObjectKind::Commit)) {
+                enqueue(&mut queue, commit)?;
+            }
+        }
+        while let Some((_, _, id)) = queue.pop() {
+            let Object::Commit(commit) = self.odb.read(&id)? else {
+                unreachable!("only commits are queued");
+            };
+            if regex.is_match(&String::from_utf8_lossy(&commit.message)) != negated {
+                return Ok(id);
+            }
+            for parent in commit.parents {
+                enqueue(&mut queue, parent)?;
+            }
+        }
+
+        Err(anyhow!("No commit message matches {}", pattern))
+    }
+}
+
+// Splits `<tree-ish>:<path>` at the first colon that is not inside braces, as in `HEAD^{/fix:}`
+fn split_tree_path(revision: &str) -> Option<(&str, &str)> {
+    let mut depth = 0;
+    for (i, c) in revision.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ':' if depth == 0 => return Some((&revision[..i], &revision[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
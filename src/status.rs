@@ -0,0 +1,550 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::io::Write;
+
+use crate::git_object::FileMode;
+use crate::ignore::Ignore;
+use crate::index::Index;
+use crate::object_id::ObjectId;
+use crate::odb::{abbreviate_object_id, ObjectDatabase};
+use crate::repository::Repository;
+use crate::revision::resolve_revision;
+use crate::worktree;
+use crate::{StatusFormat, StatusOptions};
+
+type Version = (FileMode, ObjectId);
+
+// A path that differs between HEAD, the index and the working tree. `staged` and `unstaged` are
+// the two letters of the short format, a space meaning no change.
+struct Change {
+    path: String,
+    head: Option<Version>,
+    index: Option<Version>,
+    worktree: Option<FileMode>,
+    // The versions of a conflict, from the common ancestor to theirs
+    stages: [Option<Version>; 3],
+    staged: char,
+    unstaged: char,
+}
+
+impl Change {
+    fn is_unmerged(&self) -> bool {
+        self.stages.iter().any(Option::is_some)
+    }
+}
+
+struct Status {
+    // `None` when HEAD is detached
+    branch: Option<String>,
+    head: Option<ObjectId>,
+    // A merge stopped to let conflicts be resolved
+    merging: bool,
+    changes: Vec<Change>,
+    // Paths of untracked files, or of directories where nothing is tracked with a trailing slash
+    untracked: Vec<String>,
+    ignored: Vec<String>,
+}
+
+pub(crate) fn status<W: Write>(
+    repo: &Repository,
+    prefix: &str,
+    options: &StatusOptions,
+    writer: &mut W,
+) -> Result<()> {
+    let odb = repo.objects()?;
+    let status = collect(repo, &odb, options.ignored)?;
+    let output = match options.format {
+        StatusFormat::Long => long_format(&status, prefix, &odb)?,
+        StatusFormat::Short => short_format(&status, options.branch, |path| {
+            quote_path(&relative_path(path, prefix), true)
+        }),
+        StatusFormat::Porcelain => {
+            short_format(&status, options.branch, |path| quote_path(path, true))
+        }
+        StatusFormat::PorcelainV2 => porcelain_v2(&status, options.branch, |path| {
+            quote_path(&relative_path(path, prefix), false)
+        }),
+    };
+    writer.write_all(output.as_bytes())?;
+    Ok(())
+}
+
+fn collect(repo: &Repository, odb: &dyn ObjectDatabase, show_ignored: bool) -> Result<Status> {
+    let root = repo.require_work_tree()?;
+    let refs = repo.refs();
+    let branch = refs
+        .resolve_name("HEAD")?
+        .strip_prefix("refs/heads/")
+        .map(String::from);
+    let head = refs.resolve("HEAD")?;
+    let head_tree = match head {
+        Some(_) => worktree::flatten_tree(odb, &resolve_revision(odb, &refs, "HEAD^{tree}")?)?,
+        None => BTreeMap::new(),
+    };
+
+    let index_path = repo.index_path();
+    let mut index = Index::open(&index_path)?;
+    let index_mtime = worktree::index_mtime(&index_path);
+    let paths: BTreeSet<String> = head_tree
+        .keys()
+        .cloned()
+        .chain(index.entries().iter().map(|entry| entry.path.clone()))
+        .collect();
+
+    let mut changes = Vec::new();
+    let mut refreshed = Vec::new();
+    for path in paths {
+        let file = root.join(&path);
+        let head = head_tree.get(&path).copied();
+        // A directory where a file was is as good as no file
+        let metadata = fs::symlink_metadata(&file)
+            .ok()
+            .filter(|metadata| !metadata.is_dir() || file.join(".git").exists());
+        let stages = [1, 2, 3].map(|stage| {
+            index
+                .entry(&path, stage)
+                .map(|entry| (entry.mode, entry.id))
+        });
+
+        let mut change = Change {
+            path,
+            head,
+            index: None,
+            worktree: metadata.as_ref().map(worktree::file_mode),
+            stages,
+            staged: ' ',
+            unstaged: ' ',
+        };
+        if change.is_unmerged() {
+            let codes = match stages.map(|stage| stage.is_some()) {
+                [true, false, false] => "DD",
+                [false, true, false] => "AU",
+                [true, true, false] => "UD",
+                [false, false, true] => "UA",
+                [true, false, true] => "DU",
+                [false, true, true] => "AA",
+                _ => "UU",
+            };
+            let mut codes = codes.chars();
+            change.staged = codes.next().unwrap_or(' ');
+            change.unstaged = codes.next().unwrap_or(' ');
+            changes.push(change);
+            continue;
+        }
+
+        let Some(entry) = index.entry(&change.path, 0) else {
+            // Removed from the index, and untracked if still there
+            change.staged = 'D';
+            change.worktree = None;
+            changes.push(change);
+            continue;
+        };
+        if entry.intent_to_add {
+            // Nothing is staged yet, but the file is going to be added
+            change.unstaged = if metadata.is_some() { 'A' } else { 'D' };
+            changes.push(change);
+            continue;
+        }
+        change.index = Some((entry.mode, entry.id));
+        change.staged = match head {
+            None => 'A',
+            Some(head) if head == (entry.mode, entry.id) => ' ',
+            Some((mode, _)) => change_code(mode, entry.mode),
+        };
+        change.unstaged = match &metadata {
+            None => 'D',
+            Some(metadata) if worktree::is_unchanged(entry, metadata, index_mtime) => ' ',
+            Some(metadata) => {
+                let (mode, id) = worktree::hash_file(&file, metadata, None)?;
+                if (mode, id) == (entry.mode, entry.id) {
+                    refreshed.push((change.path.clone(), metadata.clone()));
+                    ' '
+                } else {
+                    change_code(entry.mode, mode)
+                }
+            }
+        };
+        if change.staged != ' ' || change.unstaged != ' ' {
+            changes.push(change);
+        }
+    }
+
+    // Like git, save what was learned about the files so that they aren't hashed again next
+    // time. This is only an optimization, so it doesn't matter if the index can't be written.
+    if !refreshed.is_empty() {
+        for (path, metadata) in &refreshed {
+            index.refresh(path, metadata);
+        }
+        let _ = index.write(&index_path);
+    }
+
+    let mut ignore = Ignore::load(repo, |name| env::var_os(name))?;
+    let listing = worktree::list_files(root, &index, &mut ignore)?;
+    let tracked: BTreeSet<&str> = index
+        .entries()
+        .iter()
+        .map(|entry| entry.path.as_str())
+        .collect();
+    let tracked_dirs: BTreeSet<&str> = tracked
+        .iter()
+        .flat_map(|path| path.match_indices('/').map(|(i, _)| &path[..i]))
+        .collect();
+    let mut untracked: Vec<String> = Vec::new();
+    for file in listing.files {
+        if tracked.contains(file.as_str()) {
+            continue;
+        }
+        // Only the topmost directory where nothing is tracked is shown
+        let shown = match file
+            .match_indices('/')
+            .find(|(i, _)| !tracked_dirs.contains(&file[..*i]))
+        {
+            Some((i, _)) => file[..=i].to_string(),
+            None if root.join(&file).symlink_metadata()?.is_dir() => format!("{}/", file),
+            None => file,
+        };
+        if untracked.last() != Some(&shown) {
+            untracked.push(shown);
+        }
+    }
+    // Same for ignored files, unless the directory has untracked files too
+    let untracked_set: BTreeSet<&str> = untracked.iter().map(String::as_str).collect();
+    let has_untracked = |directory: &str| {
+        untracked_set
+            .range(directory..)
+            .next()
+            .is_some_and(|path| path.starts_with(directory))
+    };
+    let mut ignored: Vec<String> = Vec::new();
+    for file in listing.ignored.into_iter().filter(|_| show_ignored) {
+        let shown = match file
+            .match_indices('/')
+            .find(|(i, _)| !tracked_dirs.contains(&file[..*i]))
+        {
+            Some((i, _)) if !has_untracked(&file[..=i]) => file[..=i].to_string(),
+            _ => file,
+        };
+        if ignored.last() != Some(&shown) {
+            ignored.push(shown);
+        }
+    }
+
+    Ok(Status {
+        branch,
+        head,
+        merging: repo.git_dir().join("MERGE_HEAD").exists(),
+        changes,
+        untracked,
+        ignored,
+    })
+}
+
+// `T` when a file becomes a symlink or a submodule and the other way around, `M` otherwise
+fn change_code(old: FileMode, new: FileMode) -> char {
+    let kind = |mode| match mode {
+        FileMode::Regular | FileMode::Executable => 0,
+        FileMode::Symlink => 1,
+        _ => 2,
+    };
+    match kind(old) == kind(new) {
+        true => 'M',
+        false => 'T',
+    }
+}
+
+fn long_format(status: &Status, prefix: &str, odb: &dyn ObjectDatabase) -> Result<String> {
+    let mut output = String::new();
+    match (&status.branch, &status.head) {
+        (Some(branch), _) => output.push_str(&format!("On branch {}\n", branch)),
+        (None, Some(head)) => output.push_str(&format!(
+            "HEAD detached at {}\n",
+            abbreviate_object_id(odb, head, 7)?
+        )),
+        (None, None) => output.push_str("Not currently on any branch.\n"),
+    }
+    if status.head.is_none() {
+        output.push_str("\nNo commits yet\n\n");
+    }
+
+    let path = |path: &str| quote_path(&relative_path(path, prefix), false);
+    let section = |output: &mut String, title: &str, hints: &[&str], lines: Vec<String>| {
+        output.push_str(&format!("{}:\n", title));
+        for hint in hints {
+            output.push_str(&format!("  ({})\n", hint));
+        }
+        for line in lines {
+            output.push_str(&format!("\t{}\n", line));
+        }
+        output.push('\n');
+    };
+    let label = |code: char| match code {
+        'A' => "new file:",
+        'D' => "deleted:",
+        'T' => "typechange:",
+        _ => "modified:",
+    };
+
+    let unmerged: Vec<&Change> = status.changes.iter().filter(|c| c.is_unmerged()).collect();
+    let staged: Vec<&Change> = status
+        .changes
+        .iter()
+        .filter(|c| !c.is_unmerged() && c.staged != ' ')
+        .collect();
+    let unstaged: Vec<&Change> = status
+        .changes
+        .iter()
+        .filter(|c| !c.is_unmerged() && c.unstaged != ' ')
+        .collect();
+    // There's nothing to unstage to in the middle of a merge
+    let unstage_hint: &[&str] = match (status.merging, status.head) {
+        (true, _) => &[],
+        (false, Some(_)) => &["use \"git restore --staged <file>...\" to unstage"],
+        (false, None) => &["use \"git rm --cached <file>...\" to unstage"],
+    };
+    if status.merging {
+        match unmerged.is_empty() {
+            false => output.push_str(
+                "You have unmerged paths.\n  (fix conflicts and run \"git commit\")\n  \
+                (use \"git merge --abort\" to abort the merge)\n\n",
+            ),
+            true => output.push_str(
+                "All conflicts fixed but you are still merging.\n  \
+                (use \"git commit\" to conclude merge)\n\n",
+            ),
+        }
+    }
+
+    if !unmerged.is_empty() {
+        let deleted = unmerged
+            .iter()
+            .any(|c| c.staged == 'D' || c.unstaged == 'D');
+        let lines = unmerged
+            .iter()
+            .map(|c| {
+                let how = match (c.staged, c.unstaged) {
+                    ('D', 'D') => "both deleted:",
+                    ('A', 'U') => "added by us:",
+                    ('U', 'D') => "deleted by them:",
+                    ('U', 'A') => "added by them:",
+                    ('D', 'U') => "deleted by us:",
+                    ('A', 'A') => "both added:",
+                    _ => "both modified:",
+                };
+                format!("{:<17}{}", how, path(&c.path))
+            })
+            .collect();
+        let resolve_hint = match deleted {
+            true => "use \"git add/rm <file>...\" as appropriate to mark resolution",
+            false => "use \"git add <file>...\" to mark resolution",
+        };
+        section(
+            &mut output,
+            "Unmerged paths",
+            &[unstage_hint, &[resolve_hint]].concat(),
+            lines,
+        );
+    }
+    if !staged.is_empty() {
+        let lines = staged
+            .iter()
+            .map(|c| format!("{:<12}{}", label(c.staged), path(&c.path)))
+            .collect();
+        section(&mut output, "Changes to be committed", unstage_hint, lines);
+    }
+    if !unstaged.is_empty() {
+        let add_hint = match unstaged.iter().any(|c| c.unstaged == 'D') {
+            true => "use \"git add/rm <file>...\" to update what will be committed",
+            false => "use \"git add <file>...\" to update what will be committed",
+        };
+        let lines = unstaged
+            .iter()
+            .map(|c| format!("{:<12}{}", label(c.unstaged), path(&c.path)))
+            .collect();
+        section(
+            &mut output,
+            "Changes not staged for commit",
+            &[
+                add_hint,
+                "use \"git restore <file>...\" to discard changes in working directory",
+            ],
+            lines,
+        );
+    }
+    if !status.untracked.is_empty() {
+        let lines = status.untracked.iter().map(|file| path(file)).collect();
+        section(
+            &mut output,
+            "Untracked files",
+            &["use \"git add <file>...\" to include in what will be committed"],
+            lines,
+        );
+    }
+    if !status.ignored.is_empty() {
+        let lines = status.ignored.iter().map(|file| path(file)).collect();
+        section(
+            &mut output,
+            "Ignored files",
+            &["use \"git add -f <file>...\" to include in what will be committed"],
+            lines,
+        );
+    }
+
+    let summary = if !staged.is_empty() {
+        None
+    } else if !unstaged.is_empty() || !unmerged.is_empty() {
+        Some("no changes added to commit (use \"git add\" and/or \"git commit -a\")")
+    } else if !status.untracked.is_empty() {
+        Some("nothing added to commit but untracked files present (use \"git add\" to track)")
+    } else if status.head.is_none() {
+        Some("nothing to commit (create/copy files and use \"git add\" to track)")
+    } else {
+        Some("nothing to commit, working tree clean")
+    };
+    if let Some(summary) = summary {
+        output.push_str(&format!("{}\n", summary));
+    }
+
+    Ok(output)
+}
+
+fn short_format(status: &Status, branch: bool, path: impl Fn(&str) -> String) -> String {
+    let mut output = String::new();
+    if branch {
+        match (&status.branch, &status.head) {
+            (Some(name), Some(_)) => output.push_str(&format!("## {}\n", name)),
+            (Some(name), None) => output.push_str(&format!("## No commits yet on {}\n", name)),
+            (None, _) => output.push_str("## HEAD (no branch)\n"),
+        }
+    }
+    for change in &status.changes {
+        output.push_str(&format!(
+            "{}{} {}\n",
+            change.staged,
+            change.unstaged,
+            path(&change.path)
+        ));
+    }
+    for file in &status.untracked {
+        output.push_str(&format!("?? {}\n", path(file)));
+    }
+    for file in &status.ignored {
+        output.push_str(&format!("!! {}\n", path(file)));
+    }
+    output
+}
+
+// The version 2 of the porcelain format has the modes and ids of each version of a change:
+// `1 <XY> <sub> <mH> <mI> <mW> <hH> <hI> <path>` for ordinary changes and
+// `u <XY> <sub> <m1> <m2> <m3> <mW> <h1> <h2> <h3> <path>` for conflicts
+fn porcelain_v2(status: &Status, branch: bool, path: impl Fn(&str) -> String) -> String {
+    let mut output = String::new();
+    if branch {
+        match &status.head {
+            Some(head) => output.push_str(&format!("# branch.oid {}\n", head)),
+            None => output.push_str("# branch.oid (initial)\n"),
+        }
+        match &status.branch {
+            Some(name) => output.push_str(&format!("# branch.head {}\n", name)),
+            None => output.push_str("# branch.head (detached)\n"),
+        }
+    }
+
+    let mode = |version: Option<FileMode>| version.map_or("000000".to_string(), |m| m.to_string());
+    let id = |version: Option<Version>| version.map_or("0".repeat(40), |(_, id)| id.to_string());
+    for change in &status.changes {
+        let codes = format!(
+            "{}{}",
+            change.staged.to_string().replace(' ', "."),
+            change.unstaged.to_string().replace(' ', ".")
+        );
+        let submodule = match [change.head, change.index]
+            .iter()
+            .flatten()
+            .any(|(mode, _)| *mode == FileMode::Gitlink)
+        {
+            true => "SC..",
+            false => "N...",
+        };
+        let line = match change.is_unmerged() {
+            false => format!(
+                "1 {} {} {} {} {} {} {} {}",
+                codes,
+                submodule,
+                mode(change.head.map(|(mode, _)| mode)),
+                mode(change.index.map(|(mode, _)| mode)),
+                mode(change.worktree),
+                id(change.head),
+                id(change.index),
+                path(&change.path)
+            ),
+            true => format!(
+                "u {} N... {} {} {} {} {} {} {} {}",
+                codes,
+                mode(change.stages[0].map(|(mode, _)| mode)),
+                mode(change.stages[1].map(|(mode, _)| mode)),
+                mode(change.stages[2].map(|(mode, _)| mode)),
+                mode(change.worktree),
+                id(change.stages[0]),
+                id(change.stages[1]),
+                id(change.stages[2]),
+                path(&change.path)
+            ),
+        };
+        output.push_str(&format!("{}\n", line));
+    }
+    for file in &status.untracked {
+        output.push_str(&format!("? {}\n", path(file)));
+    }
+    for file in &status.ignored {
+        output.push_str(&format!("! {}\n", path(file)));
+    }
+    output
+}
+
+// `path` relative to the directory `prefix`, both being relative to the root of the working tree
+fn relative_path(path: &str, prefix: &str) -> String {
+    let (mut path, mut prefix) = (path, prefix);
+    while let Some(slash) = prefix.find('/') {
+        match path.strip_prefix(&prefix[..=slash]) {
+            Some(rest) => {
+                path = rest;
+                prefix = &prefix[slash + 1..];
+            }
+            None => break,
+        }
+    }
+    match format!("{}{}", "../".repeat(prefix.matches('/').count()), path) {
+        relative if relative.is_empty() => "./".to_string(),
+        relative => relative,
+    }
+}
+
+// Like git with `core.quotePath`, paths with special characters are quoted C-style and bytes
+// outside of ASCII are escaped in octal. The short format quotes paths with spaces too.
+fn quote_path(path: &str, quote_spaces: bool) -> String {
+    if !path.bytes().any(|b| {
+        !(0x20..0x7f).contains(&b) || b == b'"' || b == b'\\' || (quote_spaces && b == b' ')
+    }) {
+        return path.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for b in path.bytes() {
+        match b {
+            b'\x07' => quoted.push_str("\\a"),
+            b'\x08' => quoted.push_str("\\b"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\x0b' => quoted.push_str("\\v"),
+            b'\x0c' => quoted.push_str("\\f"),
+            b'\r' => quoted.push_str("\\r"),
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b if !(0x20..0x7f).contains(&b) => quoted.push_str(&format!("\\{:03o}", b)),
+            b => quoted.push(b as char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
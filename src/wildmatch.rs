@@ -0,0 +1,249 @@
+// Git's glob matching (`wildmatch.c`), used for `includeIf` conditions and ignore patterns
+
+// `*`, `?` and brackets don't match slashes, `**` between slashes matches any number of
+// directories
+pub(crate) const PATHNAME: u8 = 1;
+pub(crate) const CASEFOLD: u8 = 2;
+
+// Whether `text` as a whole matches `pattern`. Supports `*`, `**`, `?`, bracket expressions with
+// ranges, `!`/`^` negation and `[:class:]`, and backslash escapes.
+pub(crate) fn wildmatch(pattern: &str, text: &str, flags: u8) -> bool {
+    Matcher {
+        pattern: pattern.as_bytes(),
+        text: text.as_bytes(),
+        flags,
+    }
+    .matches(0, 0)
+        == Outcome::Matched
+}
+
+// Like in git, failures tell how far back a `*` has to go: trying it on more text can't help once
+// the text ran out, nor once a `*` would have to match a slash, short of an enclosing `**`. Without
+// that, patterns with many stars would take exponential time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Matched,
+    NoMatch,
+    AbortAll,
+    AbortToStarStar,
+}
+
+struct Matcher<'a> {
+    pattern: &'a [u8],
+    text: &'a [u8],
+    flags: u8,
+}
+
+impl Matcher<'_> {
+    fn matches(&self, mut p: usize, mut t: usize) -> Outcome {
+        let pathname = self.flags & PATHNAME != 0;
+        while p < self.pattern.len() {
+            let Some(&c) = self.text.get(t) else {
+                if self.pattern[p] == b'*' {
+                    // Stars can match nothing at the end
+                    p += self.pattern[p..].iter().take_while(|&&c| c == b'*').count();
+                    if p == self.pattern.len() {
+                        return Outcome::Matched;
+                    }
+                }
+                return Outcome::AbortAll;
+            };
+            match self.pattern[p] {
+                b'*' => {
+                    let stars = self.pattern[p..].iter().take_while(|&&c| c == b'*').count();
+                    let at_start = p == 0 || self.pattern[p - 1] == b'/';
+                    p += stars;
+                    let at_end = p == self.pattern.len() || self.pattern[p] == b'/';
+
+                    // Whether this star can match slashes
+                    let starstar = pathname && stars >= 2 && at_start && at_end;
+                    let match_slash = !pathname || starstar;
+                    // `**/` also matches no directory at all
+                    if starstar
+                        && p < self.pattern.len()
+                        && self.matches(p + 1, t) == Outcome::Matched
+                    {
+                        return Outcome::Matched;
+                    }
+                    if p == self.pattern.len() {
+                        return match match_slash || !self.text[t..].contains(&b'/') {
+                            true => Outcome::Matched,
+                            false => Outcome::NoMatch,
+                        };
+                    }
+
+                    for t in t..self.text.len() {
+                        match self.matches(p, t) {
+                            Outcome::NoMatch if !match_slash && self.text[t] == b'/' => {
+                                return Outcome::AbortToStarStar
+                            }
+                            Outcome::NoMatch => {}
+                            Outcome::AbortToStarStar if match_slash => {}
+                            result => return result,
+                        }
+                    }
+                    return Outcome::AbortAll;
+                }
+                b'?' => {
+                    if c == b'/' && pathname {
+                        return Outcome::NoMatch;
+                    }
+                    p += 1;
+                }
+                b'[' => {
+                    if c == b'/' && pathname {
+                        return Outcome::NoMatch;
+                    }
+                    match self.bracket(p + 1, c) {
+                        Some((true, end)) => p = end,
+                        Some((false, _)) => return Outcome::NoMatch,
+                        None => return Outcome::AbortAll,
+                    }
+                }
+                literal => {
+                    let (literal, len) = match literal {
+                        b'\\' if p + 1 < self.pattern.len() => (self.pattern[p + 1], 2),
+                        literal => (literal, 1),
+                    };
+                    if !self.eq(literal, c) {
+                        return Outcome::NoMatch;
+                    }
+                    p += len;
+                }
+            }
+            t += 1;
+        }
+        match t == self.text.len() {
+            true => Outcome::Matched,
+            false => Outcome::NoMatch,
+        }
+    }
+
+    // Whether `c` is in the bracket expression starting at `p` (after the `[`), and where the
+    // expression ends. Unterminated brackets match nothing.
+    fn bracket(&self, mut p: usize, c: u8) -> Option<(bool, usize)> {
+        let negated = matches!(self.pattern.get(p), Some(b'!' | b'^'));
+        if negated {
+            p += 1;
+        }
+
+        let mut matched = false;
+        let mut first = true;
+        loop {
+            let mut low = *self.pattern.get(p)?;
+            p += 1;
+            if low == b']' && !first {
+                break;
+            }
+            first = false;
+
+            if low == b'[' && self.pattern.get(p) == Some(&b':') {
+                let end = self.pattern[p..].windows(2).position(|w| w == b":]")?;
+                let class = &self.pattern[p + 1..p + end];
+                matched |= match class {
+                    b"alpha" => c.is_ascii_alphabetic(),
+                    b"digit" => c.is_ascii_digit(),
+                    b"alnum" => c.is_ascii_alphanumeric(),
+                    b"upper" => c.is_ascii_uppercase(),
+                    b"lower" => c.is_ascii_lowercase(),
+                    b"space" => c.is_ascii_whitespace(),
+                    b"xdigit" => c.is_ascii_hexdigit(),
+                    b"punct" => c.is_ascii_punctuation(),
+                    _ => return None,
+                };
+                p += end + 2;
+                continue;
+            }
+            if low == b'\\' {
+                low = *self.pattern.get(p)?;
+                p += 1;
+            }
+
+            let mut high = low;
+            if self.pattern.get(p) == Some(&b'-') && !matches!(self.pattern.get(p + 1), Some(b']'))
+            {
+                high = *self.pattern.get(p + 1)?;
+                p += 2;
+                if high == b'\\' {
+                    high = *self.pattern.get(p)?;
+                    p += 1;
+                }
+            }
+
+            matched |= (low..=high).contains(&c)
+                || (self.flags & CASEFOLD != 0
+                    && ((low..=high).contains(&c.to_ascii_lowercase())
+                        || (low..=high).contains(&c.to_ascii_uppercase())));
+        }
+
+        Some((matched != negated, p))
+    }
+
+    fn eq(&self, a: u8, b: u8) -> bool {
+        match self.flags & CASEFOLD {
+            0 => a == b,
+            _ => a.eq_ignore_ascii_case(&b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches() {
+        let cases = [
+            ("foo", "foo", 0, true),
+            ("f?o", "foo", 0, true),
+            ("*.rs", "src/main.rs", 0, true),
+            ("*.rs", "src/main.rs", PATHNAME, false),
+            ("src/*.rs", "src/main.rs", PATHNAME, true),
+            ("**/main.rs", "main.rs", PATHNAME, true),
+            ("**/main.rs", "src/bin/main.rs", PATHNAME, true),
+            ("src/**", "src/bin/main.rs", PATHNAME, true),
+            ("src/**", "src", PATHNAME, false),
+            ("a/**/b", "a/b", PATHNAME, true),
+            ("a/**/b", "a/x/y/b", PATHNAME, true),
+            ("a**b", "a/b", PATHNAME, false),
+            ("[a-c]x", "bx", 0, true),
+            ("[!a-c]x", "bx", 0, false),
+            ("[[:digit:]]*", "1abc", 0, true),
+            ("[]]", "]", 0, true),
+            ("\\*", "*", 0, true),
+            ("\\*", "a", 0, false),
+            ("FOO/[B]ar", "foo/bar", CASEFOLD, true),
+            ("FOO", "foo", 0, false),
+            ("[abc", "a", 0, false),
+            ("a*", "a", PATHNAME, true),
+            ("a/*", "a/b/c", PATHNAME, false),
+            ("a/**", "a/b/c", PATHNAME, true),
+            ("*/**/c", "a/c", PATHNAME, true),
+            ("**", "a/b", PATHNAME, true),
+        ];
+        for (pattern, text, flags, expected) in cases {
+            assert_eq!(
+                wildmatch(pattern, text, flags),
+                expected,
+                "{} {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn many_stars() {
+        // Each star would otherwise try every position left by the previous ones
+        let text = "a".repeat(40);
+        for flags in [0, PATHNAME] {
+            assert!(!wildmatch("*a*a*a*a*a*a*a*a*b", &text, flags));
+            assert!(!wildmatch("**/*a*a*a*a*a*a*a*a*b", &text, flags));
+        }
+        assert!(!wildmatch(
+            "*a*a*a*a*a*a*a*a*b",
+            &format!("{}/a", text),
+            PATHNAME
+        ));
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, Metadata};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use crate::git_object::{FileMode, Object};
+use crate::ignore::Ignore;
+use crate::index::{Index, IndexEntry, IndexTime};
+use crate::object_id::ObjectId;
+use crate::odb::ObjectDatabase;
+use crate::repository::Repository;
+
+// The files of the working tree as paths relative to its root, sorted like index entries.
+// Nested repositories are listed as a single path, like git does for submodules. Untracked files
+// that are ignored are listed separately, and so are ignored directories where nothing is tracked
+// (with a trailing slash), which aren't looked into.
+#[derive(Debug, Default)]
+pub(crate) struct Files {
+    pub(crate) files: Vec<String>,
+    pub(crate) ignored: Vec<String>,
+}
+
+pub(crate) fn list_files(root: &Path, index: &Index, ignore: &mut Ignore) -> Result<Files> {
+    let tracked: BTreeSet<&str> = index
+        .entries()
+        .iter()
+        .map(|entry| entry.path.as_str())
+        .collect();
+    let tracked_dirs: BTreeSet<&str> = tracked
+        .iter()
+        .flat_map(|path| path.match_indices('/').map(|(i, _)| &path[..i]))
+        .collect();
+    let mut walk = Walk {
+        root,
+        tracked,
+        tracked_dirs,
+        ignore,
+        files: Files::default(),
+    };
+    walk.directory("")?;
+    walk.files.files.sort();
+    walk.files.ignored.sort();
+    Ok(walk.files)
+}
+
+struct Walk<'a> {
+    root: &'a Path,
+    tracked: BTreeSet<&'a str>,
+    tracked_dirs: BTreeSet<&'a str>,
+    ignore: &'a mut Ignore,
+    files: Files,
+}
+
+impl Walk<'_> {
+    fn directory(&mut self, prefix: &str) -> Result<()> {
+        let dir = self.root.join(prefix);
+        for entry in fs::read_dir(&dir).with_context(|| format!("read {}", dir.display()))? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            if name == ".git" {
+                continue;
+            }
+            let path = format!("{}{}", prefix, name);
+            // Symlinks to directories are files as far as git is concerned
+            let is_dir = entry.file_type()?.is_dir();
+            let nested = is_dir && entry.path().join(".git").exists();
+
+            if self.tracked.contains(path.as_str()) {
+                self.files.files.push(path);
+            } else if is_dir && !nested {
+                if self.ignore.is_ignored(&path, true)?
+                    && !self.tracked_dirs.contains(path.as_str())
+                {
+                    self.files.ignored.push(format!("{}/", path));
+                } else {
+                    self.directory(&format!("{}/", path))?;
+                }
+            } else if self.ignore.is_ignored(&path, is_dir)? {
+                self.files.ignored.push(match nested {
+                    true => format!("{}/", path),
+                    false => path,
+                });
+            } else {
+                self.files.files.push(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+// The mode and id of a file as it would be staged, writing its blob to `odb` if given. Symlinks
+// are stored as blobs containing their target, and nested repositories as the commit of their
+// HEAD.
+pub(crate) fn hash_file(
+    path: &Path,
+    metadata: &Metadata,
+    odb: Option<&dyn ObjectDatabase>,
+) -> Result<(FileMode, ObjectId)> {
+    let (mode, blob) = if metadata.is_symlink() {
+        let target = fs::read_link(path)?;
+        (
+            FileMode::Symlink,
+            Object::Blob(target.into_os_string().into_vec()),
+        )
+    } else if metadata.is_dir() {
+        let head = Repository::open(path)?
+            .refs()
+            .resolve("HEAD")?
+            .with_context(|| format!("resolve HEAD of {}", path.display()))?;
+        return Ok((FileMode::Gitlink, head));
+    } else {
+        (file_mode(metadata), Object::blobify(path)?)
+    };
+
+    let id = match odb {
+        Some(odb) => odb.write(&blob)?,
+        None => blob.hash(),
+    };
+    Ok((mode, id))
+}
+
+// The mode a file would be staged with, without looking into nested repositories
+pub(crate) fn file_mode(metadata: &Metadata) -> FileMode {
+    if metadata.is_symlink() {
+        FileMode::Symlink
+    } else if metadata.is_dir() {
+        FileMode::Gitlink
+    } else if metadata.permissions().mode() & 0o111 != 0 {
+        FileMode::Executable
+    } else {
+        FileMode::Regular
+    }
+}
+
+// When the index was last written, to tell whether the stat data of its entries can be trusted
+pub(crate) fn index_mtime(path: &Path) -> Option<IndexTime> {
+    let metadata = fs::metadata(path).ok()?;
+    Some(IndexTime {
+        seconds: metadata.mtime() as u32,
+        nanoseconds: metadata.mtime_nsec() as u32,
+    })
+}
+
+// Whether the file matches its index entry going by its stat data alone. Like git, a file
+// modified no earlier than the index was written could have changed after that without its stat
+// data showing it, so it is "racily clean" and has to be compared by contents.
+pub(crate) fn is_unchanged(
+    entry: &IndexEntry,
+    metadata: &Metadata,
+    index_mtime: Option<IndexTime>,
+) -> bool {
+    let racy = index_mtime.is_none_or(|index_mtime| {
+        (entry.mtime.seconds, entry.mtime.nanoseconds)
+            >= (index_mtime.seconds, index_mtime.nanoseconds)
+    });
+    !racy && entry.is_up_to_date(metadata)
+}
+
+// Every blob and gitlink of a tree, by path
+pub(crate) fn flatten_tree(
+    odb: &dyn ObjectDatabase,
+    tree: &ObjectId,
+) -> Result<BTreeMap<String, (FileMode, ObjectId)>> {
+    let mut entries = BTreeMap::new();
+    flatten_tree_into(odb, tree, "", &mut entries)?;
+    Ok(entries)
+}
+
+fn flatten_tree_into(
+    odb: &dyn ObjectDatabase,
+    tree: &ObjectId,
+    prefix: &str,
+    entries: &mut BTreeMap<String, (FileMode, ObjectId)>,
+) -> Result<()> {
+    let Object::Tree(tree_entries) = odb.read(tree)? else {
+        return Err(anyhow!("{} is not a tree", tree));
+    };
+    for entry in tree_entries {
+        // Paths are strings here, so names that aren't valid UTF-8 can't match any file
+        let path = format!("{}{}", prefix, String::from_utf8_lossy(&entry.name));
+        match entry.mode {
+            FileMode::Directory => {
+                flatten_tree_into(odb, &entry.id, &format!("{}/", path), entries)?
+            }
+            mode => {
+                entries.insert(path, (mode, entry.id));
+            }
+        }
+    }
+    Ok(())
+}